@@ -25,23 +25,122 @@ class Effect:
     """Base class for all attachable effects"""
 
     _COUNTER = 0
+    _TYPE = None  # effect type name, if it differs from the class name
 
     def __init__(self, op: str, data: dict):
         Effect._COUNTER += 1  # Every effect has a unique name
-        self._name = f"{type(self).__name__.lower()}-{str(Effect._COUNTER)}"
+        self._name = f"{self._TYPE or type(self).__name__.lower()}-{str(Effect._COUNTER)}"
         self._op = op
         self._data = data
 
+    def nth(self, every: int, offset: int = 0):
+        """Apply only to every `every`th matching operation, starting with the `offset`th"""
+        self._data["nth"] = {"every": every, "offset": offset}
+        return self
+
+    def priority(self, priority: int):
+        """Run before effects of lower priority, 0 by default"""
+        self._data["priority"] = priority
+        return self
+
+    def dryrun(self, enabled: bool = True):
+        """Only count and trace when the effect would have delayed or failed operations"""
+        self._data["dryrun"] = enabled
+        return self
+
+    def ttl(self, duration: DurationOrMs):
+        """Deactivate and remove the effect after `duration`"""
+        self._data["ttl_ms"] = _to_ms(duration)
+        return self
+
+    def schedule(
+        self,
+        start_after: DurationOrMs = 0,
+        active: DurationOrMs | None = None,
+        repeat: DurationOrMs | None = None,
+    ):
+        """Activate the effect `start_after` it was attached, for `active`, every `repeat`"""
+        self._data["start_after_ms"] = _to_ms(start_after)
+        if active is not None:
+            self._data["active_ms"] = _to_ms(active)
+        if repeat is not None:
+            self._data["repeat_ms"] = _to_ms(repeat)
+        return self
+
+    def max_hits(self, hits: int):
+        """Deactivate and remove the effect after it fired `hits` times"""
+        self._data["max_hits"] = hits
+        return self
+
+    def skip(self, ops: int):
+        """Let the first `ops` matching operations pass"""
+        self._data["skip"] = ops
+        return self
+
+    def path(self, glob: str):
+        """Apply only to descendants matching `glob` by name, or by relative path if it contains slashes"""
+        self._data["path"] = glob
+        return self
+
+    def range(self, offset: int, len: int):
+        """Apply only to reads and writes overlapping `len` bytes at `offset`"""
+        self._data["range"] = {"offset": offset, "len": len}
+        return self
+
+    def any_of(self, *conditions: dict):
+        """Apply only to operations matching any of the conditions, like `{"pid": 10}`"""
+        self._data.setdefault("any", []).extend(conditions)
+        return self
+
+    def unless(self, condition: dict):
+        """Apply only to operations not matching the condition"""
+        self._data["not"] = condition
+        return self
+
+    def caller(
+        self, uid: int | None = None, gid: int | None = None, pid: int | None = None, comm: str | None = None
+    ):
+        """Apply only to operations issued by the given user, group or process, `comm` is a regex of the process name"""
+        for key, value in (("uid", uid), ("gid", gid), ("pid", pid), ("comm", comm)):
+            if value is not None:
+                self._data[key] = value
+        return self
+
 
 class Delay(Effect):
-    """Delay selected operations by a fixed amount of time"""
+    """
+    Delay selected operations by a fixed amount of time, reads and writes by `read` and `write` if set.
+    Random `jitter` is added on top, like {"dist": "pareto", "scale_ms": 1, "shape": 1.5}.
+    With `min_len` or `max_len` only reads and writes of that size are delayed
+    """
 
     def __init__(
         self,
         duration: datetime.timedelta | int = 10,
         op: str = "rw",
+        read: DurationOrMs | None = None,
+        write: DurationOrMs | None = None,
+        jitter: dict | None = None,
+        min_len: int | None = None,
+        max_len: int | None = None,
     ):
-        super().__init__(op, {"duration_ms": _to_ms(duration)})
+        data = {"duration_ms": _to_ms(duration), "read_ms": None, "write_ms": None, "jitter": jitter}
+        data |= {"min_len": min_len, "max_len": max_len}
+        if read is not None:
+            data["read_ms"] = _to_ms(read)
+        if write is not None:
+            data["write_ms"] = _to_ms(write)
+        super().__init__(op, data)
+
+
+class Slo(Effect):
+    """
+    Shape latencies toward target percentiles, e.g. {50: 5, 99: 200} for p50 = 5ms and p99 = 200ms
+    """
+
+    def __init__(self, percentiles: dict[float, DurationOrMs], op: str = "rw"):
+        points = [[p, _to_ms(d)] for p, d in sorted(percentiles.items())]
+        super().__init__(op, {"percentiles": points})
 
 
 class Flakey(Effect):
@@ -51,7 +150,7 @@ class Flakey(Effect):
 
     Cond = float | typing.Tuple[DurationOrMs, DurationOrMs]
 
-    def __init__(self, cond: Cond = True, op: str = "rw", err: int = errno.EIO):
+    def __init__(self, cond: Cond = True, op: str = "rw", err: int | str = "EIO"):
         data = {}
         match cond:
             case float():
@@ -78,23 +177,673 @@ class MaxSize(Effect):
         super().__init__(op, {"limit": limit})
 
 
+class MaxIo(Effect):
+    """
+    Cap read/write sizes at `limit` bytes, returning short results or EINVAL with `reject`
+    """
+
+    def __init__(self, limit: int, reject: bool = False, op: str = "rw"):
+        super().__init__(op, {"limit": limit, "reject": reject})
+
+
+class Interleave(Effect):
+    """
+    Persist large writes in chunks with pauses in between, so concurrent readers observe torn states
+    """
+
+    def __init__(self, chunk: int = 4096, interval: DurationOrMs = 10, op: str = "w"):
+        super().__init__(op, {"chunk": chunk, "interval_ms": _to_ms(interval)})
+
+
+class PageFault(Effect):
+    """
+    Fail with `err` or delay page aligned reads serving page faults of mmap'd files and writebacks of cached pages
+    """
+
+    def __init__(self, err: int | str | None = None, delay: DurationOrMs = 0, op: str = "rw"):
+        super().__init__(op, {"errno": err, "delay_ms": _to_ms(delay)})
+
+
+class NoMem(Effect):
+    """
+    Return resource exhaustion errors (ENOMEM by default, or EAGAIN) with [0-1] probability
+    """
+
+    def __init__(self, prob: float, err: int | str = "ENOMEM", op: str = "rw"):
+        super().__init__(op, {"prob": prob, "errno": err})
+
+
+class Relink(Effect):
+    """
+    Rewrite symlink targets returned by readlink, rotating through `targets` or picking them at random
+    """
+
+    def __init__(self, targets: list[str], random: bool = False, op: str = "r"):
+        super().__init__(op, {"targets": targets, "random": random})
+
+
+class Diverge(Effect):
+    """
+    Make reads through the hard link entry `name` return stale or corrupt contents compared to other entries
+    """
+
+    def __init__(self, name: str, mode: str = "stale", op: str = "r"):
+        super().__init__(op, {"name": name, "mode": mode})
+
+
+class PathLatency(Effect):
+    """
+    Delay lookups proportionally to the depth of the resolved path
+    """
+
+    _TYPE = "pathlat"
+
+    def __init__(self, per_component: DurationOrMs, op: str = "l"):
+        super().__init__(op, {"per_component_ms": _to_ms(per_component)})
+
+
+class External(Effect):
+    """
+    Let an external process listening on a unix socket decide on every operation.
+    Messages in both directions are json prefixed by their length (u32 big endian).
+    Decisions are {"decision": "ok"}, {"decision": "error", "errno": 5} or {"decision": "delay", "delay_ms": 100}
+    """
+
+    def __init__(
+        self,
+        socket: str,
+        timeout: DurationOrMs = 1000,
+        fallback: dict = {"decision": "ok"},
+        op: str = "rw",
+    ):
+        data = {"socket": socket, "timeout_ms": _to_ms(timeout), "fallback": fallback}
+        super().__init__(op, data)
+
+
+class Device(Effect):
+    """
+    Simulated device shared by all effects with the same name, operations queue up for its bandwidth
+    """
+
+    def __init__(
+        self,
+        name: str,
+        bytes_per_sec: int,
+        op_us: int = 0,
+        op: str = "rw",
+        read_bytes_per_sec: int | None = None,
+        write_bytes_per_sec: int | None = None,
+    ):
+        data = {
+            "name": name,
+            "bytes_per_sec": bytes_per_sec,
+            "read_bytes_per_sec": read_bytes_per_sec,
+            "write_bytes_per_sec": write_bytes_per_sec,
+            "op_us": op_us,
+        }
+        super().__init__(op, data)
+
+
+class Verify(Effect):
+    """
+    Read back data after each write and compare, recording mismatches as events and failing with `err` if given
+    """
+
+    def __init__(self, err: int | str | None = None, op: str = "w"):
+        super().__init__(op, {"errno": err})
+
+
+class Cow(Effect):
+    """
+    Break copy-on-write semantics of copied ranges: "copy" makes them deep copies, "shared" makes writes visible in both files
+    """
+
+    def __init__(self, mode: str = "shared", op: str = "w"):
+        super().__init__(op, {"mode": mode})
+
+
+class ReadOnlyWindow(Effect):
+    """
+    Fail operations with `err` during recurring windows, like ["00:00-01:00"] daily in UTC
+    or [(start_ms, end_ms)] within custom `period`
+    """
+
+    _TYPE = "rowindow"
+
+    def __init__(
+        self,
+        windows: list[str | typing.Tuple[int, int]],
+        period: DurationOrMs = datetime.timedelta(days=1),
+        err: int | str = "EBUSY",
+        op: str = "w",
+    ):
+        data = {"windows": windows, "period_ms": _to_ms(period), "errno": err}
+        super().__init__(op, data)
+
+
+class NegativeLookup(Effect):
+    """
+    Serve spurious negative lookups of names created within `window`, cached by the kernel for `ttl`
+    """
+
+    _TYPE = "negative"
+
+    def __init__(self, window: DurationOrMs = 1000, ttl: DurationOrMs = 1000, prob: float = 1.0, op: str = "l"):
+        super().__init__(op, {"window_ms": _to_ms(window), "ttl_ms": _to_ms(ttl), "prob": prob})
+
+
+class OnIdle(Effect):
+    """
+    Apply `effect` only to operations ending `idle` time of quiescence in the subtree it's attached at
+    """
+
+    def __init__(self, idle: DurationOrMs, effect: Effect, op: str = "rw"):
+        data = {
+            "idle_ms": _to_ms(idle),
+            "effect": effect._TYPE or type(effect).__name__.lower(),
+            "params": effect._data,
+        }
+        super().__init__(op, data)
+
+
+class CorruptRead(Effect):
+    """
+    Flip `count` random bits (or bytes with unit="byte") in returned read data with [0-1] probability,
+    leaving stored data intact
+    """
+
+    def __init__(self, count: int = 1, prob: float = 1.0, unit: str = "bit", op: str = "r"):
+        super().__init__(op, {"count": count, "prob": prob, "unit": unit})
+
+
+class CorruptWrite(Effect):
+    """
+    Corrupt written data before it's persisted with [0-1] probability, mode is one of
+    flip (count=, unit=), zero (len=) or shift (by=)
+    """
+
+    def __init__(self, mode: str = "flip", prob: float = 1.0, op: str = "w", **params):
+        super().__init__(op, {"mode": mode, "prob": prob, **params})
+
+
+class Policy(Effect):
+    """
+    Allow or deny operations by name (read, write, readlink, lookup, statfs) for a subtree,
+    failing the rest with `err`
+    """
+
+    def __init__(
+        self,
+        allow: list[str] | None = None,
+        deny: list[str] = [],
+        err: int | str = "EPERM",
+        op: str = "rwls",
+    ):
+        super().__init__(op, {"allow": allow, "deny": deny, "errno": err})
+
+
+class Coalesce(Effect):
+    """
+    Coalesce writes into batches persisted at once `window` after the first write of the batch
+    """
+
+    def __init__(self, window: DurationOrMs = 100, op: str = "w"):
+        super().__init__(op, {"window_ms": _to_ms(window)})
+
+
+class LostWrite(Effect):
+    """
+    Acknowledge writes without persisting them with [0-1] probability or every `every`th write
+    """
+
+    def __init__(self, prob: float | None = None, every: int | None = None, op: str = "w"):
+        super().__init__(op, {"prob": prob, "every": every})
+
+
+class Torn(Effect):
+    """
+    Persist only a prefix of writes up to a random `align` boundary with [0-1] probability,
+    acknowledging their full length
+    """
+
+    def __init__(self, align: int = 512, prob: float = 1.0, op: str = "w"):
+        super().__init__(op, {"align": align, "prob": prob})
+
+
+class Misdirect(Effect):
+    """
+    Persist writes at an offset moved by `by` bytes with [0-1] probability,
+    by one block in a random direction if `by` is None
+    """
+
+    def __init__(self, prob: float, by: int | None = None, op: str = "w"):
+        super().__init__(op, {"prob": prob, "by": by})
+
+
+class DupWrite(Effect):
+    """
+    Persist writes once more at the next `align` boundary with [0-1] probability,
+    the block size if `align` is None
+    """
+
+    def __init__(self, prob: float = 1.0, align: int | None = None, op: str = "w"):
+        super().__init__(op, {"prob": prob, "align": align})
+
+
+class BadRange(Effect):
+    """
+    Fail reads overlapping any of the (offset, length) ranges with `err`,
+    or ranges of specific files by path in `paths` like a bad block map
+    """
+
+    def __init__(
+        self,
+        ranges: list[tuple[int, int]] = [],
+        paths: dict[str, list[tuple[int, int]]] = {},
+        err: int | str = "EIO",
+        op: str = "r",
+    ):
+        paths = {p: [list(r) for r in rs] for p, rs in paths.items()}
+        super().__init__(op, {"ranges": [list(r) for r in ranges], "paths": paths, "errno": err})
+
+
+class Model(Effect):
+    """
+    Fail and delay operations as described by an empirical model: per operation name
+    {"errors": [{"errno": "EIO", "freq": 0.001}], "latency": {"percentiles": [[50, 2], [99, 40]]}}
+    """
+
+    def __init__(self, ops: dict, op: str = "rwls"):
+        super().__init__(op, {"ops": ops})
+
+    def load(path: str | os.PathLike, **kwargs):
+        """Load model from a json file"""
+        with open(path) as f:
+            return Model(**json.load(f), **kwargs)
+
+
+class Scrub(Effect):
+    """
+    Keep shadow checksums of written blocks and verify them on read, display shows mismatch counts
+    """
+
+    def __init__(self, block: int = 4096, op: str = "rw"):
+        super().__init__(op, {"block": block})
+
+
+class Rot(Effect):
+    """Flip random bits of stored data below the node at `rate` flips per second, independently of io"""
+
+    def __init__(self, rate: float, op: str = "rw"):
+        super().__init__(op, {"rate": rate})
+
+
+class DiskFill(Effect):
+    """
+    Shrink free space reported by statfs to zero over `duration` while ramping up ENOSPC on writes,
+    stay full for `hold`, then recover
+    """
+
+    def __init__(
+        self, duration: DurationOrMs, hold: DurationOrMs = 0, max_prob: float = 1.0, op: str = "ws"
+    ):
+        data = {"duration_ms": _to_ms(duration), "hold_ms": _to_ms(hold), "max_prob": max_prob}
+        super().__init__(op, data)
+
+
+class Signal(Effect):
+    """
+    Send `signal` to the process issuing the operation, or to all processes named `process`.
+    Fires once unless `repeat` is set
+    """
+
+    def __init__(
+        self,
+        signal: int | str = "SIGKILL",
+        process: str | None = None,
+        prob: float = 1.0,
+        repeat: bool = False,
+        op: str = "f",
+    ):
+        data = {"signal": signal, "process": process, "prob": prob, "repeat": repeat}
+        super().__init__(op, data)
+
+
+class DiskFull(Effect):
+    """
+    Fail writes with ENOSPC once `budget` bytes have been written, overwrites included.
+    With `statfs` free space reported by statfs shrinks as the budget is used up
+    """
+
+    def __init__(self, budget: int, statfs: bool = False, op: str = "ws"):
+        super().__init__(op, {"budget": budget, "statfs": statfs})
+
+
+class ReadOnly(Effect):
+    """Fail writes and metadata changes with `err`, EROFS by default"""
+
+    def __init__(self, err: int | str = "EROFS", op: str = "wm"):
+        super().__init__(op, {"errno": err})
+
+
+class AccessDenied(Effect):
+    """
+    Fail operations with `err` with probability `prob` or every `every`th call, always if neither is set
+    """
+
+    def __init__(
+        self,
+        prob: float | None = None,
+        every: int | None = None,
+        err: int | str = "EACCES",
+        op: str = "rwm",
+    ):
+        super().__init__(op, {"prob": prob, "every": every, "errno": err})
+
+
+class Busy(Effect):
+    """Fail unlink, rmdir and rename with EBUSY with probability `prob`"""
+
+    def __init__(self, prob: float = 1.0, op: str = "m"):
+        super().__init__(op, {"prob": prob})
+
+
+class CrossDevice(Effect):
+    """Fail renames between different `subtrees` with EXDEV, as if they were separate mounts"""
+
+    _TYPE = "xdev"
+
+    def __init__(self, subtrees: list[str], op: str = "m"):
+        super().__init__(op, {"subtrees": subtrees})
+
+
+class Stale(Effect):
+    """
+    Fail operations on nodes with ESTALE once renamed or `after` their lookup, until looked up again
+    """
+
+    _TYPE = "estale"
+
+    def __init__(self, renamed: bool = True, after: DurationOrMs | None = None, op: str = "rwlmf"):
+        after_ms = _to_ms(after) if after is not None else None
+        super().__init__(op, {"renamed": renamed, "after_ms": after_ms})
+
+
+class NameRules(Effect):
+    """
+    Fail new names longer than `max_len` bytes with ENAMETOOLONG and with `forbidden` characters with EINVAL
+    """
+
+    def __init__(self, max_len: int | None = None, forbidden: str = "", op: str = "m"):
+        super().__init__(op, {"max_len": max_len, "forbidden": forbidden})
+
+
+class Iops(Effect):
+    """
+    Delay operations beyond `limit` per second and bursts of `burst`,
+    shared by the subtree or for every file with `per_file`
+    """
+
+    def __init__(self, limit: int, burst: int = 1, per_file: bool = False, op: str = "rw"):
+        super().__init__(op, {"limit": limit, "burst": burst, "per_file": per_file})
+
+
+class Ttfb(Effect):
+    """Delay the first read through every file handle by `delay`"""
+
+    def __init__(self, delay: DurationOrMs, op: str = "r"):
+        super().__init__(op, {"delay_ms": _to_ms(delay)})
+
+
+class SeekLatency(Effect):
+    """
+    Delay reads and writes by `per_mib_ms` for every MiB of distance from the previous operation on the file,
+    up to `max_delay`
+    """
+
+    _TYPE = "seeklat"
+
+    def __init__(self, per_mib_ms: float, max_delay: DurationOrMs | None = None, op: str = "rw"):
+        max_ms = _to_ms(max_delay) if max_delay is not None else None
+        super().__init__(op, {"per_mib_ms": per_mib_ms, "max_ms": max_ms})
+
+
+class Aging(Effect):
+    """Delay reads by `per_write_us` for every write the file has seen, up to `max_delay`"""
+
+    def __init__(self, per_write_us: int, max_delay: DurationOrMs | None = None, op: str = "r"):
+        max_ms = _to_ms(max_delay) if max_delay is not None else None
+        super().__init__(op, {"per_write_us": per_write_us, "max_ms": max_ms})
+
+
+class Burst(Effect):
+    """Start storms of `length` consecutive errors with probability `prob` per healthy operation"""
+
+    def __init__(self, prob: float, length: int, err: int | str = "EIO", op: str = "rw"):
+        super().__init__(op, {"prob": prob, "length": length, "errno": err})
+
+
+class Hang(Effect):
+    """Hold replies with probability `prob` until the effect is removed or `release` is called on its node"""
+
+    def __init__(self, prob: float = 1.0, op: str = "rw"):
+        super().__init__(op, {"prob": prob})
+
+
+class Deadline(Effect):
+    """Delay operations by `timeout`, then fail them with `err`, with probability `prob`"""
+
+    def __init__(self, timeout: DurationOrMs, err: int | str = "EIO", prob: float = 1.0, op: str = "rw"):
+        super().__init__(op, {"timeout_ms": _to_ms(timeout), "errno": err, "prob": prob})
+
+
+class Dying(Effect):
+    """
+    Grow delays to `max_delay` and error probability to `max_prob` along `curve`
+    over `over` time or `over_ops` operations
+    """
+
+    def __init__(
+        self,
+        over: DurationOrMs | None = None,
+        over_ops: int | None = None,
+        max_delay: DurationOrMs = 0,
+        max_prob: float = 0.0,
+        curve: str = "linear",
+        err: int | str = "EIO",
+        op: str = "rw",
+    ):
+        data = {
+            "over_ms": _to_ms(over) if over is not None else None,
+            "over_ops": over_ops,
+            "max_delay_ms": _to_ms(max_delay),
+            "max_prob": max_prob,
+            "curve": curve,
+            "errno": err,
+        }
+        super().__init__(op, data)
+
+
+class MaxConc(Effect):
+    """Fail operations with `err` while `limit` operations are active in the subtree, or file with `per_file`"""
+
+    def __init__(self, limit: int, per_file: bool = False, err: int | str = "EBUSY", op: str = "rw"):
+        super().__init__(op, {"limit": limit, "per_file": per_file, "errno": err})
+
+
+class Throttle(Effect):
+    """
+    Delay reads and writes beyond `bytes_per_sec` and bursts of `burst_bytes`,
+    shared by the subtree or for every file with `per_file`
+    """
+
+    def __init__(
+        self, bytes_per_sec: int, burst_bytes: int | None = None, per_file: bool = False, op: str = "rw"
+    ):
+        super().__init__(op, {"bytes_per_sec": bytes_per_sec, "burst_bytes": burst_bytes, "per_file": per_file})
+
+
+class Script(Effect):
+    """
+    Decide on operations with a Lua chunk, receiving the operation as `...` and `rand()` for random numbers.
+    It returns decisions like External, or nothing to let the operation pass. Scripts running longer
+    than `timeout` fall back to `fallback`
+    """
+
+    def __init__(
+        self,
+        source: str,
+        fallback: dict = {"decision": "ok"},
+        timeout: DurationOrMs = 100,
+        op: str = "rw",
+    ):
+        super().__init__(op, {"source": source, "fallback": fallback, "timeout_ms": _to_ms(timeout)})
+
+
+class Hook(Effect):
+    """
+    Let an external `command` (json request on stdin) or http webhook `url` (json request POSTed) decide
+    on every operation. It answers with decisions like External, or nothing to let the operation pass
+    """
+
+    def __init__(
+        self,
+        command: str | None = None,
+        url: str | None = None,
+        timeout: DurationOrMs = 1000,
+        fallback: dict = {"decision": "ok"},
+        op: str = "rw",
+    ):
+        data = {"command": command, "url": url, "timeout_ms": _to_ms(timeout), "fallback": fallback}
+        super().__init__(op, data)
+
+
+class Quota(Effect):
+    """
+    Fail reads and writes with EDQUOT once their volume, rounded up to `align`, exceeds `volume` bytes
+    """
+
+    def __init__(self, volume: int, align: int = 1, op: str = "rw"):
+        super().__init__(op, {"volume": volume, "align": align})
+
+
+class Vanish(Effect):
+    """
+    Fail lookups of existing entries with ENOENT by a scenario like Flakey: probability or (available, unavailable) durations
+    """
+
+    def __init__(self, cond: Flakey.Cond = 1.0, op: str = "l"):
+        match cond:
+            case float():
+                data = {"prob": cond}
+            case (avail, unavail):
+                data = {"avail_ms": _to_ms(avail), "unavail_ms": _to_ms(unavail)}
+        super().__init__(op, data)
+
+
+class DirFlake(Effect):
+    """
+    Omit entries from directory listings with probability `prob` each, without touching the tree
+    """
+
+    def __init__(self, prob: float = 1.0, op: str = "d"):
+        super().__init__(op, {"prob": prob})
+
+
+class DirShuffle(Effect):
+    """
+    Shuffle directory listings differently every time and list entries twice with probability `dup_prob` each
+    """
+
+    def __init__(self, shuffle: bool = True, dup_prob: float = 0.0, op: str = "d"):
+        super().__init__(op, {"shuffle": shuffle, "dup_prob": dup_prob})
+
+
+class FakeFs(Effect):
+    """
+    Report `blocks` with `bavail` free or the `used` fraction taken, and `files` with `ffree` free inodes with statfs
+    """
+
+    def __init__(
+        self,
+        blocks: int = 262144,
+        bavail: int | None = None,
+        used: float | None = None,
+        files: int | None = None,
+        ffree: int | None = None,
+        op: str = "s",
+    ):
+        super().__init__(op, {"blocks": blocks, "bavail": bavail, "used": used, "files": files, "ffree": ffree})
+
+
+class Xattr(Effect):
+    """
+    Fail operations on user extended attributes with `err` and probability `prob`,
+    or with `max_size` only setting larger values with E2BIG. With `erange` reading values or
+    name lists into a buffer fails with ERANGE, as if they grew since their size was probed
+    """
+
+    def __init__(
+        self,
+        err: int | str = "ENOTSUP",
+        prob: float = 1.0,
+        max_size: int | None = None,
+        erange: bool = False,
+        op: str = "x",
+    ):
+        super().__init__(op, {"errno": err, "prob": prob, "max_size": max_size, "erange": erange})
+
+
 class Heatmap(Effect):
     """
-    Heatmap of given operation
+    Heatmap of given operation, display() returns offsets with the number of operations covering them
     """
 
-    def __init__(self, align: int = 1, op: str = "rw"):
+    def __init__(self, align: int = 4096, op: str = "rw"):
         super().__init__(op, {"align": align})
 
 
+class Heal(Effect):
+    """
+    Repair damaged blocks once they have been damaged for `after`
+    """
+
+    def __init__(self, after: DurationOrMs = 0, op: str = "rw"):
+        super().__init__(op, {"after_ms": _to_ms(after)})
+
+
+class Ramp(Effect):
+    """
+    Interpolate numeric parameters of a child effect over time, e.g.
+    Ramp(Flakey(0.0), {"prob": (0.0, 0.5)}, timedelta(minutes=10)). Values ramped between
+    ints stay ints. Children keeping state between operations are refused
+    """
+
+    def __init__(
+        self,
+        effect: Effect,
+        ramp: dict[str, typing.Tuple[float, float]],
+        duration: DurationOrMs,
+        op: str = "rw",
+    ):
+        data = {
+            "effect": effect._TYPE or type(effect).__name__.lower(),
+            "params": effect._data,
+            "ramp": {k: list(v) for k, v in ramp.items()},
+            "duration_ms": _to_ms(duration),
+        }
+        super().__init__(op, data)
+
+
 class Fuse:
     """Manages a running broken fuse"""
 
-    def __init__(self, mount_dir: os.PathLike):
+    def __init__(self, mount_dir: os.PathLike, *args: str):
         self._mount_dir = mount_dir
+        self._args = list(args)
 
     def start(self):
-        cmd = ["brokenfuse", str(self._mount_dir)]
+        cmd = ["brokenfuse", str(self._mount_dir), *self._args]
         self._proc = subprocess.Popen(cmd)
         while True:
             try:
@@ -133,9 +882,155 @@ def clear(path: FdOrPath):
     os.removexattr(path, "bf.effect")
 
 
+def preset(path: FdOrPath, name: str | None):
+    """Install a preset like "nfs-wan" or "dying-hdd", replacing the previous one, or remove it if None"""
+    if name is None:
+        os.removexattr(path, "bf.effect.preset")
+    else:
+        os.setxattr(path, "bf.effect.preset", name.encode("utf8"))
+
+
 def display(path: FdOrPath, effect: Effect):
     return json.loads(os.getxattr(path, f"bf.effect.{effect.name}").decode("utf8"))
 
 
+def schema(path: FdOrPath, type: str | None = None):
+    """Parameters of effect `type` with their types and defaults, or of all effect types"""
+    name = "bf.effect.help" if type is None else f"bf.effect.help.{type}"
+    return json.loads(os.getxattr(path, name).decode("utf8"))
+
+
+def effective(path: FdOrPath):
+    """Effects applying to the node in order of application, annotated with their origin path"""
+    return json.loads(os.getxattr(path, "bf.effect/effective").decode("utf8"))
+
+
+def effect_stats(path: FdOrPath):
+    """Counters of effects attached to the node by name: times evaluated and triggered, delay and errors injected"""
+    return json.loads(os.getxattr(path, "bf.effect/stats").decode("utf8"))
+
+
 def stats(path: FdOrPath):
     return json.loads(os.getxattr(path, "bf.stats").decode("utf8"))
+
+
+def damage(path: FdOrPath, offset: int, length: int):
+    """Corrupt stored data in range, it can be repaired later"""
+    data = json.dumps({"offset": offset, "len": length}).encode("utf-8")
+    os.setxattr(path, "bf.damage", data)
+
+
+def repair(path: FdOrPath):
+    """Repair all damaged blocks"""
+    os.setxattr(path, "bf.repair", b"")
+
+
+def events(path: FdOrPath):
+    return json.loads(os.getxattr(path, "bf.events").decode("utf8"))
+
+
+def dirstats(path: FdOrPath):
+    """Number of directories below `path`, their entries, how many are indexed and the largest one"""
+    return json.loads(os.getxattr(path, "bf.dirstats").decode("utf8"))
+
+
+def doctor(path: FdOrPath):
+    """Run internal consistency checks, returns problems found and outstanding handle and write counts"""
+    return json.loads(os.getxattr(path, "bf.doctor").decode("utf8"))
+
+
+def badblocks(path: FdOrPath):
+    """Bad ranges of files below `path` by their path, loadable with --badblocks"""
+    return json.loads(os.getxattr(path, "bf.badblocks").decode("utf8"))
+
+
+def trace(path: FdOrPath, capacity: int | None = None):
+    """
+    Effects consulted by the most recent operations and what they did.
+    Set `capacity` to trace that many operations, 0 turns tracing off
+    """
+    if capacity is not None:
+        os.setxattr(path, "bf.trace", str(capacity).encode("utf-8"))
+    return json.loads(os.getxattr(path, "bf.trace").decode("utf8"))
+
+
+def metrics(path: FdOrPath):
+    """Sampled operation counts, errors and injected latency per operation kind, oldest first"""
+    return json.loads(os.getxattr(path, "bf.metrics").decode("utf8"))
+
+
+def inflight(path: FdOrPath):
+    """List delayed operations that haven't been replied to yet"""
+    return json.loads(os.getxattr(path, "bf.inflight").decode("utf8"))
+
+
+def inflight_stats(path: FdOrPath):
+    """Number of in-flight operations, their limit and how many were replied to early due to it"""
+    return json.loads(os.getxattr(path, "bf.inflight/stats").decode("utf8"))
+
+
+def finish(path: FdOrPath, id: int, err: int | str | None = None):
+    """Reply to in-flight operation `id` right away, failing it with `err` if given"""
+    data = json.dumps({"id": id, "errno": err}).encode("utf-8")
+    os.setxattr(path, "bf.inflight", data)
+
+
+def order(dir: FdOrPath, order: str | None):
+    """
+    Set listing order of directory: insertion, lexicographic, reverse, hashed or random.
+    Reset to the mount default with None
+    """
+    if order is None:
+        os.removexattr(dir, "bf.order")
+    else:
+        os.setxattr(dir, "bf.order", order.encode("utf-8"))
+
+
+def idle(path: FdOrPath) -> datetime.timedelta:
+    """Time passed without io in subtree of `path`"""
+    return datetime.timedelta(milliseconds=int(os.getxattr(path, "bf.idle")))
+
+
+def release(path: FdOrPath):
+    """Release operations held by hang effects attached at `path`"""
+    os.setxattr(path, "bf.release", b"")
+
+
+def reset_quota(path: FdOrPath):
+    """Reset consumption of quota effects attached at `path`"""
+    os.setxattr(path, "bf.quota.reset", b"")
+
+
+def powercut(path: FdOrPath):
+    """Drop data not fsynced yet of files below `path`, requires --power-cuts"""
+    os.setxattr(path, "bf.powercut", b"")
+
+
+def snapshot(path: FdOrPath, name: str):
+    """Snapshot state of subtree at `path` under `name`"""
+    os.setxattr(path, f"bf.snapshot.{name}", b"")
+
+
+def diff(path: FdOrPath, since: str, to: str | None = None):
+    """Diff report between snapshots `since` and `to`, or `since` and the current state"""
+    spec = since if to is None else f"{since}..{to}"
+    return json.loads(os.getxattr(path, f"bf.diff.{spec}").decode("utf8"))
+
+
+def generate(dir: FdOrPath, name: str, size: int, kind: str = "zeros", **params):
+    """
+    Create a read only file of `size` bytes in `dir` whose contents are generated on read.
+    Kinds: zeros, random (seed=), repeat (pattern=), urandom
+    """
+    data = json.dumps({"size": size, "kind": kind, **params}).encode("utf-8")
+    os.setxattr(dir, f"bf.generate.{name}", data)
+
+
+def readonly(path: FdOrPath, enabled: bool = True):
+    """Fail writes and metadata changes below `path` with EROFS until turned off"""
+    os.setxattr(path, "bf.readonly", b"1" if enabled else b"0")
+
+
+def direct_io(path: FdOrPath, enabled: bool = True):
+    """Bypass page cache for file, takes effect on next open"""
+    os.setxattr(path, "bf.direct_io", b"1" if enabled else b"0")
@@ -0,0 +1,246 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JValue};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::effect::{Context, OpDesr};
+
+// Hit every `every`th matching operation, starting with the `offset`th (1-based), or the `every`th
+// without an offset
+#[derive(Serialize, Deserialize)]
+pub struct Nth {
+    every: u64,
+    #[serde(default)]
+    offset: u64,
+    #[serde(skip)]
+    seen: Cell<u64>,
+}
+
+impl Nth {
+    fn hit(&self) -> bool {
+        let seen = self.seen.get() + 1;
+        self.seen.set(seen);
+        let every = self.every.max(1);
+        let first = if self.offset == 0 { every } else { self.offset };
+        seen >= first && (seen - first) % every == 0
+    }
+}
+
+// Byte range of a file reads and writes have to overlap
+#[derive(Serialize, Deserialize)]
+pub struct Range {
+    #[serde(default)]
+    offset: usize,
+    len: usize,
+}
+
+impl Range {
+    // Operations without a byte range don't match
+    fn overlaps(&self, op: &OpDesr) -> bool {
+        let (offset, len) = match *op {
+            OpDesr::Read { offset, len, .. } | OpDesr::Write { offset, len, .. } => (offset, len),
+            _ => return false,
+        };
+        offset < self.offset.saturating_add(self.len) && self.offset < offset.saturating_add(len)
+    }
+}
+
+// Conditions generic to all effects, given among effect parameters. Effects apply only to
+// operations matching all of them. Nested conditions in all/any/not combine them with boolean logic
+#[derive(Serialize, Deserialize)]
+pub struct Conditions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nth: Option<Nth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl_ms: Option<u64>, // deactivate the effect after this time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_after_ms: Option<u64>, // activate the effect only after this time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    active_ms: Option<u64>, // keep the effect active only this long after it activated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repeat_ms: Option<u64>, // repeat the activation window with this period
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_hits: Option<u64>, // deactivate the effect after it fired this many times
+    #[serde(skip)]
+    hits: Cell<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skip: Option<u64>, // let this many matching operations pass first
+    #[serde(skip)]
+    skipped: Cell<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>, // glob of target path relative to where the effect is attached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comm: Option<String>, // regex of the caller's process name
+    #[serde(skip)]
+    comm_re: Option<Regex>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    range: Option<Range>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    all: Vec<Conditions>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    any: Vec<Conditions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not: Option<Box<Conditions>>,
+    #[serde(skip, default = "Instant::now")]
+    installed: Instant,
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Conditions {
+            nth: None,
+            ttl_ms: None,
+            start_after_ms: None,
+            active_ms: None,
+            repeat_ms: None,
+            max_hits: None,
+            hits: Cell::new(0),
+            skip: None,
+            skipped: Cell::new(0),
+            path: None,
+            uid: None,
+            gid: None,
+            pid: None,
+            comm: None,
+            comm_re: None,
+            range: None,
+            all: vec![],
+            any: vec![],
+            not: None,
+            installed: Instant::now(),
+        }
+    }
+}
+
+// Parameters taken by conditions instead of effects
+const KEYS: &[&str] = &["nth", "ttl_ms", "start_after_ms", "active_ms", "repeat_ms", "max_hits", "skip", "path", "uid", "gid", "pid", "comm", "range", "all", "any", "not"];
+
+impl Conditions {
+    // Take condition parameters out of effect parameters
+    pub fn take(params: &mut JValue) -> serde_json::Result<Conditions> {
+        let mut taken = Map::new();
+        if let Some(obj) = params.as_object_mut() {
+            for key in KEYS {
+                if let Some(value) = obj.remove(*key) {
+                    taken.insert(key.to_string(), value);
+                }
+            }
+        }
+        let mut conds: Conditions = serde_json::from_value(JValue::Object(taken))?;
+        conds.compile().map_err(serde::de::Error::custom)?;
+        Ok(conds)
+    }
+
+    // Compile regexes of this and nested conditions
+    fn compile(&mut self) -> Result<(), regex::Error> {
+        if let Some(ref comm) = self.comm {
+            // Match the whole name, as it's short and partial matches are rarely intended
+            self.comm_re = Some(Regex::new(&format!("^(?:{})$", comm))?);
+        }
+        let nested = self.all.iter_mut().chain(self.any.iter_mut()).chain(self.not.as_deref_mut());
+        nested.map(Conditions::compile).collect()
+    }
+
+    // Whether the effect outlived its ttl or fired all of its hits
+    pub fn expired(&self) -> bool {
+        self.ttl_ms.is_some_and(|ttl| self.installed.elapsed() >= Duration::from_millis(ttl))
+            || self.max_hits.is_some_and(|max| self.hits.get() >= max)
+    }
+
+    // Whether the effect is within its scheduled activation window
+    fn scheduled(&self) -> bool {
+        let elapsed = self.installed.elapsed().as_millis() as u64;
+        let Some(mut since) = elapsed.checked_sub(self.start_after_ms.unwrap_or(0)) else {
+            return false;
+        };
+        if let Some(period) = self.repeat_ms.filter(|p| *p > 0) {
+            since %= period;
+        }
+        self.active_ms.is_none_or(|active| since < active)
+    }
+
+    // Count the effect firing, i.e. not just acking an operation
+    pub fn fired(&self) {
+        self.hits.set(self.hits.get() + 1);
+    }
+
+    // Whether the operation is still among the skipped ones
+    fn skipping(&self) -> bool {
+        let skipped = self.skipped.get();
+        if skipped >= self.skip.unwrap_or(0) {
+            return false;
+        }
+        self.skipped.set(skipped + 1);
+        true
+    }
+
+    // Whether the target path matches the glob. Globs without slashes match only the name
+    fn path_matches(&self, ctx: &Context) -> bool {
+        let Some(ref glob) = self.path else {
+            return true;
+        };
+        let Some(mut path) = ctx.tree.relative_path(ctx.target, ctx.origin) else {
+            return false;
+        };
+        if let Some(name) = ctx.op.entry_name() {
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(name);
+        }
+        match glob.contains('/') {
+            true => crate::util::glob_match(glob, &path),
+            false => crate::util::glob_match(glob, path.rsplit('/').next().unwrap_or_default()),
+        }
+    }
+
+    // Whether the operation was issued by the selected caller
+    fn caller_matches(&self, ctx: &Context) -> bool {
+        let caller = &ctx.caller;
+        self.uid.is_none_or(|uid| uid == caller.uid)
+            && self.gid.is_none_or(|gid| gid == caller.gid)
+            && self.pid.is_none_or(|pid| pid == caller.pid)
+            && self.comm_re.as_ref().is_none_or(|re| {
+                crate::util::comm(caller.pid as i32).is_some_and(|comm| re.is_match(&comm))
+            })
+    }
+
+    // Whether the effect applies to the operation of `ctx`
+    pub fn admit(&self, ctx: &Context) -> bool {
+        !self.expired()
+            && self.scheduled()
+            && self.range.as_ref().is_none_or(|range| range.overlaps(&ctx.op))
+            && self.caller_matches(ctx)
+            && self.path_matches(ctx)
+            && self.all.iter().all(|c| c.admit(ctx))
+            && (self.any.is_empty() || self.any.iter().any(|c| c.admit(ctx)))
+            && self.not.as_ref().is_none_or(|c| !c.admit(ctx))
+            && !self.skipping()
+            && self.nth.as_ref().is_none_or(Nth::hit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hits(every: u64, offset: u64) -> Vec<u64> {
+        let nth = Nth { every, offset, seen: Cell::new(0) };
+        (1..=12).filter(|_| nth.hit()).collect()
+    }
+
+    #[test]
+    fn nth_starts_at_offset() {
+        assert_eq!(hits(3, 0), [3, 6, 9, 12]);
+        assert_eq!(hits(3, 1), [1, 4, 7, 10]);
+        assert_eq!(hits(2, 5), [5, 7, 9, 11]);
+        assert_eq!(hits(4, 10), [10]);
+    }
+}
@@ -1,25 +1,121 @@
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JValue;
 use std::{
     collections,
-    time::{SystemTime, UNIX_EPOCH},
+    rc::Rc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
     usize,
 };
 
 use crate::{
-    effect::{Context, Effect, EffectResult, OpDesr},
-    ftypes::ErrNo,
+    effect::{
+        Action, Chunking, CloneMode, Context, Effect, EffectResult, HangGate, OpDesr, OpType,
+        Scrub, Verify, VerifyStats, create_effect, service_ms,
+    },
+    events,
+    ftypes::{ErrNo, Ino, NodeItem, errno},
 };
 
-// Delay processing by X ms. {"duration_ms": 100}
+// Probability of effects firing on every selected operation unless set
+fn default_prob() -> f32 {
+    1.0
+}
+
+// Random latency drawn from a distribution, sampled with the context rng so runs are reproducible
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "dist", rename_all = "lowercase")]
+pub enum Jitter {
+    Normal { mean_ms: f64, stddev_ms: f64 },
+    Exponential { mean_ms: f64 },
+    Pareto { scale_ms: f64, shape: f64 }, // heavy tailed, never below scale_ms
+}
+
+impl Jitter {
+    fn sample(&self, rgen: &mut rand::rngs::StdRng) -> u64 {
+        // Uniform in (0, 1], so logarithms and powers stay finite
+        let u = 1.0 - rgen.random::<f64>();
+        let ms = match *self {
+            Jitter::Normal { mean_ms, stddev_ms } => {
+                // Box-Muller transform
+                let v = rgen.random::<f64>();
+                mean_ms + stddev_ms * (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos()
+            }
+            Jitter::Exponential { mean_ms } => -mean_ms * u.ln(),
+            Jitter::Pareto { scale_ms, shape } => scale_ms / u.powf(1.0 / shape),
+        };
+        ms.max(0.0) as u64
+    }
+}
+
+// Delay processing by X ms, reads and writes can be delayed differently, like on SMR drives.
+// Random `jitter` is added on top. With `min_len` or `max_len` only reads and writes of that many
+// bytes are delayed. {"duration_ms": 100} or {"read_ms": 1, "write_ms": 50, "min_len": 65536}
+// or {"jitter": {"dist": "pareto", "scale_ms": 1, "shape": 1.5}}
 #[derive(Serialize, Deserialize)]
 pub struct Delay {
+    #[serde(default)]
     duration_ms: u64,
+    read_ms: Option<u64>,
+    write_ms: Option<u64>,
+    jitter: Option<Jitter>,
+    min_len: Option<usize>,
+    max_len: Option<usize>,
 }
 
 impl Effect for Delay {
-    fn apply(&self, _ctx: &mut Context) -> EffectResult {
-        EffectResult::Delay(self.duration_ms)
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let (ms, len) = match ctx.op {
+            OpDesr::Read { len, .. } => (self.read_ms, Some(len)),
+            OpDesr::Write { len, .. } => (self.write_ms, Some(len)),
+            _ => (None, None),
+        };
+        if self.min_len.is_some() || self.max_len.is_some() {
+            let fits = len.is_some_and(|len| {
+                self.min_len.is_none_or(|min| len >= min) && self.max_len.is_none_or(|max| len <= max)
+            });
+            if !fits {
+                return EffectResult::Ack;
+            }
+        }
+        let jitter = self.jitter.as_ref().map_or(0, |j| j.sample(ctx.rgen));
+        EffectResult::Delay(ms.unwrap_or(self.duration_ms) + jitter)
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Shape latencies toward target percentiles, accounting for the service time observed for the kind of
+// operation and delays of other effects.
+// {"percentiles": [[50, 5], [99, 200]]} - p50 = 5ms, p99 = 200ms. Percentiles are ascending
+#[derive(Serialize, Deserialize)]
+pub struct Slo {
+    percentiles: Vec<(f32, u64)>,
+}
+
+impl Slo {
+    // Sample latency from the piecewise linear distribution defined by the percentiles
+    fn sample(&self, rgen: &mut rand::rngs::StdRng) -> u64 {
+        let p = rgen.random::<f32>() * 100.0;
+        let mut prev = (0.0f32, 0u64);
+        for &(pct, ms) in &self.percentiles {
+            if p <= pct {
+                let frac = (p - prev.0) / (pct - prev.0).max(f32::EPSILON);
+                return (prev.1 as f32 + (ms as f32 - prev.1 as f32) * frac) as u64;
+            }
+            prev = (pct, ms);
+        }
+        prev.1
+    }
+}
+
+impl Effect for Slo {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let target = self.sample(ctx.rgen);
+        let spent = service_ms(ctx.op.name()) + ctx.delayed_ms;
+        EffectResult::Delay(target.saturating_sub(spent))
     }
 
     fn as_any(&self) -> &dyn std::any::Any {
@@ -34,16 +130,33 @@ enum FlakeyCondition {
     Interval { avail_ms: u64, unavail_ms: u64 },
 }
 
+impl FlakeyCondition {
+    fn hit(&self, rgen: &mut rand::rngs::StdRng) -> bool {
+        match *self {
+            FlakeyCondition::Prob { prob } => rgen.random::<f32>() <= prob,
+            FlakeyCondition::Interval { avail_ms, unavail_ms } => {
+                let passed_ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis();
+
+                let rem = (passed_ms) % ((avail_ms + unavail_ms) as u128);
+                rem <= avail_ms as u128
+            }
+        }
+    }
+}
+
 // Return `errno` (EIO by default) with:
 // 1. Always or never {"always": true/false }
-// 2. `prob`% probability {"prob": 0.3, "errno": 5}
+// 2. `prob`% probability {"prob": 0.3, "errno": "EIO"}
 // 3. `avail`/`unavail` intervals in milliseconds {"avail": 5, "unavail": 10}
 #[derive(Serialize, Deserialize)]
 pub struct Flakey {
     #[serde(flatten)]
     cond: FlakeyCondition,
-    #[serde(default = "Flakey::default_errno")]
-    errno: libc::c_int,
+    #[serde(default = "Flakey::default_errno", with = "errno")]
+    errno: ErrNo,
 }
 
 impl Flakey {
@@ -54,24 +167,10 @@ impl Flakey {
 
 impl Effect for Flakey {
     fn apply(&self, ctx: &mut Context) -> EffectResult {
-        let ret = |b| {
-            if b {
-                EffectResult::Error(self.errno)
-            } else {
-                EffectResult::Ack
-            }
-        };
-        match self.cond {
-            FlakeyCondition::Prob { prob } => ret(ctx.rgen.random::<f32>() <= prob),
-            FlakeyCondition::Interval { avail_ms, unavail_ms } => {
-                let passed_ms = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis();
-
-                let rem = (passed_ms) % ((avail_ms + unavail_ms) as u128);
-                ret(rem <= avail_ms as u128)
-            }
+        if self.cond.hit(ctx.rgen) {
+            EffectResult::Error(self.errno)
+        } else {
+            EffectResult::Ack
         }
     }
 
@@ -88,7 +187,7 @@ pub struct MaxSize {
 impl Effect for MaxSize {
     fn apply(&self, ctx: &mut Context) -> EffectResult {
         let (offset, len) = match &ctx.op {
-            OpDesr::Write { offset, len } => (offset, len),
+            OpDesr::Write { offset, len, .. } => (offset, len),
             _ => return EffectResult::Ack,
         };
 
@@ -119,47 +218,25 @@ impl Effect for MaxSize {
     }
 }
 
-// Build heatmap for given file
+// Build heatmap for given file, ranges are rounded to `align` bytes (4096 by default).
+// Serialized as points where the number of operations covering the file changes
 #[derive(Serialize, Deserialize)]
 pub struct HeatMap {
+    #[serde(default = "HeatMap::default_align")]
     align: usize,
-    #[serde(skip)]
+    #[serde(skip_deserializing, rename = "points", serialize_with = "HeatMap::serialize_points")]
     values: std::cell::RefCell<
         std::collections::BTreeMap<(usize /* offset */, usize /* len */), usize>,
     >,
 }
 
-impl Effect for HeatMap {
-    fn apply(&self, ctx: &mut Context) -> EffectResult {
-        let (mut offset, mut len) = match &ctx.op {
-            OpDesr::Write { offset, len } => (*offset, *len),
-            OpDesr::Read { offset, len } => (*offset, *len),
-            _ => return EffectResult::Ack,
-        };
-
-        // Crop parameters
-        let file_size = ctx.tree.get(ctx.target).unwrap().attr.size;
-
-        offset = offset.min(file_size as usize);
-        len = len.min(file_size as usize - offset);
-        offset = offset / self.align * self.align;
-        len = (len + self.align - 1) / self.align * self.align;
-
-        self.values
-            .borrow_mut()
-            .entry((offset, len))
-            .and_modify(|e| *e += 1)
-            .or_insert(1);
-
-        EffectResult::Ack
-    }
-
-    fn as_any(&self) -> &dyn std::any::Any {
-        return self;
+impl HeatMap {
+    fn default_align() -> usize {
+        4096
     }
 
-    fn display(&self) -> Option<String> {
-        let values = self.values.borrow();
+    // Offsets with the number of operations covering the file from them on
+    fn points(values: &collections::BTreeMap<(usize, usize), usize>) -> Vec<(usize, usize)> {
         let mut out: Vec<(usize, usize)> = vec![];
         let mut record = |offset, balance| {
             if let Some((last, last_balance)) = out.last_mut()
@@ -171,12 +248,13 @@ impl Effect for HeatMap {
             }
         };
 
-        let mut removals = collections::BTreeSet::<(usize, usize)>::new();
+        // Operations by their end
+        let mut removals = collections::BTreeMap::<usize, usize>::new();
 
         let mut balance: usize = 0;
         for (start, add_delta) in values.iter() {
             // Subtract all operations that end at this index
-            while let Some((off, rem_delta)) = removals.first()
+            while let Some((off, rem_delta)) = removals.first_key_value()
                 && *off <= start.0
             {
                 balance -= rem_delta;
@@ -186,7 +264,7 @@ impl Effect for HeatMap {
 
             // Apply current operation and add it to removals
             balance += *add_delta;
-            removals.insert((start.0 + start.1, *add_delta));
+            *removals.entry(start.0 + start.1).or_default() += *add_delta;
             record(start.0, balance);
         }
 
@@ -194,28 +272,86 @@ impl Effect for HeatMap {
             balance -= rem_delta;
             record(off, balance);
         }
+        out
+    }
+
+    fn serialize_points<S: serde::Serializer>(
+        values: &std::cell::RefCell<collections::BTreeMap<(usize, usize), usize>>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        HeatMap::points(&values.borrow()).serialize(s)
+    }
+}
+
+impl Effect for HeatMap {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let (mut offset, mut len) = match &ctx.op {
+            OpDesr::Write { offset, len, .. } => (*offset, *len),
+            OpDesr::Read { offset, len, .. } => (*offset, *len),
+            _ => return EffectResult::Ack,
+        };
+
+        // Crop parameters
+        let file_size = ctx.tree.get(ctx.target).unwrap().attr.size;
+        let align = self.align.max(1);
 
-        Some(serde_json::to_string(&out).unwrap())
+        offset = offset.min(file_size as usize);
+        len = len.min(file_size as usize - offset);
+        offset = offset / align * align;
+        len = (len + align - 1) / align * align;
+
+        self.values
+            .borrow_mut()
+            .entry((offset, len))
+            .and_modify(|e| *e += 1)
+            .or_insert(1);
+
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+
+    fn display(&self) -> Option<String> {
+        Some(serde_json::to_string(&HeatMap::points(&self.values.borrow())).unwrap())
     }
 }
 
+// Limit volume of reads and writes, return EDQUOT once exceeded. Operations are rounded up to `align`.
+// Consumption is shown with getfattr and reset with bf.quota.reset. {"volume": 1048576, "align": 4096}
 #[derive(Serialize, Deserialize)]
 pub struct Quota {
+    #[serde(alias = "limit")]
     volume: usize,
+    #[serde(default = "Quota::default_align")]
     align: usize,
-    #[serde(skip)]
-    current: std::cell::Cell<usize>
+    #[serde(skip_deserializing)]
+    current: std::cell::Cell<usize>,
+}
+
+impl Quota {
+    fn default_align() -> usize {
+        1
+    }
+
+    pub fn reset(&self) {
+        self.current.set(0);
+    }
 }
 
 impl Effect for Quota {
     fn apply(&self, ctx: &mut Context) -> EffectResult {
-         let (_, mut len) = match &ctx.op {
-            OpDesr::Write { offset, len } => (*offset, *len),
-            OpDesr::Read { offset, len } => (*offset, *len),
+        let len = match &ctx.op {
+            OpDesr::Write { len, .. } | OpDesr::Read { len, .. } => *len,
             _ => return EffectResult::Ack,
         };
-        len = (len + self.align - 1) / self.align * self.align;
-        self.current.update(|v| v + len);
+        let align = self.align.max(1);
+        self.current.update(|v| v + len.div_ceil(align) * align);
 
         if self.current.get() < self.volume {
             EffectResult::Ack
@@ -225,6 +361,2467 @@ impl Effect for Quota {
     }
 
     fn as_any(&self) -> &dyn std::any::Any {
-        return  self;
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+
+    fn display(&self) -> Option<String> {
+        Some(serde_json::json!({"used": self.current.get(), "volume": self.volume}).to_string())
+    }
+}
+
+// Repair damaged blocks of the target once they have been damaged for `after_ms`.
+// Repairs happen before the operation is processed, so reads observe repaired data. {"after_ms": 1000}
+#[derive(Serialize, Deserialize)]
+pub struct Heal {
+    #[serde(default)]
+    after_ms: u64,
+}
+
+impl Effect for Heal {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        ctx.actions.push(Action::Repair {
+            ino: ctx.target,
+            older_than: Duration::from_millis(self.after_ms),
+        });
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+#[derive(Deserialize)]
+struct RampSpec {
+    effect: String,
+    #[serde(default)]
+    params: serde_json::Map<String, JValue>,
+    ramp: collections::BTreeMap<String, (serde_json::Number, serde_json::Number)>,
+    duration_ms: u64,
+}
+
+// Interpolate numeric parameters of a child effect between start and end values over a duration.
+// Values ramped between integers are rounded, so they fit integer parameters.
+// {"effect": "flakey", "params": {"errno": "EIO"}, "ramp": {"prob": [0.0, 0.5]}, "duration_ms": 600000}
+// The child is created again whenever its parameters change, so children keeping state are refused
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "RampSpec")]
+pub struct Ramp {
+    effect: String,
+    params: serde_json::Map<String, JValue>,
+    ramp: collections::BTreeMap<String, (serde_json::Number, serde_json::Number)>,
+    duration_ms: u64,
+    #[serde(skip_serializing)]
+    installed: Instant,
+    #[serde(skip_serializing)]
+    child: std::cell::RefCell<(serde_json::Map<String, JValue>, Box<dyn Effect>)>, // with its parameters
+}
+
+impl TryFrom<RampSpec> for Ramp {
+    type Error = String;
+
+    // Create the child on attach, so invalid children are rejected right away
+    fn try_from(spec: RampSpec) -> Result<Self, Self::Error> {
+        let params = Ramp::interpolate(&spec.params, &spec.ramp, 0.0);
+        let child = Ramp::create(&spec.effect, &params)?;
+        Ok(Ramp {
+            effect: spec.effect,
+            params: spec.params,
+            ramp: spec.ramp,
+            duration_ms: spec.duration_ms,
+            installed: Instant::now(),
+            child: std::cell::RefCell::new((params, child)),
+        })
+    }
+}
+
+impl Ramp {
+    // Child parameters at `t` in [0, 1] of the ramp
+    fn interpolate(
+        params: &serde_json::Map<String, JValue>,
+        ramp: &collections::BTreeMap<String, (serde_json::Number, serde_json::Number)>,
+        t: f64,
+    ) -> serde_json::Map<String, JValue> {
+        let mut params = params.clone();
+        for (key, (start, end)) in ramp {
+            let (from, to) = (start.as_f64().unwrap_or_default(), end.as_f64().unwrap_or_default());
+            let value = from + (to - from) * t;
+            let value: JValue = match start.is_f64() || end.is_f64() {
+                true => value.into(),
+                false => (value.round() as i64).into(),
+            };
+            params.insert(key.clone(), value);
+        }
+        params
+    }
+
+    fn create(
+        effect: &str,
+        params: &serde_json::Map<String, JValue>,
+    ) -> Result<Box<dyn Effect>, String> {
+        let (_, child) = create_effect(effect, JValue::Object(params.clone()))
+            .map_err(|errno| format!("invalid child effect {}: errno {}", effect, errno))?;
+        if child.stateful() {
+            return Err(format!("child effect {} keeps state", effect));
+        }
+        Ok(child)
+    }
+
+    // Child parameters at the current point in time
+    fn current(&self) -> serde_json::Map<String, JValue> {
+        let elapsed = self.installed.elapsed().as_millis() as f64;
+        let t = (elapsed / self.duration_ms.max(1) as f64).min(1.0);
+        Ramp::interpolate(&self.params, &self.ramp, t)
+    }
+}
+
+impl Effect for Ramp {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let params = self.current();
+        let mut child = self.child.borrow_mut();
+        if child.0 != params {
+            match Ramp::create(&self.effect, &params) {
+                Ok(effect) => *child = (params, effect),
+                Err(_) => return EffectResult::Error(libc::EINVAL),
+            }
+        }
+        child.1.apply(ctx)
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn display(&self) -> Option<String> {
+        Some(JValue::Object(self.current()).to_string())
+    }
+
+    fn side_effects(&self) -> bool {
+        self.child.borrow().1.side_effects()
+    }
+
+    fn stateful(&self) -> bool {
+        true
+    }
+}
+
+// Cap read/write sizes at `limit` bytes, returning short results or EINVAL with `reject`. {"limit": 4096}
+#[derive(Serialize, Deserialize)]
+pub struct MaxIo {
+    limit: usize,
+    #[serde(default)]
+    reject: bool,
+}
+
+impl Effect for MaxIo {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let len = match &ctx.op {
+            OpDesr::Write { len, .. } | OpDesr::Read { len, .. } => *len,
+            _ => return EffectResult::Ack,
+        };
+        if len <= self.limit {
+            return EffectResult::Ack;
+        }
+        if self.reject {
+            return EffectResult::Error(libc::EINVAL);
+        }
+        ctx.changes.max_len = Some(ctx.changes.max_len.map_or(self.limit, |m| m.min(self.limit)));
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Persist writes in chunks of `chunk` bytes with `interval_ms` in between, other operations
+// are processed meanwhile and observe torn intermediate states. {"chunk": 4096, "interval_ms": 10}
+#[derive(Serialize, Deserialize)]
+pub struct Interleave {
+    chunk: usize,
+    interval_ms: u64,
+}
+
+impl Effect for Interleave {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if let OpDesr::Write { len, .. } = ctx.op
+            && len > self.chunk
+        {
+            ctx.changes.chunking = Some(Chunking {
+                size: self.chunk,
+                interval_ms: self.interval_ms,
+            });
+        }
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Fail or delay operations serving the page cache: page aligned reads, which serve page faults
+// of mmap'd files and buffered reads, and writebacks of cached pages. {"errno": "EIO"} or {"delay_ms": 100}
+#[derive(Serialize, Deserialize)]
+pub struct PageFault {
+    #[serde(default, with = "errno::option")]
+    errno: Option<ErrNo>,
+    #[serde(default)]
+    delay_ms: u64,
+}
+
+impl PageFault {
+    const PAGE_SIZE: usize = 4096;
+}
+
+impl Effect for PageFault {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let matches = match ctx.op {
+            OpDesr::Read { offset, len, .. } => {
+                offset % Self::PAGE_SIZE == 0 && len % Self::PAGE_SIZE == 0
+            }
+            OpDesr::Write { cached, .. } => cached,
+            _ => false,
+        };
+        match (matches, self.errno) {
+            (false, _) => EffectResult::Ack,
+            (true, Some(errno)) => EffectResult::Error(errno),
+            (true, None) => EffectResult::Delay(self.delay_ms),
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Return resource exhaustion errors with `prob` probability. {"prob": 0.1, "errno": "EAGAIN"}
+// ENOMEM by default, EAGAIN is the other common choice
+#[derive(Serialize, Deserialize)]
+pub struct NoMem {
+    prob: f32,
+    #[serde(default = "NoMem::default_errno", with = "errno")]
+    errno: ErrNo,
+}
+
+impl NoMem {
+    fn default_errno() -> ErrNo {
+        libc::ENOMEM
+    }
+}
+
+impl Effect for NoMem {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if ctx.rgen.random::<f32>() < self.prob {
+            EffectResult::Error(self.errno)
+        } else {
+            EffectResult::Ack
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Rewrite targets returned by readlink, rotating through `targets` or picking them at random.
+// {"targets": ["releases/1", "releases/2"], "random": false}
+#[derive(Serialize, Deserialize)]
+pub struct Relink {
+    targets: Vec<std::path::PathBuf>,
+    #[serde(default)]
+    random: bool,
+    #[serde(skip)]
+    next: std::cell::Cell<usize>,
+}
+
+impl Effect for Relink {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if !matches!(ctx.op, OpDesr::Readlink) || self.targets.is_empty() {
+            return EffectResult::Ack;
+        }
+        let idx = if self.random {
+            ctx.rgen.random_range(0..self.targets.len())
+        } else {
+            self.next.replace((self.next.get() + 1) % self.targets.len())
+        };
+        ctx.changes.link_target = Some(self.targets[idx].clone());
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DivergeMode {
+    Stale,   // contents as of the first read through the entry
+    Corrupt, // inverted bytes
+}
+
+// Make reads through the hard link entry `name` return different contents than through others.
+// {"name": "copy.db", "mode": "stale"}
+#[derive(Serialize, Deserialize)]
+pub struct Diverge {
+    name: String,
+    mode: DivergeMode,
+    #[serde(skip)]
+    snapshots: std::cell::RefCell<collections::HashMap<Ino, Vec<u8>>>,
+}
+
+impl Effect for Diverge {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let OpDesr::Read { offset, len, .. } = ctx.op else {
+            return EffectResult::Ack;
+        };
+        if ctx.entry.as_ref().is_none_or(|(_, name)| *name != self.name) {
+            return EffectResult::Ack;
+        }
+        let Some(NodeItem::File(file)) = ctx.tree.get(ctx.target).map(|n| &n.item) else {
+            return EffectResult::Ack;
+        };
+
+        let data = match self.mode {
+            DivergeMode::Stale => {
+                let mut snapshots = self.snapshots.borrow_mut();
+                let snapshot = snapshots.entry(ctx.target).or_insert_with(|| {
+                    file.storage().read(0, file.storage().len()).into_owned()
+                });
+                let start = offset.min(snapshot.len());
+                let end = (offset + len).min(snapshot.len());
+                snapshot[start..end].to_vec()
+            }
+            DivergeMode::Corrupt => {
+                let mut data = file.storage().read(offset, len).into_owned();
+                data.iter_mut().for_each(|b| *b = !*b);
+                data
+            }
+        };
+        ctx.changes.read_data = Some(data);
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Charge latency on lookups proportional to the depth of the resolved path, modeling
+// filesystems resolving whole paths remotely. {"per_component_ms": 2}
+#[derive(Serialize, Deserialize)]
+pub struct PathLatency {
+    per_component_ms: u64,
+}
+
+impl Effect for PathLatency {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if !matches!(ctx.op, OpDesr::Lookup { .. }) {
+            return EffectResult::Ack;
+        }
+        // Components of the looked up path: directories from root and the looked up name
+        let depth = ctx.tree.climb(ctx.target).count() as u64;
+        EffectResult::Delay(self.per_component_ms * depth)
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Decision of an external decider
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(tag = "decision", rename_all = "lowercase")]
+enum Decision {
+    Ok,
+    Error {
+        #[serde(with = "errno")]
+        errno: ErrNo,
+    },
+    Delay { delay_ms: u64 },
+}
+
+// Let an external process decide on operations. Every operation is sent as length prefixed
+// (u32 big endian) json to a unix socket and answered the same way with a decision:
+// {"decision": "ok"}, {"decision": "error", "errno": "EIO"} or {"decision": "delay", "delay_ms": 100}.
+// The fallback decision applies if the decider doesn't answer within timeout.
+// {"socket": "/tmp/decider.sock", "timeout_ms": 100, "fallback": {"decision": "ok"}}
+#[derive(Serialize, Deserialize)]
+pub struct External {
+    socket: std::path::PathBuf,
+    #[serde(default = "External::default_timeout")]
+    timeout_ms: u64,
+    #[serde(default = "External::default_fallback")]
+    fallback: Decision,
+    #[serde(skip)]
+    conn: std::cell::RefCell<Option<std::os::unix::net::UnixStream>>,
+}
+
+impl External {
+    fn default_timeout() -> u64 {
+        1000
+    }
+
+    fn default_fallback() -> Decision {
+        Decision::Ok
+    }
+
+    fn ask(&self, request: &[u8]) -> std::io::Result<Decision> {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        let mut conn = self.conn.borrow_mut();
+        if conn.is_none() {
+            let stream = UnixStream::connect(&self.socket)?;
+            let timeout = Some(Duration::from_millis(self.timeout_ms.max(1)));
+            stream.set_read_timeout(timeout)?;
+            stream.set_write_timeout(timeout)?;
+            *conn = Some(stream);
+        }
+        let stream = conn.as_mut().unwrap();
+
+        let result = (|| {
+            stream.write_all(&(request.len() as u32).to_be_bytes())?;
+            stream.write_all(request)?;
+            let mut len = [0u8; 4];
+            stream.read_exact(&mut len)?;
+            let mut response = vec![0u8; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut response)?;
+            serde_json::from_slice(&response).map_err(std::io::Error::other)
+        })();
+        // Connection state is unknown after a failure, start over next time
+        if result.is_err() {
+            *conn = None;
+        }
+        result
+    }
+}
+
+impl Effect for External {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let request = serde_json::json!({
+            "op": &ctx.op,
+            "ino": ctx.target,
+            "path": ctx.tree.path(ctx.target),
+            "origin": ctx.tree.path(ctx.origin),
+        });
+        let decision = self
+            .ask(request.to_string().as_bytes())
+            .unwrap_or(self.fallback);
+        match decision {
+            Decision::Ok => EffectResult::Ack,
+            Decision::Error { errno } => EffectResult::Error(errno),
+            Decision::Delay { delay_ms } => EffectResult::Delay(delay_ms),
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Time until which each simulated device is busy serving queued operations, by device name
+static DEVICE_QUEUES: std::sync::Mutex<collections::BTreeMap<String, Instant>> =
+    std::sync::Mutex::new(collections::BTreeMap::new());
+
+// Simulated device shared by all effects with the same name. Operations queue up and occupy it
+// for `op_us` plus their transfer time, so heavy io on one file slows down others on the same device.
+// Reads and writes can have different bandwidth with `read_bytes_per_sec` and `write_bytes_per_sec`.
+// {"name": "disk0", "bytes_per_sec": 10485760, "op_us": 100}
+#[derive(Serialize, Deserialize)]
+pub struct Device {
+    name: String,
+    bytes_per_sec: u64,
+    read_bytes_per_sec: Option<u64>, // bandwidth of reads and writes if they differ
+    write_bytes_per_sec: Option<u64>,
+    #[serde(default)]
+    op_us: u64,
+}
+
+impl Effect for Device {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let (len, bytes_per_sec) = match ctx.op {
+            OpDesr::Read { len, .. } => (len as u64, self.read_bytes_per_sec),
+            OpDesr::Write { len, .. } => (len as u64, self.write_bytes_per_sec),
+            _ => (0, None),
+        };
+        let bytes_per_sec = bytes_per_sec.unwrap_or(self.bytes_per_sec);
+        let service = Duration::from_micros(self.op_us)
+            + Duration::from_secs_f64(len as f64 / bytes_per_sec.max(1) as f64);
+
+        let now = Instant::now();
+        let mut queues = DEVICE_QUEUES.lock().unwrap();
+        let busy_until = queues.entry(self.name.clone()).or_insert(now);
+        let done = (*busy_until).max(now) + service;
+        *busy_until = done;
+        EffectResult::Delay((done - now).as_millis() as u64)
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Read back data after each write and compare it with what was written. Mismatches are counted
+// and recorded as events, and fail the write with `errno` if set. {"errno": "EIO"}
+#[derive(Serialize, Deserialize)]
+pub struct WriteVerify {
+    #[serde(default, with = "errno::option")]
+    errno: Option<ErrNo>,
+    #[serde(skip)]
+    stats: Rc<VerifyStats>,
+}
+
+impl Effect for WriteVerify {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if let OpDesr::Write { .. } = ctx.op {
+            ctx.changes.verify = Some(Verify {
+                errno: self.errno,
+                stats: self.stats.clone(),
+            });
+        }
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn stateful(&self) -> bool {
+        true
+    }
+
+    fn display(&self) -> Option<String> {
+        Some(serde_json::to_string(&*self.stats).unwrap())
+    }
+}
+
+// Break copy-on-write semantics of copied ranges: with `copy` they silently become deep copies,
+// with `shared` writes to either file show up in both. {"mode": "shared"}
+#[derive(Serialize, Deserialize)]
+pub struct BrokenCow {
+    mode: CloneMode,
+}
+
+impl Effect for BrokenCow {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        ctx.changes.clone_mode = Some(self.mode);
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WindowRepr {
+    Range(u64, u64), // offsets in ms within the period
+    Clock(String),   // "HH:MM-HH:MM" in UTC
+}
+
+fn parse_windows<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<(u64, u64)>, D::Error> {
+    let clock_ms = |s: &str| {
+        let (h, m) = s.trim().split_once(':')?;
+        Some(h.parse::<u64>().ok()? * 3_600_000 + m.parse::<u64>().ok()? * 60_000)
+    };
+    Vec::<WindowRepr>::deserialize(d)?
+        .into_iter()
+        .map(|w| match w {
+            WindowRepr::Range(start, end) => Some((start, end)),
+            WindowRepr::Clock(s) => s.split_once('-').and_then(|(a, b)| Some((clock_ms(a)?, clock_ms(b)?))),
+        })
+        .map(|w| w.ok_or_else(|| serde::de::Error::custom("invalid window")))
+        .collect()
+}
+
+// Fail operations with `errno` (EBUSY by default) during recurring windows, like backup windows or
+// maintenance locks. Windows repeat every `period_ms` (a day by default) counted from the unix epoch,
+// so daily windows are in UTC and may wrap around midnight.
+// {"windows": ["00:00-01:00"]} or {"period_ms": 60000, "windows": [[0, 10000]]}
+#[derive(Serialize, Deserialize)]
+pub struct ReadOnlyWindow {
+    #[serde(deserialize_with = "parse_windows")]
+    windows: Vec<(u64, u64)>,
+    #[serde(default = "ReadOnlyWindow::default_period")]
+    period_ms: u64,
+    #[serde(default = "ReadOnlyWindow::default_errno", with = "errno")]
+    errno: ErrNo,
+}
+
+impl ReadOnlyWindow {
+    fn default_period() -> u64 {
+        24 * 3_600_000
+    }
+
+    fn default_errno() -> ErrNo {
+        libc::EBUSY
+    }
+}
+
+impl Effect for ReadOnlyWindow {
+    fn apply(&self, _ctx: &mut Context) -> EffectResult {
+        let passed_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let now = (passed_ms % self.period_ms.max(1) as u128) as u64;
+        let inside = self.windows.iter().any(|&(start, end)| {
+            if start <= end {
+                (start..end).contains(&now)
+            } else {
+                now >= start || now < end
+            }
+        });
+        if inside {
+            EffectResult::Error(self.errno)
+        } else {
+            EffectResult::Ack
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Serve spurious negative lookups of names created within the last `window_ms` with `prob`
+// probability. The kernel caches them for `ttl_ms`, like a stale negative cache of nfs.
+// {"window_ms": 1000, "ttl_ms": 1000}
+#[derive(Serialize, Deserialize)]
+pub struct NegativeLookup {
+    window_ms: u64,
+    #[serde(default = "NegativeLookup::default_ttl")]
+    ttl_ms: u64,
+    #[serde(default = "default_prob")]
+    prob: f32,
+}
+
+impl NegativeLookup {
+    fn default_ttl() -> u64 {
+        1000
+    }
+}
+
+impl Effect for NegativeLookup {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let OpDesr::Lookup { ref name } = ctx.op else {
+            return EffectResult::Ack;
+        };
+        let Some(NodeItem::Dir(dir)) = ctx.tree.get(ctx.target).map(|n| &n.item) else {
+            return EffectResult::Ack;
+        };
+        let Some(child) = dir.lookup(name.as_str()).and_then(|ino| ctx.tree.get(ino)) else {
+            return EffectResult::Ack;
+        };
+        let fresh = child
+            .attr
+            .crtime
+            .elapsed()
+            .is_ok_and(|age| age < Duration::from_millis(self.window_ms));
+        if fresh && ctx.rgen.random::<f32>() < self.prob {
+            ctx.changes.negative_ttl = Some(Duration::from_millis(self.ttl_ms));
+        }
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Apply a child effect only to operations ending at least `idle_ms` of quiescence in the subtree it's
+// attached at, like during a checkpoint quiesce. The child keeps its state between operations.
+// {"idle_ms": 5000, "effect": "flakey", "params": {"prob": 1.0}}
+#[derive(Serialize, Deserialize)]
+pub struct OnIdle {
+    idle_ms: u64,
+    effect: String,
+    #[serde(default)]
+    params: serde_json::Map<String, JValue>,
+    #[serde(skip)]
+    child: std::cell::RefCell<Option<Box<dyn Effect>>>,
+}
+
+impl Effect for OnIdle {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if ctx.tree.idle(ctx.origin) < Duration::from_millis(self.idle_ms) {
+            return EffectResult::Ack;
+        }
+        let mut child = self.child.borrow_mut();
+        if child.is_none() {
+            *child = create_effect(&self.effect, JValue::Object(self.params.clone()))
+                .ok()
+                .map(|(_, effect)| effect);
+        }
+        child.as_ref().map_or(EffectResult::Ack, |child| child.apply(ctx))
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FlipUnit {
+    #[default]
+    Bit,
+    Byte,
+}
+
+// Flip `count` random bits or bytes of `data`
+fn flip(data: &mut [u8], count: usize, unit: FlipUnit, rgen: &mut impl Rng) {
+    if data.is_empty() {
+        return;
+    }
+    for _ in 0..count {
+        let pos = rgen.random_range(0..data.len());
+        data[pos] ^= match unit {
+            FlipUnit::Bit => 1 << rgen.random_range(0..8),
+            FlipUnit::Byte => rgen.random_range(1..=255),
+        };
+    }
+}
+
+// Flip `count` random bits (or bytes with "unit": "byte") in data returned by reads with `prob`
+// probability, leaving stored data intact. {"count": 1, "prob": 0.1}
+#[derive(Serialize, Deserialize)]
+pub struct CorruptRead {
+    #[serde(default = "CorruptRead::default_count")]
+    count: usize,
+    #[serde(default)]
+    unit: FlipUnit,
+    #[serde(default = "default_prob")]
+    prob: f32,
+}
+
+impl CorruptRead {
+    fn default_count() -> usize {
+        1
+    }
+}
+
+impl Effect for CorruptRead {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let OpDesr::Read { offset, len, .. } = ctx.op else {
+            return EffectResult::Ack;
+        };
+        if ctx.rgen.random::<f32>() >= self.prob {
+            return EffectResult::Ack;
+        }
+        let mut data = match ctx.changes.read_data.take() {
+            Some(data) => data,
+            None => match ctx.tree.get(ctx.target).map(|n| &n.item) {
+                Some(NodeItem::File(file)) => file.storage().read(offset, len).into_owned(),
+                _ => return EffectResult::Ack,
+            },
+        };
+        flip(&mut data, self.count, self.unit, ctx.rgen);
+        ctx.changes.read_data = Some(data);
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Corruption of written data before it's persisted
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum Corruption {
+    Flip {
+        #[serde(default = "CorruptRead::default_count")]
+        count: usize,
+        #[serde(default)]
+        unit: FlipUnit,
+    },
+    Zero {
+        len: usize, // zero a range of this length at a random position
+    },
+    Shift {
+        by: i64, // persist at offset moved by this many bytes, like misdirect
+    },
+}
+
+impl Corruption {
+    // Corrupt `data` to be written
+    pub fn apply(&self, data: &mut [u8], rgen: &mut impl Rng) {
+        match *self {
+            Corruption::Flip { count, unit } => flip(data, count, unit, rgen),
+            Corruption::Zero { len } => {
+                let len = len.min(data.len());
+                let start = rgen.random_range(0..=data.len() - len);
+                data[start..start + len].fill(0);
+            }
+            // Data stays intact, the offset is moved like for misdirected writes
+            Corruption::Shift { .. } => (),
+        }
+    }
+}
+
+// Corrupt written data with `prob` probability before it's persisted, so corruption is durable:
+// flip bits, zero ranges or shift offsets. {"mode": "flip", "count": 1}, {"mode": "zero", "len": 512}
+// or {"mode": "shift", "by": -4096}
+#[derive(Serialize, Deserialize)]
+pub struct CorruptWrite {
+    #[serde(flatten)]
+    corruption: Corruption,
+    #[serde(default = "default_prob")]
+    prob: f32,
+}
+
+impl Effect for CorruptWrite {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if matches!(ctx.op, OpDesr::Write { .. }) && ctx.rgen.random::<f32>() < self.prob {
+            match self.corruption {
+                Corruption::Shift { by } => {
+                    ctx.changes.misdirect = Some(ctx.changes.misdirect.unwrap_or(0) + by)
+                }
+                corruption => ctx.changes.corruption = Some(corruption),
+            }
+        }
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Allow or deny operations by name for a subtree, failing the rest with `errno` (EPERM by default).
+// {"allow": ["read", "lookup"]} or {"deny": ["write"]}
+#[derive(Serialize, Deserialize)]
+pub struct Policy {
+    allow: Option<Vec<String>>,
+    #[serde(default)]
+    deny: Vec<String>,
+    #[serde(default = "Policy::default_errno", with = "errno")]
+    errno: ErrNo,
+}
+
+impl Policy {
+    fn default_errno() -> ErrNo {
+        libc::EPERM
+    }
+}
+
+impl Effect for Policy {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let name = ctx.op.name();
+        let allowed = self.allow.as_ref().is_none_or(|allow| allow.iter().any(|a| a == name));
+        if allowed && !self.deny.iter().any(|d| d == name) {
+            EffectResult::Ack
+        } else {
+            EffectResult::Error(self.errno)
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Coalesce writes into batches persisted all at once `window_ms` after the first write of the batch.
+// Writes complete right away, but readers see their data only once the batch is flushed.
+// Splitting writes is done by interleave. {"window_ms": 100}
+#[derive(Serialize, Deserialize)]
+pub struct Coalesce {
+    window_ms: u64,
+}
+
+impl Effect for Coalesce {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if let OpDesr::Write { .. } = ctx.op {
+            ctx.changes.coalesce = Some(Duration::from_millis(self.window_ms));
+        }
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Acknowledge writes with their full length without persisting them, with `prob` probability or
+// every `every`th write. Lost writes are recorded as events. {"prob": 0.01} or {"every": 100}
+#[derive(Serialize, Deserialize)]
+pub struct LostWrite {
+    prob: Option<f32>,
+    every: Option<usize>,
+    #[serde(skip)]
+    writes: std::cell::Cell<usize>,
+}
+
+impl Effect for LostWrite {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let OpDesr::Write { offset, len, .. } = ctx.op else {
+            return EffectResult::Ack;
+        };
+        self.writes.set(self.writes.get() + 1);
+        let nth = self.every.is_some_and(|every| self.writes.get() % every.max(1) == 0);
+        let hit = self.prob.is_some_and(|prob| ctx.rgen.random::<f32>() < prob);
+        if nth || hit {
+            ctx.changes.lost = true;
+            events::emit(ctx.target, "lost", serde_json::json!({"offset": offset, "len": len}));
+        }
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Persist only a prefix of writes up to a random `align` boundary within them with `prob` probability,
+// while acknowledging their full length, like a power loss mid-write. Writes within a single
+// aligned block are never torn. {"align": 512, "prob": 0.1}
+#[derive(Serialize, Deserialize)]
+pub struct Torn {
+    #[serde(default = "Torn::default_align")]
+    align: usize,
+    #[serde(default = "default_prob")]
+    prob: f32,
+}
+
+impl Torn {
+    fn default_align() -> usize {
+        512
+    }
+}
+
+impl Effect for Torn {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let OpDesr::Write { offset, len, .. } = ctx.op else {
+            return EffectResult::Ack;
+        };
+        let align = self.align.max(1);
+        // Aligned boundaries strictly within the write
+        let first = offset / align + 1;
+        let last = (offset + len).saturating_sub(1) / align;
+        if len == 0 || first > last || ctx.rgen.random::<f32>() >= self.prob {
+            return EffectResult::Ack;
+        }
+        let kept = ctx.rgen.random_range(first..=last) * align - offset;
+        ctx.changes.torn = Some(kept);
+        events::emit(ctx.target, "torn", serde_json::json!({"offset": offset, "len": len, "kept": kept}));
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Persist writes at a wrong offset of the same file with `prob` probability, like a misdirected write
+// of buggy firmware. Offsets are moved by `by` bytes, or by a block in a random direction by default.
+// Misdirected writes are recorded as events. {"prob": 0.01, "by": 4096}
+#[derive(Serialize, Deserialize)]
+pub struct Misdirect {
+    prob: f32,
+    by: Option<i64>,
+}
+
+impl Effect for Misdirect {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let OpDesr::Write { offset, len, .. } = ctx.op else {
+            return EffectResult::Ack;
+        };
+        if ctx.rgen.random::<f32>() >= self.prob {
+            return EffectResult::Ack;
+        }
+        let by = self.by.unwrap_or_else(|| {
+            let blksize = ctx.tree.get(ctx.target).map_or(4096, |n| n.attr.blksize as i64);
+            if ctx.rgen.random() { blksize } else { -blksize }
+        });
+        // Writes at the start of the file can't move backwards
+        let by = by.max(-(offset as i64));
+        ctx.changes.misdirect = Some(ctx.changes.misdirect.unwrap_or(0) + by);
+        events::emit(ctx.target, "misdirected", serde_json::json!({"offset": offset, "len": len, "by": by}));
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Persist writes a second time at the next `align` boundary after their offset with `prob` probability,
+// like io replayed by a flaky transport. The block size of the target by default.
+// Duplicated writes are recorded as events. {"prob": 0.01, "align": 4096}
+#[derive(Serialize, Deserialize)]
+pub struct DupWrite {
+    #[serde(default = "default_prob")]
+    prob: f32,
+    align: Option<usize>,
+}
+
+impl Effect for DupWrite {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let OpDesr::Write { offset, len, .. } = ctx.op else {
+            return EffectResult::Ack;
+        };
+        if ctx.rgen.random::<f32>() >= self.prob {
+            return EffectResult::Ack;
+        }
+        let align = self
+            .align
+            .or_else(|| ctx.tree.get(ctx.target).map(|n| n.attr.blksize as usize))
+            .unwrap_or(4096)
+            .max(1);
+        let at = (offset / align + 1) * align;
+        ctx.changes.duplicate = Some(at);
+        events::emit(ctx.target, "duplicated", serde_json::json!({"offset": offset, "len": len, "at": at}));
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Fail reads overlapping any of `ranges` of the target with `errno` (EIO by default), like unreadable
+// sectors, while reads of other ranges succeed. Ranges of specific files below the node the effect
+// is attached at are listed in `paths` by their path, like in a bad block map.
+// {"ranges": [[4096, 512], [1048576, 4096]]} or {"paths": {"/data/db": [[0, 512]]}}
+#[derive(Serialize, Deserialize)]
+pub struct BadRange {
+    #[serde(default)]
+    ranges: Vec<(usize, usize)>, // (offset, len)
+    #[serde(default)]
+    paths: collections::BTreeMap<String, Vec<(usize, usize)>>,
+    #[serde(default = "Flakey::default_errno", with = "errno")]
+    errno: ErrNo,
+}
+
+impl BadRange {
+    // Bad ranges of the file at `path`
+    pub fn ranges_of(&self, path: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let listed = self.paths.get(path).into_iter().flatten();
+        self.ranges.iter().chain(listed).copied()
+    }
+}
+
+impl Effect for BadRange {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let OpDesr::Read { offset, len, .. } = ctx.op else {
+            return EffectResult::Ack;
+        };
+        let path = match self.paths.is_empty() {
+            true => String::new(),
+            false => ctx.tree.path(ctx.target).unwrap_or_default(),
+        };
+        let overlaps = |(start, rlen): (usize, usize)| start < offset + len && offset < start + rlen;
+        match self.ranges_of(&path).any(overlaps) {
+            true => EffectResult::Error(self.errno),
+            false => EffectResult::Ack,
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Error of an error model with its frequency
+#[derive(Serialize, Deserialize)]
+struct ModelError {
+    #[serde(with = "errno")]
+    errno: ErrNo,
+    freq: f32,
+}
+
+// Behaviour of an operation kind in an error model
+#[derive(Serialize, Deserialize)]
+struct OpModel {
+    #[serde(default)]
+    errors: Vec<ModelError>,
+    latency: Option<Slo>,
+}
+
+// Make operations fail and take as long as described by an empirical model, like one derived from
+// production traces, with error frequencies and latency percentiles per operation name.
+// {"ops": {"read": {"errors": [{"errno": "EIO", "freq": 0.001}], "latency": {"percentiles": [[50, 2], [99, 40]]}}}}
+#[derive(Serialize, Deserialize)]
+pub struct Model {
+    ops: collections::BTreeMap<String, OpModel>,
+}
+
+impl Effect for Model {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let Some(model) = self.ops.get(ctx.op.name()) else {
+            return EffectResult::Ack;
+        };
+        let mut p = ctx.rgen.random::<f32>();
+        for error in &model.errors {
+            if p < error.freq {
+                return EffectResult::Error(error.errno);
+            }
+            p -= error.freq;
+        }
+        match model.latency {
+            Some(ref latency) => latency.apply(ctx),
+            None => EffectResult::Ack,
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Keep shadow checksums of blocks of `block` bytes on write and verify them on read, counting
+// mismatching blocks and recording them as events. Serves as an oracle that corruption injected on
+// the way reaches readers. {"block": 4096}
+#[derive(Serialize, Deserialize)]
+pub struct ScrubEffect {
+    #[serde(default = "ScrubEffect::default_block")]
+    block: usize,
+    #[serde(skip)]
+    state: std::cell::OnceCell<Rc<Scrub>>,
+}
+
+impl ScrubEffect {
+    fn default_block() -> usize {
+        4096
+    }
+
+    fn state(&self) -> &Rc<Scrub> {
+        self.state.get_or_init(|| Rc::new(Scrub::new(self.block)))
+    }
+}
+
+impl Effect for ScrubEffect {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if let OpDesr::Read { .. } | OpDesr::Write { .. } = ctx.op {
+            ctx.changes.scrub = Some(self.state().clone());
+        }
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn stateful(&self) -> bool {
+        true
+    }
+
+    fn display(&self) -> Option<String> {
+        Some(serde_json::to_string(&**self.state()).unwrap())
+    }
+}
+
+// Flip random bits of stored data of files below the node at `rate` flips per second, like bit rot
+// of idle media. Rot happens independently of io to the files and is recorded as events. {"rate": 0.5}
+#[derive(Serialize, Deserialize)]
+pub struct Rot {
+    rate: f64,
+    #[serde(skip)]
+    last: std::cell::Cell<Option<Instant>>,
+}
+
+impl Rot {
+    // Bits due to flip for the time passed since the last call
+    pub fn due(&self) -> u64 {
+        let now = Instant::now();
+        let last = self.last.get().unwrap_or(now);
+        let due = (now.duration_since(last).as_secs_f64() * self.rate) as u64;
+        self.last.set(Some(match due {
+            0 => last,
+            _ => last + Duration::from_secs_f64(due as f64 / self.rate),
+        }));
+        due
+    }
+}
+
+impl Effect for Rot {
+    fn apply(&self, _ctx: &mut Context) -> EffectResult {
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Scenario of a disk slowly filling up: over `duration_ms` the free space reported by statfs shrinks
+// to zero while writes fail with ENOSPC with a probability ramping up to `max_prob`. The disk stays
+// full for `hold_ms`, then space is freed again. Progress is recorded as events.
+// {"duration_ms": 600000, "hold_ms": 60000}
+#[derive(Serialize, Deserialize)]
+pub struct DiskFill {
+    duration_ms: u64,
+    #[serde(default)]
+    hold_ms: u64,
+    #[serde(default = "default_prob")]
+    max_prob: f32,
+    #[serde(skip, default = "Instant::now")]
+    installed: Instant,
+    #[serde(skip)]
+    reported: std::cell::Cell<u8>, // last progress step recorded, in tenths
+}
+
+impl DiskFill {
+    // Filled fraction and phase of the scenario
+    fn progress(&self) -> (f64, &'static str) {
+        let elapsed = self.installed.elapsed().as_millis() as u64;
+        if elapsed < self.duration_ms {
+            (elapsed as f64 / self.duration_ms as f64, "filling")
+        } else if elapsed < self.duration_ms + self.hold_ms {
+            (1.0, "full")
+        } else {
+            (0.0, "recovered")
+        }
+    }
+}
+
+impl Effect for DiskFill {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let (fill, phase) = self.progress();
+        let step = match phase {
+            "filling" => (fill * 10.0) as u8,
+            "full" => 10,
+            _ => 11,
+        };
+        if step > self.reported.get() {
+            self.reported.set(step);
+            events::emit(ctx.origin, "fill", serde_json::json!({"phase": phase, "fill": fill}));
+        }
+
+        match ctx.op {
+            OpDesr::Statfs => {
+                let free = ctx.changes.free_ratio.unwrap_or(1.0).min(1.0 - fill);
+                ctx.changes.free_ratio = Some(free);
+                EffectResult::Ack
+            }
+            OpDesr::Write { .. } if ctx.rgen.random::<f64>() < fill * self.max_prob as f64 => {
+                EffectResult::Error(libc::ENOSPC)
+            }
+            _ => EffectResult::Ack,
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+
+    fn display(&self) -> Option<String> {
+        let (fill, phase) = self.progress();
+        Some(serde_json::json!({"phase": phase, "fill": fill}).to_string())
+    }
+}
+
+// Signals by name, accepted besides numbers
+const SIGNALS: &[(&str, i32)] = &[
+    ("SIGHUP", libc::SIGHUP),
+    ("SIGINT", libc::SIGINT),
+    ("SIGQUIT", libc::SIGQUIT),
+    ("SIGABRT", libc::SIGABRT),
+    ("SIGKILL", libc::SIGKILL),
+    ("SIGUSR1", libc::SIGUSR1),
+    ("SIGSEGV", libc::SIGSEGV),
+    ("SIGUSR2", libc::SIGUSR2),
+    ("SIGTERM", libc::SIGTERM),
+    ("SIGCONT", libc::SIGCONT),
+    ("SIGSTOP", libc::SIGSTOP),
+];
+
+fn parse_signal<'de, D: serde::Deserializer<'de>>(d: D) -> Result<i32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Number(i32),
+        Name(String),
+    }
+    match Repr::deserialize(d)? {
+        Repr::Number(sig) => Ok(sig),
+        Repr::Name(name) => SIGNALS
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(&name) || n[3..].eq_ignore_ascii_case(&name))
+            .map(|(_, sig)| *sig)
+            .ok_or_else(|| serde::de::Error::custom("unknown signal")),
+    }
+}
+
+// Send `signal` (SIGKILL by default) to the process issuing the operation, or to all processes named
+// `process`, with `prob` probability. Fires only once unless `repeat` is set. Signals sent are
+// recorded as events. {"signal": "SIGKILL"} or {"signal": "SIGSTOP", "process": "postgres"}
+#[derive(Serialize, Deserialize)]
+pub struct Signal {
+    #[serde(default = "Signal::default_signal", deserialize_with = "parse_signal")]
+    signal: i32,
+    process: Option<String>,
+    #[serde(default = "default_prob")]
+    prob: f32,
+    #[serde(default)]
+    repeat: bool,
+    #[serde(skip)]
+    fired: std::cell::Cell<bool>,
+}
+
+impl Signal {
+    fn default_signal() -> i32 {
+        libc::SIGKILL
+    }
+}
+
+impl Effect for Signal {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if (self.fired.get() && !self.repeat) || ctx.rgen.random::<f32>() >= self.prob {
+            return EffectResult::Ack;
+        }
+        let pids = match self.process {
+            Some(ref name) => crate::util::pids_by_name(name),
+            None => vec![ctx.caller.pid as i32],
+        };
+        for pid in &pids {
+            unsafe { libc::kill(*pid, self.signal) };
+        }
+        self.fired.set(true);
+        events::emit(ctx.target, "signal", serde_json::json!({"pids": pids, "signal": self.signal}));
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Count bytes written below the node, overwrites included, and fail writes with ENOSPC once `budget`
+// would be exceeded. With `statfs` set, free space reported by statfs shrinks by the used fraction
+// of the budget. {"budget": 1048576, "statfs": true}
+#[derive(Serialize, Deserialize)]
+pub struct DiskFull {
+    budget: u64,
+    #[serde(default)]
+    statfs: bool,
+    #[serde(skip)]
+    written: std::cell::Cell<u64>,
+}
+
+impl Effect for DiskFull {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let written = self.written.get();
+        match ctx.op {
+            OpDesr::Statfs if self.statfs => {
+                let left = 1.0 - (written as f64 / self.budget as f64).min(1.0);
+                ctx.changes.free_ratio = Some(ctx.changes.free_ratio.unwrap_or(1.0).min(left));
+                EffectResult::Ack
+            }
+            OpDesr::Write { len, .. } if written + len as u64 > self.budget => {
+                EffectResult::Error(libc::ENOSPC)
+            }
+            OpDesr::Write { len, .. } => {
+                self.written.set(written + len as u64);
+                EffectResult::Ack
+            }
+            _ => EffectResult::Ack,
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+
+    fn display(&self) -> Option<String> {
+        Some(serde_json::json!({"written": self.written.get(), "budget": self.budget}).to_string())
+    }
+}
+
+// Fail all mutating operations with EROFS, like a filesystem remounted read only after errors.
+// Define it with `op: "wm"` or toggle it with the bf.readonly attribute. {"errno": "EROFS"}
+#[derive(Serialize, Deserialize)]
+pub struct ReadOnly {
+    #[serde(default = "ReadOnly::default_errno", with = "errno")]
+    errno: ErrNo,
+}
+
+impl ReadOnly {
+    fn default_errno() -> ErrNo {
+        libc::EROFS
+    }
+}
+
+impl Effect for ReadOnly {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        match ctx.op.mutating() {
+            true => EffectResult::Error(self.errno),
+            false => EffectResult::Ack,
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Fail operations with EACCES, or EPERM with `errno`, with `prob` probability or every `every`th
+// operation, always if neither is set. Modes are left untouched. {"prob": 0.1} or {"every": 3}
+#[derive(Serialize, Deserialize)]
+pub struct AccessDenied {
+    prob: Option<f32>,
+    every: Option<usize>,
+    #[serde(default = "AccessDenied::default_errno", with = "errno")]
+    errno: ErrNo,
+    #[serde(skip)]
+    calls: std::cell::Cell<usize>,
+}
+
+impl AccessDenied {
+    fn default_errno() -> ErrNo {
+        libc::EACCES
+    }
+}
+
+impl Effect for AccessDenied {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        self.calls.set(self.calls.get() + 1);
+        let nth = self.every.is_some_and(|every| self.calls.get() % every.max(1) == 0);
+        let hit = self.prob.is_some_and(|prob| ctx.rgen.random::<f32>() < prob);
+        match nth || hit || (self.prob.is_none() && self.every.is_none()) {
+            true => EffectResult::Error(self.errno),
+            false => EffectResult::Ack,
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Fail unlink, rmdir and rename with EBUSY with `prob` probability, like "file in use" errors of
+// windows and SMB shares. Other operations pass. {"prob": 0.5}
+#[derive(Serialize, Deserialize)]
+pub struct Busy {
+    #[serde(default = "default_prob")]
+    prob: f32,
+}
+
+impl Effect for Busy {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        match ctx.op {
+            OpDesr::Unlink { .. } | OpDesr::Rmdir { .. } | OpDesr::Rename { .. }
+                if ctx.rgen.random::<f32>() < self.prob =>
+            {
+                EffectResult::Error(libc::EBUSY)
+            }
+            _ => EffectResult::Ack,
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Fail renames between different `subtrees` with EXDEV, as if they were separate mounts, so
+// callers fall back to copy and unlink. Paths outside of all subtrees form one more mount.
+// {"subtrees": ["/data", "/tmp/**"]}
+#[derive(Serialize, Deserialize)]
+pub struct CrossDevice {
+    subtrees: Vec<String>,
+}
+
+impl CrossDevice {
+    // Index of the subtree `ino` is within
+    fn device(&self, ctx: &Context, ino: Ino) -> Option<usize> {
+        let path = ctx.tree.path(ino)?;
+        self.subtrees
+            .iter()
+            .position(|glob| crate::util::glob_match(&format!("{}/**", glob.trim_end_matches('/')), &path))
+    }
+}
+
+impl Effect for CrossDevice {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let OpDesr::Rename { newparent, .. } = ctx.op else {
+            return EffectResult::Ack;
+        };
+        match self.device(ctx, ctx.target) == self.device(ctx, newparent) {
+            true => EffectResult::Ack,
+            false => EffectResult::Error(libc::EXDEV),
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Fail operations on nodes with ESTALE once they have been renamed, or `after_ms` after they were
+// looked up, until they're looked up again, like stale NFS file handles. {"after_ms": 5000}
+#[derive(Serialize, Deserialize)]
+pub struct Stale {
+    #[serde(default = "Stale::default_renamed")]
+    renamed: bool,
+    after_ms: Option<u64>,
+    #[serde(skip)]
+    handles: std::cell::RefCell<collections::HashMap<Ino, (Instant, bool)>>, // lookup time, renamed since
+}
+
+impl Stale {
+    fn default_renamed() -> bool {
+        true
+    }
+
+    fn child(ctx: &Context, name: &str) -> Option<Ino> {
+        match ctx.tree.get(ctx.target)?.item {
+            NodeItem::Dir(ref dir) => dir.lookup(name),
+            _ => None,
+        }
+    }
+}
+
+impl Effect for Stale {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let mut handles = self.handles.borrow_mut();
+        let stale = handles.get(&ctx.target).is_some_and(|(looked_up, renamed)| {
+            (*renamed && self.renamed)
+                || self.after_ms.is_some_and(|ms| looked_up.elapsed() >= Duration::from_millis(ms))
+        });
+        if stale {
+            return EffectResult::Error(libc::ESTALE);
+        }
+
+        match ctx.op {
+            OpDesr::Lookup { ref name } => {
+                if let Some(ino) = Self::child(ctx, name) {
+                    handles.insert(ino, (Instant::now(), false));
+                }
+            }
+            OpDesr::Rename { ref name, .. } => {
+                if let Some(handle) = Self::child(ctx, name).and_then(|ino| handles.get_mut(&ino)) {
+                    handle.1 = true;
+                }
+            }
+            _ => (),
+        }
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Restrict names of new entries like FAT or SMB do: names longer than `max_len` bytes fail with
+// ENAMETOOLONG, names with `forbidden` characters with EINVAL. {"max_len": 255, "forbidden": "<>:\"|?*\\"}
+#[derive(Serialize, Deserialize)]
+pub struct NameRules {
+    max_len: Option<usize>,
+    #[serde(default)]
+    forbidden: String,
+}
+
+impl Effect for NameRules {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let name = match ctx.op {
+            OpDesr::Create { ref name }
+            | OpDesr::Mkdir { ref name }
+            | OpDesr::Symlink { ref name }
+            | OpDesr::Link { ref name }
+            | OpDesr::Rename { newname: ref name, .. } => name,
+            _ => return EffectResult::Ack,
+        };
+        if self.max_len.is_some_and(|max| name.len() > max) {
+            EffectResult::Error(libc::ENAMETOOLONG)
+        } else if name.contains(|c| self.forbidden.contains(c)) {
+            EffectResult::Error(libc::EINVAL)
+        } else {
+            EffectResult::Ack
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Token bucket refilled at `rate` tokens per second up to `burst`. Takes are reserved ahead when
+// tokens run out, so waiting takes queue up behind each other
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(burst: f64) -> TokenBucket {
+        TokenBucket { tokens: burst, refilled: Instant::now() }
+    }
+
+    // Take `amount` tokens, return how long to wait for them
+    fn take(&mut self, amount: f64, rate: f64, burst: f64) -> Duration {
+        let now = Instant::now();
+        let rate = rate.max(f64::EPSILON);
+        self.tokens = (self.tokens + (now - self.refilled).as_secs_f64() * rate).min(burst);
+        self.refilled = now;
+        self.tokens -= amount;
+        Duration::from_secs_f64((-self.tokens).max(0.0) / rate)
+    }
+}
+
+// Token buckets of an effect, one for the subtree it's attached at or one per file
+#[derive(Default)]
+struct Buckets(std::cell::RefCell<collections::HashMap<Ino, TokenBucket>>);
+
+impl Buckets {
+    fn take(&self, key: Ino, amount: f64, rate: f64, burst: f64) -> u64 {
+        let mut buckets = self.0.borrow_mut();
+        let bucket = buckets.entry(key).or_insert_with(|| TokenBucket::new(burst));
+        bucket.take(amount, rate, burst).as_millis() as u64
+    }
+}
+
+// Limit operations to `limit` per second, allowing bursts of `burst` operations. One bucket is shared
+// by all files of the subtree, so they compete for operations, or every file has its own with
+// `per_file`. Surplus operations are delayed until their slot, like on IOPS capped cloud volumes.
+// {"limit": 100, "burst": 10}
+#[derive(Serialize, Deserialize)]
+pub struct Iops {
+    limit: u64,
+    #[serde(default = "Iops::default_burst")]
+    burst: u64,
+    #[serde(default)]
+    per_file: bool,
+    #[serde(skip)]
+    buckets: Buckets,
+}
+
+impl Iops {
+    fn default_burst() -> u64 {
+        1
+    }
+}
+
+impl Effect for Iops {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let key = if self.per_file { ctx.target } else { ctx.origin };
+        EffectResult::Delay(self.buckets.take(key, 1.0, self.limit as f64, self.burst.max(1) as f64))
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Limit read and write throughput to `bytes_per_sec`, allowing bursts of `burst_bytes`. Like iops, one
+// bucket is shared by all files of the subtree, so they compete for bandwidth as on a single
+// spindle, or every file has its own with `per_file`. {"bytes_per_sec": 10485760}
+#[derive(Serialize, Deserialize)]
+pub struct Throttle {
+    bytes_per_sec: u64,
+    burst_bytes: Option<u64>, // a second of throughput by default
+    #[serde(default)]
+    per_file: bool,
+    #[serde(skip)]
+    buckets: Buckets,
+}
+
+impl Effect for Throttle {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let len = match ctx.op {
+            OpDesr::Read { len, .. } | OpDesr::Write { len, .. } => len,
+            _ => return EffectResult::Ack,
+        };
+        let key = if self.per_file { ctx.target } else { ctx.origin };
+        let burst = self.burst_bytes.unwrap_or(self.bytes_per_sec).max(1);
+        EffectResult::Delay(self.buckets.take(key, len as f64, self.bytes_per_sec as f64, burst as f64))
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Delay only the first read through every file handle by `delay_ms`, the rest are served right away,
+// like cold object stores or disks spinning up. {"delay_ms": 200}
+#[derive(Serialize, Deserialize)]
+pub struct Ttfb {
+    delay_ms: u64,
+}
+
+impl Effect for Ttfb {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        match ctx.op {
+            OpDesr::Read { first: true, .. } => EffectResult::Delay(self.delay_ms),
+            _ => EffectResult::Ack,
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Delay reads and writes by `per_mib_ms` for every MiB between their offset and the end of the
+// previous operation on the same file, up to `max_ms`, like seeks of a disk head. Sequential access
+// isn't delayed. {"per_mib_ms": 0.5, "max_ms": 15}
+#[derive(Serialize, Deserialize)]
+pub struct SeekLatency {
+    per_mib_ms: f64,
+    max_ms: Option<u64>,
+    #[serde(skip)]
+    heads: std::cell::RefCell<collections::HashMap<Ino, usize>>, // end of the last operation by file
+}
+
+impl Effect for SeekLatency {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let (offset, len) = match ctx.op {
+            OpDesr::Read { offset, len, .. } | OpDesr::Write { offset, len, .. } => (offset, len),
+            _ => return EffectResult::Ack,
+        };
+        let head = self.heads.borrow_mut().insert(ctx.target, offset + len);
+        let distance = head.map_or(0, |head| head.abs_diff(offset));
+        let ms = (distance as f64 / (1 << 20) as f64 * self.per_mib_ms) as u64;
+        EffectResult::Delay(self.max_ms.map_or(ms, |max| ms.min(max)))
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Delay reads by `per_write_us` for every write the file has seen, up to `max_ms`, like fragmentation
+// building up over a long running test. {"per_write_us": 10, "max_ms": 50}
+#[derive(Serialize, Deserialize)]
+pub struct Aging {
+    per_write_us: u64,
+    max_ms: Option<u64>,
+}
+
+impl Effect for Aging {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if !matches!(ctx.op, OpDesr::Read { .. }) {
+            return EffectResult::Ack;
+        }
+        let Some(NodeItem::File(ref file)) = ctx.tree.get(ctx.target).map(|n| &n.item) else {
+            return EffectResult::Ack;
+        };
+        let ms = file.stats.writes.get() as u64 * self.per_write_us / 1000;
+        EffectResult::Delay(self.max_ms.map_or(ms, |max| ms.min(max)))
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Fail operations in bursts: every healthy operation starts a storm with `prob` probability, which
+// fails the next `length` operations with `errno` (EIO by default) in a row. Storms are recorded as
+// events. {"prob": 0.001, "length": 50}
+#[derive(Serialize, Deserialize)]
+pub struct Burst {
+    prob: f32,
+    length: usize,
+    #[serde(default = "Flakey::default_errno", with = "errno")]
+    errno: ErrNo,
+    #[serde(skip)]
+    remaining: std::cell::Cell<usize>, // failures left in the current storm
+}
+
+impl Effect for Burst {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if self.remaining.get() == 0 && ctx.rgen.random::<f32>() < self.prob {
+            self.remaining.set(self.length);
+            events::emit(ctx.origin, "burst", serde_json::json!({"length": self.length}));
+        }
+        match self.remaining.get() {
+            0 => EffectResult::Ack,
+            left => {
+                self.remaining.set(left - 1);
+                EffectResult::Error(self.errno)
+            }
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Hold replies of operations with `prob` probability until the effect is removed or bf.release is
+// set on its node, to test application io timeouts and watchdogs. Held operations are listed in
+// bf.inflight. {"prob": 1.0}
+#[derive(Serialize, Deserialize)]
+pub struct Hang {
+    #[serde(default = "default_prob")]
+    prob: f32,
+    #[serde(skip)]
+    gate: Rc<HangGate>,
+}
+
+impl Hang {
+    pub fn release(&self) {
+        self.gate.release();
+    }
+}
+
+impl Drop for Hang {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl Effect for Hang {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if ctx.rgen.random::<f32>() < self.prob {
+            ctx.changes.hang = Some(self.gate.clone());
+        }
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn stateful(&self) -> bool {
+        true
+    }
+
+    fn display(&self) -> Option<String> {
+        Some(serde_json::json!({"held": self.gate.held()}).to_string())
+    }
+}
+
+// Time out operations with `prob` probability: delay them by `timeout_ms`, then fail them with
+// `errno` (EIO by default), like storage stacks aborting slow requests. {"timeout_ms": 30000}
+#[derive(Serialize, Deserialize)]
+pub struct Deadline {
+    timeout_ms: u64,
+    #[serde(default = "Flakey::default_errno", with = "errno")]
+    errno: ErrNo,
+    #[serde(default = "default_prob")]
+    prob: f32,
+}
+
+impl Effect for Deadline {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        match ctx.rgen.random::<f32>() < self.prob {
+            true => EffectResult::Timeout(self.timeout_ms, self.errno),
+            false => EffectResult::Ack,
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Shape of degradation over the lifetime of a dying device
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    #[default]
+    Linear,
+    Quadratic,
+    Exponential, // stays healthy for long, then collapses
+}
+
+impl Curve {
+    // Degradation at `progress` from 0 to 1
+    fn at(self, progress: f64) -> f64 {
+        let p = progress.clamp(0.0, 1.0);
+        match self {
+            Curve::Linear => p,
+            Curve::Quadratic => p * p,
+            Curve::Exponential => ((5.0 * p).exp() - 1.0) / (5f64.exp() - 1.0),
+        }
+    }
+}
+
+// Device deteriorating over `over_ms` of wall clock time or `over_ops` operations: delays grow to
+// `max_delay_ms` and error probability to `max_prob` along `curve`, failing with `errno` (EIO by default).
+// {"over_ms": 3600000, "max_delay_ms": 500, "max_prob": 0.2, "curve": "exponential"}
+#[derive(Serialize, Deserialize)]
+pub struct Dying {
+    over_ms: Option<u64>,
+    over_ops: Option<u64>,
+    #[serde(default)]
+    max_delay_ms: u64,
+    #[serde(default)]
+    max_prob: f32,
+    #[serde(default)]
+    curve: Curve,
+    #[serde(default = "Flakey::default_errno", with = "errno")]
+    errno: ErrNo,
+    #[serde(skip, default = "Instant::now")]
+    installed: Instant,
+    #[serde(skip)]
+    ops: std::cell::Cell<u64>,
+}
+
+impl Dying {
+    fn degradation(&self) -> f64 {
+        let progress = match (self.over_ops, self.over_ms) {
+            (Some(ops), _) => self.ops.get() as f64 / ops.max(1) as f64,
+            (None, Some(ms)) => self.installed.elapsed().as_millis() as f64 / ms.max(1) as f64,
+            (None, None) => 1.0,
+        };
+        self.curve.at(progress)
+    }
+}
+
+impl Effect for Dying {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        self.ops.set(self.ops.get() + 1);
+        let degradation = self.degradation();
+        let delay_ms = (self.max_delay_ms as f64 * degradation) as u64;
+        match ctx.rgen.random::<f64>() < self.max_prob as f64 * degradation {
+            true => EffectResult::Timeout(delay_ms, self.errno),
+            false => EffectResult::Delay(delay_ms),
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+
+    fn display(&self) -> Option<String> {
+        Some(serde_json::json!({"degradation": self.degradation(), "ops": self.ops.get()}).to_string())
+    }
+}
+
+// Fail operations with `errno` (EBUSY by default) while `limit` operations are active at once, for the
+// whole subtree or every file with `per_file`, like devices with shallow queues. Operations are
+// active until their delayed replies are sent. {"limit": 4, "errno": "EAGAIN"}
+#[derive(Serialize, Deserialize)]
+pub struct MaxConc {
+    limit: usize,
+    #[serde(default)]
+    per_file: bool,
+    #[serde(default = "MaxConc::default_errno", with = "errno")]
+    errno: ErrNo,
+    #[serde(skip)]
+    active: std::cell::RefCell<collections::HashMap<Ino, std::sync::Arc<std::sync::atomic::AtomicUsize>>>,
+}
+
+impl MaxConc {
+    fn default_errno() -> ErrNo {
+        libc::EBUSY
+    }
+}
+
+impl Effect for MaxConc {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let key = if self.per_file { ctx.target } else { ctx.origin };
+        let counter = self.active.borrow_mut().entry(key).or_default().clone();
+        if counter.load(std::sync::atomic::Ordering::Relaxed) >= self.limit {
+            return EffectResult::Error(self.errno);
+        }
+        ctx.changes.active.push(counter);
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Deserialize)]
+struct ScriptSource {
+    source: String,
+    #[serde(default = "External::default_fallback")]
+    fallback: Decision,
+    #[serde(default = "Script::default_timeout")]
+    timeout_ms: u64,
+}
+
+// Decide on operations with a Lua chunk. It receives the operation like the external decider as
+// its argument `...`, can draw random numbers in [0, 1) with `rand()` from the seeded rng and returns
+// a decision like the external decider or nothing to let the operation pass. Scripts failing at
+// runtime or running longer than `timeout_ms` fall back to `fallback`. Scripts run on the filesystem
+// thread, so all operations wait for them. The standard libraries are loaded, including os and io.
+// {"source": "local req = ...; if req.op.write and rand() < 0.1 then return {decision = 'error', errno = 'EIO'} end"}
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "ScriptSource")]
+pub struct Script {
+    source: String,
+    fallback: Decision,
+    timeout_ms: u64,
+    #[serde(skip_serializing)]
+    lua: mlua::Lua,
+    #[serde(skip_serializing)]
+    chunk: mlua::Function,
+}
+
+impl Script {
+    fn default_timeout() -> u64 {
+        100
+    }
+}
+
+impl TryFrom<ScriptSource> for Script {
+    type Error = mlua::Error;
+
+    // Compile on attach, so syntax errors are rejected right away
+    fn try_from(spec: ScriptSource) -> Result<Self, Self::Error> {
+        let ScriptSource { source, fallback, timeout_ms } = spec;
+        let lua = mlua::Lua::new();
+        let chunk = lua.load(&source).set_name("script").into_function()?;
+        Ok(Script {
+            source,
+            fallback,
+            timeout_ms,
+            lua,
+            chunk,
+        })
+    }
+}
+
+impl Effect for Script {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        use mlua::LuaSerdeExt;
+
+        let request = serde_json::json!({
+            "op": &ctx.op,
+            "ino": ctx.target,
+            "path": ctx.tree.path(ctx.target),
+            "origin": ctx.tree.path(ctx.origin),
+            "pid": ctx.caller.pid,
+        });
+        // Abort chunks running past the deadline, so a stuck script can't freeze the filesystem
+        let deadline = Instant::now() + Duration::from_millis(self.timeout_ms);
+        let triggers = mlua::HookTriggers::new().every_nth_instruction(1000);
+        self.lua.set_hook(triggers, move |_, _| match Instant::now() < deadline {
+            true => Ok(mlua::VmState::Continue),
+            false => Err(mlua::Error::runtime("script timed out")),
+        });
+        let rgen = &mut *ctx.rgen;
+        let decision = self.lua.scope(|scope| {
+            let rand = scope.create_function_mut(|_, ()| Ok(rgen.random::<f64>()))?;
+            self.lua.globals().set("rand", rand)?;
+            let value: mlua::Value = self.chunk.call(self.lua.to_value(&request)?)?;
+            self.lua.from_value::<Option<Decision>>(value)
+        });
+        self.lua.remove_hook();
+        match decision.unwrap_or(Some(self.fallback)) {
+            None | Some(Decision::Ok) => EffectResult::Ack,
+            Some(Decision::Error { errno }) => EffectResult::Error(errno),
+            Some(Decision::Delay { delay_ms }) => EffectResult::Delay(delay_ms),
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Let an external command or webhook decide on operations. The operation is passed like to the
+// external decider, as json on stdin of `command` run with sh or POSTed to the http `url`. Its
+// output or response body is a decision like the external decider's, empty to let the operation
+// pass. The fallback decision applies if it fails or doesn't answer within timeout. Hooks run
+// synchronously, so all operations wait for every run, up to the timeout. A command is spawned for
+// every matching operation, narrow it down with conditions.
+// {"command": "/opt/decide.sh"} or {"url": "http://localhost:8080/decide", "timeout_ms": 100}
+#[derive(Serialize, Deserialize)]
+pub struct Hook {
+    command: Option<String>,
+    url: Option<String>,
+    #[serde(default = "External::default_timeout")]
+    timeout_ms: u64,
+    #[serde(default = "External::default_fallback")]
+    fallback: Decision,
+}
+
+impl Hook {
+    fn decision(output: &[u8]) -> std::io::Result<Decision> {
+        if output.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Decision::Ok);
+        }
+        serde_json::from_slice(output).map_err(std::io::Error::other)
+    }
+
+    fn run(&self, command: &str, request: &[u8]) -> std::io::Result<Decision> {
+        use std::io::{ErrorKind, Read, Write};
+        use std::os::unix::process::CommandExt;
+        use std::process::{Command, Stdio};
+
+        // In its own process group, so processes it started in the background can be killed along
+        let mut child = Command::new("sh")
+            .args(["-c", command])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn()?;
+        // Read output while the command runs, so it never blocks on a full pipe. The reader ends once
+        // all processes holding the pipe exit
+        let mut stdout = child.stdout.take().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut output = vec![];
+            let _ = tx.send(stdout.read_to_end(&mut output).map(|_| output));
+        });
+        // Dropping stdin closes it, so the command sees the end of the request. Commands may ignore it
+        let written = match child.stdin.take().unwrap().write_all(request) {
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => Ok(()),
+            written => written,
+        };
+        let deadline = Instant::now() + Duration::from_millis(self.timeout_ms);
+        let output = rx.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+        let exited = loop {
+            match child.try_wait() {
+                Ok(None) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(1))
+                }
+                Ok(status) => break status.is_some(),
+                Err(_) => break false,
+            }
+        };
+
+        // Once out of time kill the whole group, including background processes keeping the output
+        // open after the command exited, and always reap the command
+        if !exited || output.is_err() {
+            unsafe { libc::kill(-(child.id() as i32), libc::SIGKILL) };
+        }
+        child.wait()?;
+        if !exited {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        written?;
+        let output = output.map_err(|_| std::io::Error::from(ErrorKind::TimedOut))??;
+        Hook::decision(&output)
+    }
+
+    // Plain http only, the response must not be chunked
+    fn post(&self, url: &str, request: &[u8]) -> std::io::Result<Decision> {
+        use std::io::{ErrorKind, Read, Write};
+        use std::net::{TcpStream, ToSocketAddrs};
+
+        let rest = url.strip_prefix("http://").ok_or(ErrorKind::InvalidInput)?;
+        let (host, path) = match rest.split_once('/') {
+            Some((host, path)) => (host, format!("/{}", path)),
+            None => (rest, "/".to_owned()),
+        };
+        let addr = match host.contains(':') {
+            true => host.to_owned(),
+            false => format!("{}:80", host),
+        };
+        let addr = addr.to_socket_addrs()?.next().ok_or(ErrorKind::NotFound)?;
+        let timeout = Duration::from_millis(self.timeout_ms.max(1));
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let header = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            path,
+            host,
+            request.len()
+        );
+        stream.write_all(header.as_bytes())?;
+        stream.write_all(request)?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").ok_or(ErrorKind::InvalidData)?;
+        let status = response.split(|c| *c == b' ').nth(1).unwrap_or_default();
+        if !status.starts_with(b"2") {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        Hook::decision(&response[split + 4..])
+    }
+}
+
+impl Effect for Hook {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let request = serde_json::json!({
+            "op": &ctx.op,
+            "ino": ctx.target,
+            "path": ctx.tree.path(ctx.target),
+            "origin": ctx.tree.path(ctx.origin),
+            "pid": ctx.caller.pid,
+        })
+        .to_string();
+        let decision = match (&self.command, &self.url) {
+            (Some(command), _) => self.run(command, request.as_bytes()),
+            (None, Some(url)) => self.post(url, request.as_bytes()),
+            (None, None) => Ok(self.fallback),
+        };
+        match decision.unwrap_or(self.fallback) {
+            Decision::Ok => EffectResult::Ack,
+            Decision::Error { errno } => EffectResult::Error(errno),
+            Decision::Delay { delay_ms } => EffectResult::Delay(delay_ms),
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+
+    fn side_effects(&self) -> bool {
+        true
+    }
+}
+
+// Make lookups of existing entries fail with ENOENT, like eventually consistent listings or racing
+// deleters, with probability `prob` or during intervals like flakey. {"prob": 0.1}
+#[derive(Serialize, Deserialize)]
+pub struct Vanish {
+    #[serde(flatten)]
+    cond: FlakeyCondition,
+}
+
+impl Effect for Vanish {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        let OpDesr::Lookup { ref name } = ctx.op else {
+            return EffectResult::Ack;
+        };
+        let Some(NodeItem::Dir(dir)) = ctx.tree.get(ctx.target).map(|n| &n.item) else {
+            return EffectResult::Ack;
+        };
+        match dir.lookup(name.as_str()).is_some() && self.cond.hit(ctx.rgen) {
+            true => EffectResult::Error(libc::ENOENT),
+            false => EffectResult::Ack,
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Omit entries from directory listings with probability `prob` each, without touching the tree,
+// like incomplete listings of network filesystems. {"prob": 0.05}
+#[derive(Serialize, Deserialize)]
+pub struct DirFlake {
+    #[serde(default = "default_prob")]
+    prob: f32,
+}
+
+impl Effect for DirFlake {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if matches!(ctx.op, OpDesr::Readdir { .. }) {
+            ctx.changes.omit = Some(self.prob);
+        }
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Shuffle directory listings differently every time and list entries twice with probability
+// `dup_prob` each, as applications often assume stable duplicate free listings real filesystems
+// don't guarantee. {"shuffle": true, "dup_prob": 0.01}
+#[derive(Serialize, Deserialize)]
+pub struct DirShuffle {
+    #[serde(default = "DirShuffle::default_shuffle")]
+    shuffle: bool,
+    #[serde(default)]
+    dup_prob: f32,
+}
+
+impl DirShuffle {
+    fn default_shuffle() -> bool {
+        true
+    }
+}
+
+impl Effect for DirShuffle {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if matches!(ctx.op, OpDesr::Readdir { .. }) {
+            if self.shuffle {
+                ctx.changes.dir_order = Some(crate::ftypes::DirOrder::Random);
+            }
+            if self.dup_prob > 0.0 {
+                ctx.changes.dup = Some(self.dup_prob);
+            }
+        }
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Report arbitrary capacity with statfs, so tools checking free space can be tested without filling
+// storage. Free blocks are `bavail` or what's left of `blocks` with the `used` fraction taken.
+// {"blocks": 262144, "used": 0.99} or {"blocks": 262144, "bavail": 0, "files": 100, "ffree": 0}
+#[derive(Serialize, Deserialize)]
+pub struct FakeFs {
+    #[serde(default = "FakeFs::default_blocks")]
+    blocks: u64,
+    bavail: Option<u64>,
+    used: Option<f64>,
+    files: Option<u64>,
+    ffree: Option<u64>,
+}
+
+impl FakeFs {
+    fn default_blocks() -> u64 {
+        262144 // 1GiB of 4KiB blocks
+    }
+}
+
+impl Effect for FakeFs {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if !matches!(ctx.op, OpDesr::Statfs) {
+            return EffectResult::Ack;
+        }
+        let bavail = match (self.bavail, self.used) {
+            (Some(bavail), _) => bavail.min(self.blocks),
+            (None, Some(used)) => (self.blocks as f64 * (1.0 - used.clamp(0.0, 1.0))) as u64,
+            (None, None) => self.blocks,
+        };
+        ctx.changes.statfs = Some(crate::storage::Stat {
+            blocks: self.blocks,
+            bavail,
+        });
+        ctx.changes.files = self.files.or(ctx.changes.files);
+        ctx.changes.ffree = self.ffree.or(ctx.changes.ffree);
+        EffectResult::Ack
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
+    }
+}
+
+// Fail operations on user extended attributes, like on filesystems lacking them, with `errno`
+// (ENOTSUP by default) and probability `prob`. With `max_size` only setting larger values fails,
+// with E2BIG. With `erange` reading values or name lists into a buffer fails with ERANGE with `prob`,
+// as if they grew since their size was probed. {"errno": "ENOTSUP"}, {"max_size": 4096} or
+// {"erange": true}
+#[derive(Serialize, Deserialize)]
+pub struct XattrFault {
+    #[serde(default = "XattrFault::default_errno", with = "errno")]
+    errno: ErrNo,
+    #[serde(default = "default_prob")]
+    prob: f32,
+    max_size: Option<usize>,
+    #[serde(default)]
+    erange: bool,
+}
+
+impl XattrFault {
+    fn default_errno() -> ErrNo {
+        libc::ENOTSUP
+    }
+}
+
+impl Effect for XattrFault {
+    fn apply(&self, ctx: &mut Context) -> EffectResult {
+        if let Some(max_size) = self.max_size {
+            return match ctx.op {
+                OpDesr::Setxattr { size, .. } if size > max_size => EffectResult::Error(libc::E2BIG),
+                _ => EffectResult::Ack,
+            };
+        }
+        // Only operations on extended attributes fail, even if attached more broadly
+        if !ctx.op.optype().contains(OpType::X) {
+            return EffectResult::Ack;
+        }
+        let errno = match ctx.op {
+            _ if !self.erange => self.errno,
+            OpDesr::Getxattr { size, .. } | OpDesr::Listxattr { size } if size > 0 => libc::ERANGE,
+            _ => return EffectResult::Ack,
+        };
+        match ctx.rgen.random::<f32>() < self.prob {
+            true => EffectResult::Error(errno),
+            false => EffectResult::Ack,
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        return self;
     }
 }
@@ -1,24 +1,58 @@
 use bitflags::bitflags;
 use libc::EINVAL;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Serialize, Serializer};
 use serde_json::Value as JValue;
 use std::any::Any;
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::ftree;
-use crate::ftypes::{ErrNo, Ino};
+use crate::storage;
+use crate::inflight::{self, Replier};
+use crate::trace;
+use crate::ftypes::{DirOrder, ErrNo, Ino};
 mod detail;
+pub mod cond;
+pub mod preset;
+pub mod schema;
 
 pub enum EffectResult {
     Ack,          // Acknowledge operation, don't do anything
     Error(ErrNo), // Cause error
     Delay(u64),   // Sleep ms
+    Timeout(u64, ErrNo), // Sleep ms, then cause error
 }
 
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
 pub enum OpDesr {
-    Read { offset: usize, len: usize },
-    Write { offset: usize, len: usize },
+    Read { offset: usize, len: usize, first: bool }, // first: first read through the file handle
+    Write { offset: usize, len: usize, cached: bool }, // cached: writeback of page cache
+    Readlink,
+    Lookup { name: String }, // lookup of `name` in target directory
+    Getattr,
+    Readdir { offset: i64 }, // listing of target directory continuing after `offset`
+    Statfs,
+    Fsync,
+    // Operations on user extended attributes of the target
+    Getxattr { name: String, size: usize }, // size: of the buffer, 0 to probe the size of the value
+    Listxattr { size: usize },
+    Setxattr { name: String, size: usize },
+    Removexattr { name: String },
+    // Mutations of metadata, target is the directory of `name` or the changed node
+    Create { name: String },
+    Mkdir { name: String },
+    Symlink { name: String },
+    Link { name: String },
+    Unlink { name: String },
+    Rmdir { name: String },
+    Rename { name: String, newparent: Ino, newname: String },
+    Setattr { size: Option<u64> },
 }
 
 impl OpDesr {
@@ -26,22 +60,326 @@ impl OpDesr {
         match self {
             OpDesr::Read { .. } => OpType::R,
             OpDesr::Write { .. } => OpType::W,
+            OpDesr::Readlink => OpType::R,
+            OpDesr::Lookup { .. } | OpDesr::Getattr => OpType::L,
+            OpDesr::Readdir { .. } => OpType::D,
+            OpDesr::Statfs => OpType::S,
+            OpDesr::Fsync => OpType::F,
+            OpDesr::Getxattr { .. } | OpDesr::Listxattr { .. } | OpDesr::Setxattr { .. } | OpDesr::Removexattr { .. } => {
+                OpType::X
+            }
+            _ => OpType::M,
+        }
+    }
+
+    // Whether operation modifies data or metadata
+    pub fn mutating(&self) -> bool {
+        !matches!(
+            self,
+            OpDesr::Read { .. }
+                | OpDesr::Readlink
+                | OpDesr::Lookup { .. }
+                | OpDesr::Getattr
+                | OpDesr::Readdir { .. }
+                | OpDesr::Statfs
+                | OpDesr::Fsync
+                | OpDesr::Getxattr { .. }
+                | OpDesr::Listxattr { .. }
+        )
+    }
+
+    // Name of the entry in the target directory the operation addresses, if any
+    pub fn entry_name(&self) -> Option<&str> {
+        match self {
+            OpDesr::Lookup { name }
+            | OpDesr::Create { name }
+            | OpDesr::Mkdir { name }
+            | OpDesr::Symlink { name }
+            | OpDesr::Link { name }
+            | OpDesr::Unlink { name }
+            | OpDesr::Rmdir { name }
+            | OpDesr::Rename { name, .. } => Some(name),
+            _ => None,
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpDesr::Read { .. } => "read",
+            OpDesr::Write { .. } => "write",
+            OpDesr::Readlink => "readlink",
+            OpDesr::Lookup { .. } => "lookup",
+            OpDesr::Getattr => "getattr",
+            OpDesr::Readdir { .. } => "readdir",
+            OpDesr::Statfs => "statfs",
+            OpDesr::Fsync => "fsync",
+            OpDesr::Getxattr { .. } => "getxattr",
+            OpDesr::Listxattr { .. } => "listxattr",
+            OpDesr::Setxattr { .. } => "setxattr",
+            OpDesr::Removexattr { .. } => "removexattr",
+            OpDesr::Create { .. } => "create",
+            OpDesr::Mkdir { .. } => "mkdir",
+            OpDesr::Symlink { .. } => "symlink",
+            OpDesr::Link { .. } => "link",
+            OpDesr::Unlink { .. } => "unlink",
+            OpDesr::Rmdir { .. } => "rmdir",
+            OpDesr::Rename { .. } => "rename",
+            OpDesr::Setattr { .. } => "setattr",
+        }
+    }
+}
+
+// Change requested by an effect, applied by the filesystem after the effect run
+pub enum Action {
+    // Repair blocks of `ino` damaged for at least `older_than`
+    Repair { ino: Ino, older_than: Duration },
+}
+
+// Process an operation was issued by
+#[derive(Clone, Copy, Serialize)]
+pub struct Caller {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
 }
 
 pub struct Context<'a> {
     pub op: OpDesr,
     pub origin: Ino, // where the effect is defined at
     pub target: Ino, // where the effect is applied at
+    pub entry: Option<(Ino, String)>, // entry (parent, name) the target was reached by, if known
+    pub caller: Caller,
     pub tree: &'a ftree::Tree,
     pub rgen: &'a mut rand::rngs::StdRng,
+    pub start: Instant,   // when the operation started processing
+    pub delayed_ms: u64,  // delay accumulated by effects so far
+    pub actions: Vec<Action>,
+    pub changes: Changes,
+    pub trace: Option<Vec<(String, Ino, trace::Verdict)>>, // effects consulted with their origin, if tracing
+}
+
+// Changes to the operation itself requested by effects
+#[derive(Default)]
+pub struct Changes {
+    pub max_len: Option<usize>, // cap on read/write length, shortening the operation
+    pub chunking: Option<Chunking>,
+    pub link_target: Option<PathBuf>, // target returned by readlink
+    pub read_data: Option<Vec<u8>>,   // data returned by read instead of stored data
+    pub verify: Option<Verify>,
+    pub clone_mode: Option<CloneMode>, // how copied ranges share storage
+    pub negative_ttl: Option<Duration>, // serve lookup as missing, cached for ttl
+    pub statfs: Option<storage::Stat>,  // capacity reported by statfs
+    pub free_ratio: Option<f64>,        // fraction of free space reported by statfs
+    pub corruption: Option<detail::Corruption>, // corrupt written data before it's persisted
+    pub coalesce: Option<Duration>, // persist write with the batch of the file flushed after this window
+    pub lost: bool,                 // acknowledge write without persisting it
+    pub torn: Option<usize>,        // persist only this many leading bytes of write
+    pub misdirect: Option<i64>,     // persist write at offset moved by this many bytes
+    pub duplicate: Option<usize>,   // persist write once more at this offset
+    pub scrub: Option<Rc<Scrub>>,   // record checksums of written blocks or verify read ones
+    pub hang: Option<Rc<HangGate>>, // hold the reply until the gate is released
+    pub active: Vec<Arc<AtomicUsize>>, // counters of active operations to hold until the reply
+    pub omit: Option<f32>, // probability to omit each entry of a directory listing
+    pub dir_order: Option<DirOrder>, // order of a directory listing instead of the directory's
+    pub dup: Option<f32>, // probability to list each entry of a directory listing twice
+    pub files: Option<u64>, // number of files reported by statfs
+    pub ffree: Option<u64>, // number of free inodes reported by statfs
+}
+
+// Operations held by a hang effect
+#[derive(Default)]
+pub struct HangGate {
+    held: std::cell::RefCell<Vec<u64>>, // in-flight ids
+}
+
+impl HangGate {
+    fn hold(&self, id: u64) {
+        self.held.borrow_mut().push(id);
+    }
+
+    pub fn held(&self) -> usize {
+        self.held.borrow().len()
+    }
+
+    // Complete all held operations
+    pub fn release(&self) {
+        for id in self.held.borrow_mut().drain(..) {
+            inflight::finish(id, None);
+        }
+    }
+}
+
+// Broken semantics of copies sharing storage
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CloneMode {
+    Copy,   // copies never share storage
+    Shared, // writes to either copy are visible in both
+}
+
+// Read back written data and compare, failing the write with `errno` on mismatch if set
+pub struct Verify {
+    pub errno: Option<ErrNo>,
+    pub stats: Rc<VerifyStats>,
+}
+
+#[derive(Default, Serialize)]
+pub struct VerifyStats {
+    pub checked: Cell<usize>,
+    pub mismatches: Cell<usize>,
+}
+
+// Shadow checksums of blocks as they should read after the writes seen so far
+#[derive(Default, Serialize)]
+pub struct Scrub {
+    #[serde(skip)]
+    pub block: usize,
+    #[serde(skip)]
+    sums: std::cell::RefCell<std::collections::HashMap<(Ino, usize), u64>>,
+    pub checked: Cell<usize>,
+    pub mismatches: Cell<usize>,
+}
+
+fn checksum(data: &[u8]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::hash::DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Scrub {
+    pub fn new(block: usize) -> Self {
+        Scrub {
+            block: block.max(1),
+            ..Default::default()
+        }
+    }
+
+    // Record checksums of blocks overlapping `data` written at `offset`, as stored now with `data` on top
+    pub fn record(&self, ino: Ino, offset: usize, data: &[u8], stored: &dyn storage::Storage) {
+        let mut sums = self.sums.borrow_mut();
+        let end = offset + data.len();
+        for start in (offset / self.block * self.block..end).step_by(self.block) {
+            let bend = (start + self.block).min(stored.len().max(end));
+            let mut block = stored.read(start, bend - start).into_owned();
+            block.resize(bend - start, 0);
+            let (from, to) = (offset.max(start), end.min(bend));
+            block[from - start..to - start].copy_from_slice(&data[from - offset..to - offset]);
+            sums.insert((ino, start), checksum(&block));
+        }
+    }
+
+    // Verify blocks entirely covered by `data` read at `offset` from a file of `size`, returning
+    // offsets of mismatching ones
+    pub fn verify(&self, ino: Ino, offset: usize, data: &[u8], size: usize) -> Vec<usize> {
+        let sums = self.sums.borrow();
+        let end = offset + data.len();
+        let mut mismatched = vec![];
+        for start in (offset.div_ceil(self.block) * self.block..end).step_by(self.block) {
+            let bend = (start + self.block).min(size);
+            let Some(sum) = sums.get(&(ino, start)).filter(|_| bend <= end) else {
+                continue;
+            };
+            self.checked.set(self.checked.get() + 1);
+            if checksum(&data[start - offset..bend - offset]) != *sum {
+                self.mismatches.set(self.mismatches.get() + 1);
+                mismatched.push(start);
+            }
+        }
+        mismatched
+    }
+}
+
+// Persist writes in chunks of `size` with `interval_ms` in between
+#[derive(Clone, Copy)]
+pub struct Chunking {
+    pub size: usize,
+    pub interval_ms: u64,
+}
+
+// Operation counted as active until dropped along with its reply
+struct Active(Vec<Arc<AtomicUsize>>);
+
+impl Active {
+    fn enter(counters: &[Arc<AtomicUsize>]) -> Active {
+        for counter in counters {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        Active(counters.to_vec())
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        for counter in &self.0 {
+            counter.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Smoothed service time of operations by kind in ms, from the start of processing until the reply
+// without injected delays
+static SERVICE_MS: Mutex<std::collections::BTreeMap<&'static str, f64>> =
+    Mutex::new(std::collections::BTreeMap::new());
+
+fn observe(op: &'static str, took: Duration) {
+    const ALPHA: f64 = 0.1;
+    let took = took.as_secs_f64() * 1000.0;
+    let mut service = SERVICE_MS.lock().unwrap();
+    let ms = service.entry(op).or_insert(took);
+    *ms += ALPHA * (took - *ms);
+}
+
+// Service time operations of kind `op` take by themselves as observed so far
+pub fn service_ms(op: &str) -> u64 {
+    SERVICE_MS.lock().unwrap().get(op).map_or(0, |ms| ms.round() as u64)
+}
+
+// Combined result of all effects for an operation
+pub struct Outcome {
+    pub sleep_ms: u64,
+    pub errno: Option<ErrNo>,
+    pub changes: Changes,
+    pub label: String, // operation and path, identifies delayed replies
+    pub op: &'static str,
+    pub start: Instant, // when the operation started processing
+}
+
+impl Outcome {
+    // Reply after the combined delay, or hold the reply until released if hung
+    pub fn reply<R: Replier>(&self, reply: R, replier: impl FnOnce(R) + Send + 'static) {
+        observe(self.op, self.start.elapsed());
+        let active = Active::enter(&self.changes.active);
+        let replier = move |reply| {
+            replier(reply);
+            drop(active);
+        };
+        match self.changes.hang {
+            Some(ref gate) => {
+                if let Some(id) = inflight::spawn(None, &self.label, reply, replier) {
+                    gate.hold(id);
+                }
+            }
+            None => self::reply(self.sleep_ms, &self.label, reply, replier),
+        }
+    }
 }
 
 pub trait Effect {
     fn apply(&self, ctx: &mut Context) -> EffectResult;
     fn as_any(&self) -> &dyn Any;
 
+    // Whether applying acts beyond the result and requested changes, like advancing counters,
+    // signaling processes or emitting events. Such effects can't be dry run
+    fn side_effects(&self) -> bool {
+        false
+    }
+
+    // Whether the effect keeps state between operations, which is lost if it's created again
+    fn stateful(&self) -> bool {
+        self.side_effects()
+    }
+
     // Response to getfattr of this effect
     fn display(&self) -> Option<String> {
         None
@@ -55,6 +393,10 @@ bitflags! {
         const W = 1 << 1;
         const L = 1 << 2;
         const M = 1 << 3;
+        const S = 1 << 4;
+        const F = 1 << 5;
+        const D = 1 << 6;
+        const X = 1 << 7;
     }
 }
 
@@ -93,6 +435,43 @@ pub struct DefinedEffect {
     #[serde(flatten, serialize_with = "serialize_box")]
     pub effect: Box<dyn Effect>,
     pub op: OpType,
+    #[serde(skip_serializing_if = "is_default_priority")]
+    pub priority: i32, // effects with higher priority run first
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub dryrun: bool, // only count and trace what the effect would have done
+    #[serde(flatten)]
+    pub when: cond::Conditions,
+    #[serde(skip)]
+    pub stats: Stats,
+}
+
+// Counters of an effect since it was attached
+#[derive(Default, Serialize)]
+pub struct Stats {
+    evaluated: Cell<u64>, // operations it was consulted for
+    triggered: Cell<u64>, // operations it delayed or failed
+    delay_ms: Cell<u64>,  // total delay injected
+    errors: Cell<u64>,    // errors returned
+}
+
+impl Stats {
+    fn record(&self, result: &Option<EffectResult>) {
+        let add = |c: &Cell<u64>, v: u64| c.set(c.get() + v);
+        add(&self.evaluated, 1);
+        let (delay_ms, error) = match *result {
+            None | Some(EffectResult::Ack) => return,
+            Some(EffectResult::Error(_)) => (0, true),
+            Some(EffectResult::Delay(ms)) => (ms, false),
+            Some(EffectResult::Timeout(ms, _)) => (ms, true),
+        };
+        add(&self.triggered, 1);
+        add(&self.delay_ms, delay_ms);
+        add(&self.errors, error as u64);
+    }
+}
+
+fn is_default_priority(priority: &i32) -> bool {
+    *priority == 0
 }
 
 fn serialize_box<S>(b: &Box<dyn Effect>, s: S) -> Result<S::Ok, S::Error>
@@ -100,15 +479,78 @@ where
     S: Serializer,
 {
     let a = b.as_any();
-    if let Some(delay) = a.downcast_ref::<detail::Delay>() {
-        delay.serialize(s)
-    } else if let Some(flakey) = a.downcast_ref::<detail::Flakey>() {
-        flakey.serialize(s)
-    } else if let Some(maxsize) = a.downcast_ref::<detail::MaxSize>() {
-        maxsize.serialize(s)
-    } else {
-        panic!("Unsupported dynamic type!");
+    macro_rules! serialize_as {
+        ($($efft:ty),*) => {
+            $(if let Some(e) = a.downcast_ref::<$efft>() {
+                return e.serialize(s);
+            })*
+        };
     }
+    serialize_as!(detail::Delay, detail::Flakey, detail::MaxSize, detail::HeatMap, detail::Quota, detail::Slo, detail::Heal,
+        detail::Ramp, detail::MaxIo, detail::Interleave,
+        detail::PageFault, detail::NoMem,
+        detail::Relink, detail::Diverge, detail::PathLatency,
+        detail::External, detail::Device, detail::WriteVerify, detail::BrokenCow,
+        detail::ReadOnlyWindow, detail::NegativeLookup, detail::OnIdle,
+        detail::CorruptRead, detail::CorruptWrite, detail::Policy,
+        detail::Coalesce, detail::LostWrite, detail::Torn,
+        detail::Misdirect, detail::DupWrite, detail::BadRange,
+        detail::Model, detail::ScrubEffect,
+        detail::Rot, detail::DiskFill,
+        detail::Signal, detail::DiskFull, detail::ReadOnly,
+        detail::AccessDenied, detail::Busy, detail::CrossDevice,
+        detail::Stale, detail::NameRules, detail::Iops,
+        detail::Ttfb, detail::SeekLatency, detail::Aging,
+        detail::Burst, detail::Hang,
+        detail::Deadline, detail::Dying, detail::MaxConc,
+        detail::Throttle, detail::Script, detail::Hook,
+        detail::Vanish, detail::DirFlake, detail::DirShuffle,
+        detail::FakeFs, detail::XattrFault);
+    panic!("Unsupported dynamic type!");
+}
+
+// Create effect of given type from its parameters
+pub fn create_effect(eftype: &str, params: JValue) -> Result<(&'static str, Box<dyn Effect>), ErrNo> {
+    macro_rules! match_effect {
+        ($($name:literal => $efft:ty),*) => {
+            match eftype {
+                $($name => ($name, Box::new(serde_json::from_value::<$efft>(params).map_err(|_|EINVAL)?)),)*
+                _ => return Err(EINVAL),
+            }
+        };
+    }
+
+    schema::check(eftype, &params)?;
+    let (sname, effect): (&'static str, Box<dyn Effect>) = match_effect! {
+        "delay" => detail::Delay, "flakey" => detail::Flakey, "maxsize" => detail::MaxSize,
+        "heatmap" => detail::HeatMap, "quota" => detail::Quota, "slo" => detail::Slo,
+        "heal" => detail::Heal, "ramp" => detail::Ramp, "maxio" => detail::MaxIo,
+        "interleave" => detail::Interleave, "pagefault" => detail::PageFault,
+        "nomem" => detail::NoMem, "relink" => detail::Relink,
+        "diverge" => detail::Diverge, "pathlat" => detail::PathLatency,
+        "external" => detail::External, "device" => detail::Device,
+        "verify" => detail::WriteVerify, "cow" => detail::BrokenCow,
+        "rowindow" => detail::ReadOnlyWindow, "negative" => detail::NegativeLookup,
+        "onidle" => detail::OnIdle, "corruptread" => detail::CorruptRead,
+        "corruptwrite" => detail::CorruptWrite, "policy" => detail::Policy,
+        "coalesce" => detail::Coalesce, "lostwrite" => detail::LostWrite,
+        "torn" => detail::Torn, "misdirect" => detail::Misdirect,
+        "dupwrite" => detail::DupWrite, "badrange" => detail::BadRange,
+        "model" => detail::Model, "scrub" => detail::ScrubEffect,
+        "rot" => detail::Rot, "diskfill" => detail::DiskFill,
+        "signal" => detail::Signal, "diskfull" => detail::DiskFull,
+        "readonly" => detail::ReadOnly, "accessdenied" => detail::AccessDenied,
+        "busy" => detail::Busy, "xdev" => detail::CrossDevice,
+        "estale" => detail::Stale, "namerules" => detail::NameRules,
+        "iops" => detail::Iops, "ttfb" => detail::Ttfb, "seeklat" => detail::SeekLatency,
+        "aging" => detail::Aging, "burst" => detail::Burst, "hang" => detail::Hang,
+        "deadline" => detail::Deadline, "dying" => detail::Dying,
+        "maxconc" => detail::MaxConc, "throttle" => detail::Throttle,
+        "script" => detail::Script, "hook" => detail::Hook, "vanish" => detail::Vanish,
+        "dirflake" => detail::DirFlake, "dirshuffle" => detail::DirShuffle,
+        "fakefs" => detail::FakeFs, "xattr" => detail::XattrFault
+    };
+    Ok((sname, effect))
 }
 
 impl DefinedEffect {
@@ -120,28 +562,177 @@ impl DefinedEffect {
             .and_then(|obj| obj.as_str().map(|s| s.to_owned()))
             .ok_or(EINVAL)?
             .parse()?;
+        let priority = match parsed.as_object_mut().and_then(|obj| obj.remove("priority")) {
+            Some(value) => value.as_i64().and_then(|p| i32::try_from(p).ok()).ok_or(EINVAL)?,
+            None => 0,
+        };
+        let dryrun = match parsed.as_object_mut().and_then(|obj| obj.remove("dryrun")) {
+            Some(value) => value.as_bool().ok_or(EINVAL)?,
+            None => false,
+        };
 
+        let when = cond::Conditions::take(&mut parsed).map_err(|_| EINVAL)?;
         let (eftype, _) = name.split_once("-").unwrap_or((name, name));
-
-        macro_rules! match_effect {
-            ($($name:literal => $efft:ty),*) => {
-                match eftype {
-                    $($name => ($name, Box::new(serde_json::from_value::<$efft>(parsed).map_err(|_|EINVAL)?)),)*
-                    _ => return Err(EINVAL),
-                }
-            };
+        let (_, effect) = create_effect(eftype, parsed)?;
+        if dryrun && effect.side_effects() {
+            return Err(EINVAL);
         }
-
-        let (sname, effect): (&'static str, Box<dyn Effect>) = match_effect! {
-            "delay" => detail::Delay, "flakey" => detail::Flakey, "maxsize" => detail::MaxSize,
-            "heatmap" => detail::HeatMap
-        };
         Ok(DefinedEffect {
-            name: sname.to_owned(),
+            name: name.to_owned(),
             effect,
             op,
+            priority,
+            dryrun,
+            when,
+            stats: Stats::default(),
         })
     }
+
+    // Effect type, the name without its suffix
+    pub fn eftype(&self) -> &str {
+        self.name.split_once("-").map_or(&self.name, |(eftype, _)| eftype)
+    }
+
+    // Parameters the effect can be created from again, without state reported alongside them
+    pub fn config(&self) -> JValue {
+        let mut out = serde_json::to_value(self).unwrap();
+        let fields = schema::find(self.eftype()).map_or(&[][..], |s| s.fields);
+        let accepted = |key: &str| {
+            ["op", "priority", "dryrun"].contains(&key)
+                || fields.iter().chain(schema::CONDITIONS).any(|f| f.name == key)
+        };
+        if let Some(obj) = out.as_object_mut() {
+            obj.retain(|key, _| accepted(key));
+        }
+        out
+    }
+}
+
+// Effects attached in the tree by path of their node and name, installed again with `import`
+pub fn export(tree: &ftree::Tree) -> serde_json::Map<String, JValue> {
+    tree.traverse(1)
+        .filter(|node| !node.effects.effects.is_empty())
+        .filter_map(|node| {
+            let path = tree.path(node.attr.ino as Ino)?;
+            let effects: serde_json::Map<_, _> = (&node.effects)
+                .into_iter()
+                .map(|de| (de.name.clone(), de.config()))
+                .collect();
+            Some((path, effects.into()))
+        })
+        .collect()
+}
+
+// Install effects exported with `export`, returning the ones at paths missing from the tree. Effects
+// that can't be created or attached anymore are skipped with a warning
+pub fn import(
+    tree: &mut ftree::Tree,
+    state: serde_json::Map<String, JValue>,
+) -> serde_json::Map<String, JValue> {
+    let mut missing = serde_json::Map::new();
+    for (path, effects) in state {
+        let Some(ino) = tree.resolve(&path) else {
+            missing.insert(path, effects);
+            continue;
+        };
+        let Some(effects) = effects.as_object() else {
+            eprintln!("Skipping effects at {}: not a json object", path);
+            continue;
+        };
+        for (name, params) in effects {
+            let installed = DefinedEffect::create(name, &params.to_string())
+                .and_then(|de| attach(tree, ino, de));
+            if let Err(errno) = installed {
+                eprintln!("Skipping effect {} at {}: errno {}", name, path, errno);
+            }
+        }
+    }
+    missing
+}
+
+// Bits due to flip by rot effects for the time passed since their last rot, by node they're attached at
+pub fn rot_due(tree: &ftree::Tree) -> Vec<(Ino, u64)> {
+    tree.traverse(1)
+        .flat_map(|node| {
+            (&node.effects)
+                .into_iter()
+                .filter_map(|de| de.effect.as_any().downcast_ref::<detail::Rot>())
+                .map(move |rot| (node.attr.ino as Ino, rot.due()))
+        })
+        .filter(|(_, due)| *due > 0)
+        .collect()
+}
+
+// Globs of paths effects may be attached at, anywhere if empty
+static CHAOS_PATHS: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+pub fn restrict_paths(globs: Vec<String>) {
+    *CHAOS_PATHS.lock().unwrap() = globs;
+}
+
+fn permitted(path: &str) -> bool {
+    let globs = CHAOS_PATHS.lock().unwrap();
+    globs.is_empty() || globs.iter().any(|g| crate::util::glob_match(g, path))
+}
+
+// Attach effect to node, unless it's outside of the paths effects are restricted to
+pub fn attach(tree: &mut ftree::Tree, ino: Ino, effect: DefinedEffect) -> Result<(), ErrNo> {
+    let path = tree.path(ino).ok_or(libc::ENOENT)?;
+    if !permitted(&path) {
+        return Err(libc::EPERM);
+    }
+    tree.get_mut(ino).ok_or(libc::ENOENT)?.effects.add(effect);
+    Ok(())
+}
+
+// Remove expired effects from all nodes
+pub fn prune(tree: &mut ftree::Tree) {
+    let inos: Vec<Ino> = tree.traverse(1).map(|n| n.attr.ino as Ino).collect();
+    for ino in inos {
+        if let Some(node) = tree.get_mut(ino) {
+            node.effects.prune();
+        }
+    }
+}
+
+// Release operations held by hang effects attached to node
+pub fn release(tree: &ftree::Tree, ino: Ino) -> Option<()> {
+    for de in &tree.get(ino)?.effects {
+        if let Some(hang) = de.effect.as_any().downcast_ref::<detail::Hang>() {
+            hang.release();
+        }
+    }
+    Some(())
+}
+
+// Reset consumption of quota effects attached to node
+pub fn reset_quota(tree: &ftree::Tree, ino: Ino) -> Option<()> {
+    for de in &tree.get(ino)?.effects {
+        if let Some(quota) = de.effect.as_any().downcast_ref::<detail::Quota>() {
+            quota.reset();
+        }
+    }
+    Some(())
+}
+
+// Check effects attached below `root`, returning problems found by inode
+pub fn check(tree: &ftree::Tree, root: Ino) -> Vec<(Ino, String)> {
+    let mut problems = vec![];
+    for node in tree.traverse(root) {
+        let ino = node.attr.ino as Ino;
+        for de in &node.effects {
+            if schema::find(de.eftype()).is_none() {
+                problems.push((ino, format!("effect {} has no schema", de.name)));
+            }
+            if de.op.is_empty() {
+                problems.push((ino, format!("effect {} applies to no operations", de.name)));
+            }
+            if !tree.path(ino).is_some_and(|path| permitted(&path)) {
+                problems.push((ino, format!("effect {} is outside of chaos paths", de.name)));
+            }
+        }
+    }
+    problems
 }
 
 #[derive(Default, Serialize)]
@@ -166,6 +757,10 @@ impl Group {
         self.effects.retain(|de| de.name != name);
     }
 
+    pub fn retain(&mut self, keep: impl FnMut(&DefinedEffect) -> bool) {
+        self.effects.retain(keep);
+    }
+
     pub fn add(&mut self, nde: DefinedEffect) {
         self.remove(&nde.name);
         self.effects.push(nde);
@@ -174,46 +769,267 @@ impl Group {
     pub fn find(&self, name: &str) -> Option<&DefinedEffect> {
         self.effects.iter().find(|de| de.name == name)
     }
+
+    // Remove effects past their ttl
+    fn prune(&mut self) {
+        self.effects.retain(|de| !de.when.expired());
+    }
+}
+
+// Bad ranges of files in the subtree of `ino` by path, as configured by badrange effects
+pub fn badblocks(tree: &ftree::Tree, ino: Ino) -> std::collections::BTreeMap<String, Vec<(usize, usize)>> {
+    let mut out = std::collections::BTreeMap::new();
+    for node in tree.traverse(ino) {
+        let file = node.attr.ino as Ino;
+        let (crate::ftypes::NodeItem::File(_), Some(path)) = (&node.item, tree.path(file)) else {
+            continue;
+        };
+        let ranges: Vec<_> = resolve(tree.climb(file))
+            .filter_map(|(_, de)| de.effect.as_any().downcast_ref::<detail::BadRange>())
+            .flat_map(|br| br.ranges_of(&path))
+            .collect();
+        if !ranges.is_empty() {
+            out.insert(path, ranges);
+        }
+    }
+    out
+}
+
+// Resolve effects applying to the first node of `it` in order of application, with their origin.
+// Effects run by descending priority, equal priorities from the target up the tree and in order of
+// attachment within a node
+pub fn resolve<'a>(
+    it: impl Iterator<Item = &'a crate::ftypes::Node>,
+) -> impl Iterator<Item = (Ino, &'a DefinedEffect)> {
+    let mut effects: Vec<_> = it
+        .flat_map(|node| {
+            (&node.effects)
+                .into_iter()
+                .map(move |de| (node.attr.ino as Ino, de))
+        })
+        .collect();
+    effects.sort_by_key(|(_, de)| std::cmp::Reverse(de.priority));
+    effects.into_iter()
 }
 
 pub fn run<'a>(
     it: impl Iterator<Item = &'a crate::ftypes::Node>,
-    mut ctx: Context,
+    ctx: &mut Context,
 ) -> (u64, Option<ErrNo>) {
     let mut sleep_ms: u64 = 0;
     let mut first_errno: Option<ErrNo> = None;
-    'outer: for node in it {
-        ctx.origin = node.attr.ino as Ino;
-        for DefinedEffect { effect, op, .. } in &node.effects {
-            if (ctx.op.optype() & *op).is_empty() {
-                continue;
+    for (origin, DefinedEffect { name, effect, op, when, stats, dryrun, .. }) in resolve(it) {
+        ctx.origin = origin;
+        let mut result = match (ctx.op.optype() & *op).is_empty() || !when.admit(ctx) {
+            true => None,
+            false if *dryrun => {
+                // Discard changes and actions requested by the effect
+                ctx.delayed_ms = sleep_ms;
+                let changes = std::mem::take(&mut ctx.changes);
+                let actions = std::mem::take(&mut ctx.actions);
+                let result = effect.apply(ctx);
+                ctx.changes = changes;
+                ctx.actions = actions;
+                Some(result)
             }
-            match effect.apply(&mut ctx) {
-                EffectResult::Ack => (),
-                EffectResult::Error(errno) => {
-                    first_errno = Some(errno);
-                    break 'outer;
-                }
-                EffectResult::Delay(ms) => {
-                    sleep_ms += ms;
-                }
+            false => {
+                ctx.delayed_ms = sleep_ms;
+                Some(effect.apply(ctx))
+            }
+        };
+        if let Some(ref mut trace) = ctx.trace {
+            let verdict = match result {
+                None => trace::Verdict::Skipped,
+                Some(EffectResult::Ack) => trace::Verdict::Ack,
+                Some(EffectResult::Error(errno)) => trace::Verdict::Error(errno),
+                Some(EffectResult::Delay(ms)) => trace::Verdict::Delay(ms),
+                Some(EffectResult::Timeout(ms, errno)) => trace::Verdict::Timeout { after_ms: ms, errno },
+            };
+            let verdict = if *dryrun { verdict.dry() } else { verdict };
+            trace.push((name.clone(), origin, verdict));
+        }
+        stats.record(&result);
+        if *dryrun {
+            result = None;
+        }
+        if !matches!(result, None | Some(EffectResult::Ack)) {
+            when.fired();
+        }
+        match result {
+            None | Some(EffectResult::Ack) => (),
+            Some(EffectResult::Error(errno)) => {
+                first_errno = Some(errno);
+                break;
+            }
+            Some(EffectResult::Delay(ms)) => {
+                sleep_ms += ms;
+            }
+            Some(EffectResult::Timeout(ms, errno)) => {
+                sleep_ms += ms;
+                first_errno = Some(errno);
+                break;
             }
         }
     }
     (sleep_ms, first_errno)
 }
 
-// Reply, possibly delayed
-pub fn reply(sleep_ms: u64, replier: impl FnOnce() + Send + 'static) {
+// Limits the number of injected errors per time window. Once exhausted, errors pass through
+pub struct ErrorBudget {
+    limit: usize,
+    window: Duration,
+    window_start: Instant,
+    spent: usize,
+}
+
+impl ErrorBudget {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        ErrorBudget {
+            limit,
+            window,
+            window_start: Instant::now(),
+            spent: 0,
+        }
+    }
+
+    // Spend one error, return false if the budget of the current window is exhausted
+    pub fn spend(&mut self) -> bool {
+        if self.window_start.elapsed() >= self.window {
+            self.window_start = Instant::now();
+            self.spent = 0;
+        }
+        if self.spent >= self.limit {
+            return false;
+        }
+        self.spent += 1;
+        true
+    }
+}
+
+// Fails operations once the resident memory of the daemon exceeds a limit
+pub struct RssGuard {
+    limit: u64,
+    errno: ErrNo,
+    last_check: Instant,
+    exceeded: bool,
+}
+
+impl RssGuard {
+    // Interval between rss checks
+    const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+    pub fn new(limit: u64, errno: ErrNo) -> Self {
+        RssGuard {
+            limit,
+            errno,
+            last_check: Instant::now() - Self::CHECK_INTERVAL,
+            exceeded: false,
+        }
+    }
+
+    // Error to fail operations with, if the limit is exceeded
+    pub fn check(&mut self) -> Option<ErrNo> {
+        if self.last_check.elapsed() >= Self::CHECK_INTERVAL {
+            self.last_check = Instant::now();
+            self.exceeded = crate::util::rss_bytes().is_some_and(|rss| rss > self.limit);
+        }
+        Some(self.errno).filter(|_| self.exceeded)
+    }
+}
+
+// Reply, possibly delayed. Long delays are tracked as in-flight operations
+pub fn reply<R: Replier>(sleep_ms: u64, label: &str, reply: R, replier: impl FnOnce(R) + Send + 'static) {
     if sleep_ms >= 5 {
-        std::thread::spawn(move || {
-            std::thread::sleep(Duration::from_millis(sleep_ms));
-            replier();
-        });
+        inflight::spawn(Some(Duration::from_millis(sleep_ms)), label, reply, replier);
     } else {
         if sleep_ms > 0 {
             std::thread::sleep(Duration::from_millis(sleep_ms));
         }
-        replier()
+        replier(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    // Apply effect to an operation on the root of a tree without other nodes
+    fn apply(de: &DefinedEffect, op: OpDesr) -> EffectResult {
+        use crate::ftypes::{Dir, Node, NodeItem};
+        let node = |ino| Node {
+            parent: 1,
+            item: NodeItem::Dir(Dir::default()),
+            attr: crate::fresh_attr(ino, fuser::FileType::Directory, 0, 0o755, 0, 0, 4096),
+            effects: Group::default(),
+            xattrs: std::collections::BTreeMap::new(),
+        };
+        let tree = ftree::Tree::new([node(0), node(1)]);
+        let mut rgen = rand::rngs::StdRng::seed_from_u64(0);
+        let mut ctx = Context {
+            op,
+            origin: 1,
+            target: 1,
+            entry: None,
+            caller: Caller { pid: 0, uid: 0, gid: 0 },
+            tree: &tree,
+            rgen: &mut rgen,
+            start: Instant::now(),
+            delayed_ms: 0,
+            actions: vec![],
+            changes: Changes::default(),
+            trace: None,
+        };
+        de.effect.apply(&mut ctx)
+    }
+
+    #[test]
+    fn dryrun_refuses_side_effects() {
+        // Installed, a dry run signal would still kill the caller and diskfull would count writes
+        let signal = DefinedEffect::create("signal", r#"{"op": "w", "dryrun": true}"#);
+        assert!(matches!(signal, Err(EINVAL)));
+        let diskfull = DefinedEffect::create("diskfull", r#"{"op": "w", "budget": 10, "dryrun": true}"#);
+        assert!(matches!(diskfull, Err(EINVAL)));
+        // Devices queue up operations of all effects with the same name
+        let device = r#"{"op": "rw", "name": "disk0", "bytes_per_sec": 1000, "dryrun": true}"#;
+        assert!(matches!(DefinedEffect::create("device", device), Err(EINVAL)));
+        // Ramps apply their child, which may not act beyond the operation either
+        let ramp = |child: &str| {
+            let params = format!(
+                r#"{{"op": "w", "effect": "{}", "ramp": {{"prob": [0.0, 1.0]}}, "duration_ms": 1000, "dryrun": true}}"#,
+                child
+            );
+            DefinedEffect::create("ramp", &params)
+        };
+        assert!(matches!(ramp("signal"), Err(EINVAL)));
+        assert!(ramp("flakey").is_ok());
+
+        let diskfull = DefinedEffect::create("diskfull", r#"{"op": "w", "budget": 10}"#).unwrap();
+        assert_eq!(diskfull.effect.display().unwrap(), r#"{"budget":10,"written":0}"#);
+        assert!(DefinedEffect::create("flakey", r#"{"op": "w", "prob": 1.0, "dryrun": true}"#).is_ok());
+    }
+    #[test]
+    fn xattr_erange_after_size_probe() {
+        let de = DefinedEffect::create("xattr", r#"{"op": "x", "erange": true}"#).unwrap();
+        let getxattr = |size| OpDesr::Getxattr { name: "user.tag".into(), size };
+        assert!(matches!(apply(&de, getxattr(0)), EffectResult::Ack));
+        assert!(matches!(apply(&de, getxattr(64)), EffectResult::Error(libc::ERANGE)));
+        assert!(matches!(apply(&de, OpDesr::Listxattr { size: 64 }), EffectResult::Error(libc::ERANGE)));
+        let setxattr = OpDesr::Setxattr { name: "user.tag".into(), size: 64 };
+        assert!(matches!(apply(&de, setxattr), EffectResult::Ack));
+
+        // Attached more broadly, other operations pass
+        let de = DefinedEffect::create("xattr", r#"{"op": "rwx"}"#).unwrap();
+        assert!(matches!(apply(&de, OpDesr::Listxattr { size: 0 }), EffectResult::Error(libc::ENOTSUP)));
+        let read = OpDesr::Read { offset: 0, len: 1, first: true };
+        assert!(matches!(apply(&de, read), EffectResult::Ack));
+    }
+    #[test]
+    fn script_timeout_falls_back() {
+        let script = r#"{"op": "r", "source": "while true do end", "timeout_ms": 10,
+            "fallback": {"decision": "error", "errno": "EIO"}}"#;
+        let de = DefinedEffect::create("script", script).unwrap();
+        let read = OpDesr::Read { offset: 0, len: 1, first: true };
+        assert!(matches!(apply(&de, read), EffectResult::Error(libc::EIO)));
     }
 }
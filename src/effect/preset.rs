@@ -0,0 +1,94 @@
+use libc::EINVAL;
+use serde::Serialize;
+
+use crate::effect::{self, DefinedEffect};
+use crate::ftree::Tree;
+use crate::ftypes::{ErrNo, Ino};
+
+// Curated combination of effects modeling a real device, installed with bf.effect.preset
+#[derive(Serialize)]
+pub struct Preset {
+    name: &'static str,
+    about: &'static str,
+    effects: &'static [(&'static str, &'static str)], // effect name and parameters
+}
+
+pub static PRESETS: &[Preset] = &[
+    Preset {
+        name: "usb2-stick",
+        about: "Cheap USB 2.0 flash drive: 35MB/s reads, 10MB/s writes, millisecond latencies",
+        effects: &[
+            ("throttle-preset-read", r#"{"op": "r", "bytes_per_sec": 35000000}"#),
+            ("throttle-preset-write", r#"{"op": "w", "bytes_per_sec": 10000000}"#),
+            (
+                "delay-preset",
+                r#"{"op": "rwf", "read_ms": 1, "write_ms": 5, "duration_ms": 20,
+                    "jitter": {"dist": "exponential", "mean_ms": 2}}"#,
+            ),
+        ],
+    },
+    Preset {
+        name: "nfs-wan",
+        about: "NFS mount over a WAN link: 40ms round trips for every operation, 100Mbit/s, rare errors",
+        effects: &[
+            (
+                "delay-preset",
+                r#"{"op": "rwlmsf", "duration_ms": 40, "jitter": {"dist": "normal", "mean_ms": 0, "stddev_ms": 10}}"#,
+            ),
+            ("throttle-preset", r#"{"op": "rw", "bytes_per_sec": 12500000}"#),
+            ("flakey-preset", r#"{"op": "rwlmf", "prob": 0.0005, "errno": "EIO"}"#),
+        ],
+    },
+    Preset {
+        name: "dying-hdd",
+        about: "Spinning disk wearing out over 10 minutes: seeks, 80MB/s and growing stalls and read errors",
+        effects: &[
+            ("seeklat-preset", r#"{"op": "rw", "per_mib_ms": 0.01, "max_ms": 15}"#),
+            ("throttle-preset", r#"{"op": "rw", "bytes_per_sec": 80000000}"#),
+            (
+                "dying-preset",
+                r#"{"op": "rwf", "over_ms": 600000, "max_delay_ms": 2000, "max_prob": 0.05,
+                    "curve": "exponential", "errno": "EIO"}"#,
+            ),
+        ],
+    },
+    Preset {
+        name: "sd-card",
+        about: "SD card: 20MB/s reads, 8MB/s writes with occasional garbage collection stalls",
+        effects: &[
+            ("throttle-preset-read", r#"{"op": "r", "bytes_per_sec": 20000000}"#),
+            ("throttle-preset-write", r#"{"op": "w", "bytes_per_sec": 8000000}"#),
+            (
+                "delay-preset",
+                r#"{"op": "wf", "duration_ms": 2, "jitter": {"dist": "pareto", "scale_ms": 1, "shape": 2}}"#,
+            ),
+        ],
+    },
+];
+
+// Effects installed by presets are marked by their name
+fn installed(name: &str) -> bool {
+    name.contains("-preset")
+}
+
+// Replace effects of a previously installed preset at `ino` with the ones of preset `name`
+pub fn install(tree: &mut Tree, ino: Ino, name: &str) -> Result<(), ErrNo> {
+    let name = name.trim().trim_matches('"');
+    let preset = PRESETS.iter().find(|p| p.name == name).ok_or(EINVAL)?;
+    let effects = preset
+        .effects
+        .iter()
+        .map(|(name, data)| DefinedEffect::create(name, data))
+        .collect::<Result<Vec<_>, _>>()?;
+    uninstall(tree, ino).ok_or(libc::ENOENT)?;
+    for effect in effects {
+        effect::attach(tree, ino, effect)?;
+    }
+    Ok(())
+}
+
+// Remove effects installed by a preset at `ino`
+pub fn uninstall(tree: &mut Tree, ino: Ino) -> Option<()> {
+    tree.get_mut(ino)?.effects.retain(|de| !installed(&de.name));
+    Some(())
+}
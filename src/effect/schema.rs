@@ -0,0 +1,462 @@
+use libc::EINVAL;
+use serde::Serialize;
+use serde_json::Value as JValue;
+
+use crate::ftypes::ErrNo;
+
+// Parameter of an effect
+#[derive(Serialize)]
+pub struct Field {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub ty: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<&'static str>, // none if required or an alternative to other fields
+}
+
+// Parameters of an effect type, as accepted in bf.effect.<type> values besides "op"
+#[derive(Serialize)]
+pub struct Schema {
+    #[serde(rename = "type")]
+    pub name: &'static str,
+    pub about: &'static str,
+    pub fields: &'static [Field],
+}
+
+const fn f(name: &'static str, ty: &'static str) -> Field {
+    Field { name, ty, default: None }
+}
+
+const fn d(name: &'static str, ty: &'static str, default: &'static str) -> Field {
+    Field { name, ty, default: Some(default) }
+}
+
+pub static SCHEMAS: &[Schema] = &[
+    Schema {
+        name: "delay",
+        about: "Delay operations by duration_ms, reads by read_ms and writes by write_ms if set, plus random jitter, only of min_len to max_len bytes if set",
+        fields: &[
+            d("duration_ms", "u64", "0"),
+            d("read_ms", "u64", "null"),
+            d("write_ms", "u64", "null"),
+            d(
+                "jitter",
+                "{dist: normal, mean_ms: f64, stddev_ms: f64} | {dist: exponential, mean_ms: f64} | {dist: pareto, scale_ms: f64, shape: f64}",
+                "null",
+            ),
+            d("min_len", "usize", "null"),
+            d("max_len", "usize", "null"),
+        ],
+    },
+    Schema {
+        name: "flakey",
+        about: "Fail operations with errno with probability prob or during unavailability intervals",
+        fields: &[
+            f("prob", "f32"),
+            f("avail_ms", "u64"),
+            f("unavail_ms", "u64"),
+            d("errno", "errno", "EIO"),
+        ],
+    },
+    Schema {
+        name: "maxsize",
+        about: "Fail writes growing files beyond limit bytes",
+        fields: &[f("limit", "usize")],
+    },
+    Schema {
+        name: "heatmap",
+        about: "Count accessed ranges aligned to align bytes",
+        fields: &[d("align", "usize", "4096")],
+    },
+    Schema {
+        name: "quota",
+        about: "Fail reads and writes with EDQUOT once their volume rounded up to align bytes exceeds volume",
+        fields: &[f("volume", "usize"), d("align", "usize", "1")],
+    },
+    Schema {
+        name: "slo",
+        about: "Shape latencies toward target percentiles",
+        fields: &[f("percentiles", "[[f32, u64]]")],
+    },
+    Schema {
+        name: "heal",
+        about: "Repair damaged blocks after they have been damaged for after_ms",
+        fields: &[d("after_ms", "u64", "0")],
+    },
+    Schema {
+        name: "ramp",
+        about: "Interpolate numeric parameters of a child effect without state over duration_ms, integers stay integers",
+        fields: &[
+            f("effect", "string"),
+            d("params", "object", "{}"),
+            f("ramp", "{string: [f64, f64]}"),
+            f("duration_ms", "u64"),
+        ],
+    },
+    Schema {
+        name: "maxio",
+        about: "Cap read/write sizes at limit bytes, or reject them",
+        fields: &[f("limit", "usize"), d("reject", "bool", "false")],
+    },
+    Schema {
+        name: "interleave",
+        about: "Persist writes in chunks with interval_ms in between",
+        fields: &[f("chunk", "usize"), f("interval_ms", "u64")],
+    },
+    Schema {
+        name: "pagefault",
+        about: "Fail or delay operations serving the page cache",
+        fields: &[d("errno", "errno", "null"), d("delay_ms", "u64", "0")],
+    },
+    Schema {
+        name: "nomem",
+        about: "Return resource exhaustion errors with probability prob",
+        fields: &[f("prob", "f32"), d("errno", "errno", "ENOMEM")],
+    },
+    Schema {
+        name: "relink",
+        about: "Rewrite targets returned by readlink",
+        fields: &[f("targets", "[path]"), d("random", "bool", "false")],
+    },
+    Schema {
+        name: "diverge",
+        about: "Make reads through the hard link entry name return different contents",
+        fields: &[f("name", "string"), f("mode", "stale|corrupt")],
+    },
+    Schema {
+        name: "pathlat",
+        about: "Charge lookup latency proportional to path depth",
+        fields: &[f("per_component_ms", "u64")],
+    },
+    Schema {
+        name: "external",
+        about: "Let an external process listening on socket decide on operations",
+        fields: &[
+            f("socket", "path"),
+            d("timeout_ms", "u64", "1000"),
+            d("fallback", "decision", "{\"decision\": \"ok\"}"),
+        ],
+    },
+    Schema {
+        name: "device",
+        about: "Queue operations on a simulated device shared by name",
+        fields: &[
+            f("name", "string"),
+            f("bytes_per_sec", "u64"),
+            d("read_bytes_per_sec", "u64", "null"),
+            d("write_bytes_per_sec", "u64", "null"),
+            d("op_us", "u64", "0"),
+        ],
+    },
+    Schema {
+        name: "verify",
+        about: "Read back and compare written data, failing mismatches with errno if set",
+        fields: &[d("errno", "errno", "null")],
+    },
+    Schema {
+        name: "cow",
+        about: "Break copy-on-write semantics of copied ranges",
+        fields: &[f("mode", "copy|shared")],
+    },
+    Schema {
+        name: "rowindow",
+        about: "Fail operations during recurring windows",
+        fields: &[
+            f("windows", "[\"HH:MM-HH:MM\" | [u64, u64]]"),
+            d("period_ms", "u64", "86400000"),
+            d("errno", "errno", "EBUSY"),
+        ],
+    },
+    Schema {
+        name: "negative",
+        about: "Serve spurious negative lookups of recently created names",
+        fields: &[f("window_ms", "u64"), d("ttl_ms", "u64", "1000"), d("prob", "f32", "1.0")],
+    },
+    Schema {
+        name: "onidle",
+        about: "Apply a child effect only after idle_ms of quiescence in the subtree",
+        fields: &[f("idle_ms", "u64"), f("effect", "string"), d("params", "object", "{}")],
+    },
+    Schema {
+        name: "corruptread",
+        about: "Flip bits or bytes of data returned by reads",
+        fields: &[d("count", "usize", "1"), d("unit", "bit|byte", "bit"), d("prob", "f32", "1.0")],
+    },
+    Schema {
+        name: "corruptwrite",
+        about: "Corrupt written data before it's persisted",
+        fields: &[
+            f("mode", "flip|zero|shift"),
+            d("count", "usize", "1"),
+            d("unit", "bit|byte", "bit"),
+            f("len", "usize"),
+            f("by", "i64"),
+            d("prob", "f32", "1.0"),
+        ],
+    },
+    Schema {
+        name: "policy",
+        about: "Allow or deny operations by name",
+        fields: &[
+            d("allow", "[string]", "null"),
+            d("deny", "[string]", "[]"),
+            d("errno", "errno", "EPERM"),
+        ],
+    },
+    Schema {
+        name: "coalesce",
+        about: "Coalesce writes into batches persisted window_ms after the first write",
+        fields: &[f("window_ms", "u64")],
+    },
+    Schema {
+        name: "lostwrite",
+        about: "Acknowledge writes without persisting them",
+        fields: &[d("prob", "f32", "null"), d("every", "usize", "null")],
+    },
+    Schema {
+        name: "torn",
+        about: "Persist only a prefix of writes up to a random aligned boundary",
+        fields: &[d("align", "usize", "512"), d("prob", "f32", "1.0")],
+    },
+    Schema {
+        name: "misdirect",
+        about: "Persist writes at an offset moved by by bytes, a block in a random direction by default",
+        fields: &[f("prob", "f32"), d("by", "i64", "null")],
+    },
+    Schema {
+        name: "dupwrite",
+        about: "Persist writes once more at the next aligned offset, the block size by default",
+        fields: &[d("prob", "f32", "1.0"), d("align", "usize", "null")],
+    },
+    Schema {
+        name: "badrange",
+        about: "Fail reads overlapping any of the (offset, len) ranges, of all files or by path",
+        fields: &[
+            d("ranges", "[[usize, usize]]", "[]"),
+            d("paths", "{path: [[usize, usize]]}", "{}"),
+            d("errno", "errno", "EIO"),
+        ],
+    },
+    Schema {
+        name: "model",
+        about: "Fail and delay operations by error frequencies and latency percentiles per operation name",
+        fields: &[f(
+            "ops",
+            "{string: {errors: [{errno: errno, freq: f32}], latency: {percentiles: [[f32, u64]]}}}",
+        )],
+    },
+    Schema {
+        name: "scrub",
+        about: "Keep shadow checksums of blocks on write and verify them on read",
+        fields: &[d("block", "usize", "4096")],
+    },
+    Schema {
+        name: "rot",
+        about: "Flip random bits of files below the node at rate flips per second, independently of io",
+        fields: &[f("rate", "f64")],
+    },
+    Schema {
+        name: "diskfill",
+        about: "Shrink free space to zero over duration_ms while ramping ENOSPC on writes, recover after hold_ms",
+        fields: &[f("duration_ms", "u64"), d("hold_ms", "u64", "0"), d("max_prob", "f32", "1.0")],
+    },
+    Schema {
+        name: "signal",
+        about: "Send a signal to the calling process or processes named process",
+        fields: &[
+            d("signal", "signal", "SIGKILL"),
+            d("process", "string", "null"),
+            d("prob", "f32", "1.0"),
+            d("repeat", "bool", "false"),
+        ],
+    },
+    Schema {
+        name: "diskfull",
+        about: "Fail writes with ENOSPC once budget bytes have been written, optionally shrinking statfs",
+        fields: &[f("budget", "u64"), d("statfs", "bool", "false")],
+    },
+    Schema {
+        name: "readonly",
+        about: "Fail writes and metadata changes with EROFS",
+        fields: &[d("errno", "errno", "EROFS")],
+    },
+    Schema {
+        name: "accessdenied",
+        about: "Fail operations with EACCES with probability prob or every every-th call, always if neither is set",
+        fields: &[d("prob", "f32", "null"), d("every", "usize", "null"), d("errno", "errno", "EACCES")],
+    },
+    Schema {
+        name: "busy",
+        about: "Fail unlink, rmdir and rename with EBUSY with probability prob",
+        fields: &[d("prob", "f32", "1.0")],
+    },
+    Schema {
+        name: "xdev",
+        about: "Fail renames between different subtrees with EXDEV, as if they were separate mounts",
+        fields: &[f("subtrees", "[glob]")],
+    },
+    Schema {
+        name: "estale",
+        about: "Fail operations on nodes with ESTALE once renamed or after_ms after lookup, until looked up again",
+        fields: &[d("renamed", "bool", "true"), d("after_ms", "u64", "null")],
+    },
+    Schema {
+        name: "namerules",
+        about: "Fail new names longer than max_len bytes with ENAMETOOLONG and with forbidden characters with EINVAL",
+        fields: &[d("max_len", "usize", "null"), d("forbidden", "string", "\"\"")],
+    },
+    Schema {
+        name: "iops",
+        about: "Delay operations beyond limit per second and bursts of burst, shared by the subtree or per file with per_file",
+        fields: &[f("limit", "u64"), d("burst", "u64", "1"), d("per_file", "bool", "false")],
+    },
+    Schema {
+        name: "ttfb",
+        about: "Delay the first read through every file handle by delay_ms",
+        fields: &[f("delay_ms", "u64")],
+    },
+    Schema {
+        name: "seeklat",
+        about: "Delay reads and writes by per_mib_ms for every MiB of distance from the previous operation on the file",
+        fields: &[f("per_mib_ms", "f64"), d("max_ms", "u64", "null")],
+    },
+    Schema {
+        name: "aging",
+        about: "Delay reads by per_write_us for every write the file has seen, up to max_ms",
+        fields: &[f("per_write_us", "u64"), d("max_ms", "u64", "null")],
+    },
+    Schema {
+        name: "burst",
+        about: "Start storms of length consecutive errors with probability prob per healthy operation",
+        fields: &[f("prob", "f32"), f("length", "usize"), d("errno", "errno", "EIO")],
+    },
+    Schema {
+        name: "hang",
+        about: "Hold replies with probability prob until the effect is removed or bf.release is set",
+        fields: &[d("prob", "f32", "1.0")],
+    },
+    Schema {
+        name: "deadline",
+        about: "Delay operations by timeout_ms, then fail them with errno, with probability prob",
+        fields: &[f("timeout_ms", "u64"), d("errno", "errno", "EIO"), d("prob", "f32", "1.0")],
+    },
+    Schema {
+        name: "dying",
+        about: "Grow delays to max_delay_ms and error probability to max_prob along curve over over_ms or over_ops",
+        fields: &[
+            f("over_ms", "u64"),
+            f("over_ops", "u64"),
+            d("max_delay_ms", "u64", "0"),
+            d("max_prob", "f32", "0"),
+            d("curve", "linear|quadratic|exponential", "linear"),
+            d("errno", "errno", "EIO"),
+        ],
+    },
+    Schema {
+        name: "maxconc",
+        about: "Fail operations with errno while limit operations are active, in the subtree or file with per_file",
+        fields: &[f("limit", "usize"), d("per_file", "bool", "false"), d("errno", "errno", "EBUSY")],
+    },
+    Schema {
+        name: "throttle",
+        about: "Delay reads and writes beyond bytes_per_sec and bursts of burst_bytes, shared by the subtree or per file",
+        fields: &[
+            f("bytes_per_sec", "u64"),
+            d("burst_bytes", "u64", "bytes_per_sec"),
+            d("per_file", "bool", "false"),
+        ],
+    },
+    Schema {
+        name: "script",
+        about: "Decide on operations with a Lua chunk receiving the operation and returning a decision like the external decider",
+        fields: &[
+            f("source", "lua"),
+            d("fallback", "decision", "{decision: ok}"),
+            d("timeout_ms", "u64", "100"),
+        ],
+    },
+    Schema {
+        name: "hook",
+        about: "Decide on operations with an external command or http webhook answering like the external decider",
+        fields: &[
+            d("command", "string", "null"),
+            d("url", "http url", "null"),
+            d("timeout_ms", "u64", "1000"),
+            d("fallback", "decision", "{decision: ok}"),
+        ],
+    },
+    Schema {
+        name: "vanish",
+        about: "Fail lookups of existing entries with ENOENT with probability prob or during unavail_ms of every period",
+        fields: &[f("prob", "f32"), f("avail_ms", "u64"), f("unavail_ms", "u64")],
+    },
+    Schema {
+        name: "dirflake",
+        about: "Omit entries from directory listings with probability prob each, without touching the tree",
+        fields: &[d("prob", "f32", "1.0")],
+    },
+    Schema {
+        name: "dirshuffle",
+        about: "Shuffle directory listings differently every time and list entries twice with probability dup_prob each",
+        fields: &[d("shuffle", "bool", "true"), d("dup_prob", "f32", "0.0")],
+    },
+    Schema {
+        name: "fakefs",
+        about: "Report blocks, free blocks as bavail or by the used fraction, files and free inodes with statfs",
+        fields: &[
+            d("blocks", "u64", "262144"),
+            d("bavail", "u64", "null"),
+            d("used", "f64", "null"),
+            d("files", "u64", "null"),
+            d("ffree", "u64", "null"),
+        ],
+    },
+    Schema {
+        name: "xattr",
+        about: "Fail operations on user extended attributes with errno and probability prob, or only setting values larger than max_size with E2BIG, or with erange reading values into a buffer with ERANGE",
+        fields: &[
+            d("errno", "errno", "ENOTSUP"),
+            d("prob", "f32", "1.0"),
+            d("max_size", "usize", "null"),
+            d("erange", "bool", "false"),
+        ],
+    },
+];
+
+// Conditions accepted by all effect types, along with their priority and dry run mode
+pub static CONDITIONS: &[Field] = &[
+    d("nth", "{every: u64, offset: u64}", "null"),
+    d("ttl_ms", "u64", "null"),
+    d("start_after_ms", "u64", "0"),
+    d("active_ms", "u64", "null"),
+    d("repeat_ms", "u64", "null"),
+    d("max_hits", "u64", "null"),
+    d("skip", "u64", "null"),
+    d("path", "glob", "null"),
+    d("uid", "u32", "null"),
+    d("gid", "u32", "null"),
+    d("pid", "u32", "null"),
+    d("comm", "regex", "null"),
+    d("range", "{offset: usize, len: usize}", "null"),
+    d("all", "[conditions]", "[]"),
+    d("any", "[conditions]", "[]"),
+    d("not", "conditions", "null"),
+    d("priority", "i32", "0"),
+    d("dryrun", "bool", "false"),
+];
+
+pub fn find(name: &str) -> Option<&'static Schema> {
+    SCHEMAS.iter().find(|s| s.name == name)
+}
+
+// Reject parameters unknown to the schema of `eftype`, which serde would silently ignore
+pub fn check(eftype: &str, params: &JValue) -> Result<(), ErrNo> {
+    let schema = find(eftype).ok_or(EINVAL)?;
+    let Some(obj) = params.as_object() else {
+        return Err(EINVAL);
+    };
+    match obj.keys().all(|k| schema.fields.iter().any(|f| f.name == k)) {
+        true => Ok(()),
+        false => Err(EINVAL),
+    }
+}
@@ -0,0 +1,48 @@
+use serde::Serialize;
+use serde_json::Value as JValue;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::ftypes::Ino;
+
+// Number of most recent events kept
+const CAPACITY: usize = 256;
+
+// Notable event, like a damaged or repaired block
+#[derive(Serialize)]
+pub struct Event {
+    pub time_ms: u128,
+    pub ino: Ino,
+    pub kind: &'static str,
+    pub detail: JValue,
+}
+
+static EVENTS: Mutex<VecDeque<Event>> = Mutex::new(VecDeque::new());
+
+// Record event, dropping the oldest one if full
+pub fn emit(ino: Ino, kind: &'static str, detail: JValue) {
+    let time_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let mut events = EVENTS.lock().unwrap();
+    if events.len() >= CAPACITY {
+        events.pop_front();
+    }
+    events.push_back(Event {
+        time_ms,
+        ino,
+        kind,
+        detail,
+    });
+}
+
+// Serialize all recorded events, oldest first
+pub fn dump() -> String {
+    serde_json::to_string(&*EVENTS.lock().unwrap()).unwrap()
+}
+
+pub fn clear() {
+    EVENTS.lock().unwrap().clear();
+}
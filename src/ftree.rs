@@ -1,5 +1,7 @@
 use fuser::FileAttr;
 use libc::ENOENT;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 
 use crate::ftypes::{Dir, ErrNo, Ino, Node, NodeItem};
 use crate::util::AttrOps;
@@ -7,6 +9,8 @@ use crate::util::AttrOps;
 pub struct Tree {
     nodes: Vec<Option<Node>>,
     freelist: Vec<Ino>,
+    activity: BTreeMap<Ino, Instant>, // last activity within subtree
+    since: Instant,
 }
 
 // A tree manages a set of nodes, their directory structure and lifetimes
@@ -15,9 +19,25 @@ impl Tree {
         Tree {
             nodes: nodes.into_iter().map(|n| Some(n)).collect(),
             freelist: vec![],
+            activity: BTreeMap::new(),
+            since: Instant::now(),
         }
     }
 
+    // Record activity at `ino`, which is activity within subtrees of all its ancestors
+    pub fn touch(&mut self, ino: Ino) {
+        let now = Instant::now();
+        let inos: Vec<Ino> = self.climb(ino).map(|n| n.attr.ino as Ino).collect();
+        for ino in inos {
+            self.activity.insert(ino, now);
+        }
+    }
+
+    // Time passed without activity within subtree of `ino`
+    pub fn idle(&self, ino: Ino) -> Duration {
+        self.activity.get(&ino).unwrap_or(&self.since).elapsed()
+    }
+
     // Count number of occupied nodes
     pub fn count(&self) -> usize {
         self.nodes.iter().filter(|n| n.is_some()).count()
@@ -61,6 +81,54 @@ impl Tree {
         }
     }
 
+    // Resolve path of `ino` relative to root, hard linked nodes resolve to their first entry
+    pub fn path(&self, ino: Ino) -> Option<String> {
+        let mut names = vec![];
+        let mut cur = ino;
+        for node in self.climb(ino).skip(1) {
+            let NodeItem::Dir(ref dir) = node.item else {
+                return None;
+            };
+            names.push(dir.list().find(|(i, _)| *i == cur)?.1);
+            cur = node.attr.ino as Ino;
+        }
+        names.reverse();
+        Some(format!("/{}", names.join("/")))
+    }
+
+    // Find node at `path` relative to root
+    pub fn resolve(&self, path: &str) -> Option<Ino> {
+        let mut ino = 1;
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            let NodeItem::Dir(ref dir) = self.get(ino)?.item else {
+                return None;
+            };
+            ino = dir.lookup(name)?;
+        }
+        Some(ino)
+    }
+
+    // Resolve path of `ino` relative to its ancestor `base`, None if `ino` isn't below `base`
+    pub fn relative_path(&self, ino: Ino, base: Ino) -> Option<String> {
+        let mut names = vec![];
+        let mut cur = ino;
+        for node in self.climb(ino).skip(1) {
+            if cur == base {
+                break;
+            }
+            let NodeItem::Dir(ref dir) = node.item else {
+                return None;
+            };
+            names.push(dir.list().find(|(i, _)| *i == cur)?.1);
+            cur = node.attr.ino as Ino;
+        }
+        if cur != base {
+            return None;
+        }
+        names.reverse();
+        Some(names.join("/"))
+    }
+
     pub fn traverse(&self, ino: Ino) -> impl Iterator<Item = &Node> {
         struct It<'a> {
             q: std::collections::VecDeque<Ino>,
@@ -85,6 +153,53 @@ impl Tree {
         }
     }
 
+    // Check structural invariants, returning problems found by inode
+    pub fn check(&self) -> Vec<(Ino, String)> {
+        let mut problems = vec![];
+        let mut links: BTreeMap<Ino, u32> = BTreeMap::new();
+        for (ino, node) in self.nodes.iter().enumerate() {
+            let Some(node) = node else {
+                continue;
+            };
+            if node.attr.ino as Ino != ino {
+                problems.push((ino, format!("attributes carry inode {}", node.attr.ino)));
+            }
+            if !matches!(self.get(node.parent).map(|p| &p.item), Some(NodeItem::Dir(_))) {
+                problems.push((ino, format!("parent {} is not a directory", node.parent)));
+            }
+            if let NodeItem::Dir(ref dir) = node.item {
+                if node.attr.size != dir.entries() as u64 {
+                    problems.push((ino, format!("size {} with {} entries", node.attr.size, dir.entries())));
+                }
+                for (child, name) in dir.list() {
+                    match self.get(child) {
+                        Some(_) => *links.entry(child).or_default() += 1,
+                        None => problems.push((ino, format!("entry {} points to missing inode {}", name, child))),
+                    }
+                }
+            }
+        }
+
+        // Files are counted by their entries, directories by the entry they're attached with
+        for (ino, node) in self.nodes.iter().enumerate().skip(2) {
+            let Some(node) = node else {
+                continue;
+            };
+            let entries = links.get(&ino).copied().unwrap_or(0);
+            if entries == 0 {
+                problems.push((ino, "orphaned, no entry points to it".to_owned()));
+            } else if !matches!(node.item, NodeItem::Dir(_)) && entries != node.attr.nlink {
+                problems.push((ino, format!("{} links with {} entries", node.attr.nlink, entries)));
+            }
+        }
+        for ino in &self.freelist {
+            if self.get(*ino).is_some() {
+                problems.push((*ino, "free inode is occupied".to_owned()));
+            }
+        }
+        problems
+    }
+
     // Add node to `parent` under `name` pointing to `ino`
     fn add_entry(&mut self, ino: Ino, parent: Ino, name: String) -> Result<(), ErrNo> {
         let (pdir, pattr) = self.get_dir_mut(parent).ok_or(ENOENT)?;
@@ -153,7 +268,9 @@ impl Tree {
 
         let attr = &mut self.get_mut(ino).unwrap().attr;
         attr.nlink_balance(-1);
-        self.nodes[ino].take_if(|n| n.attr.nlink == 0);
+        if self.nodes[ino].take_if(|n| n.attr.nlink == 0).is_some() {
+            self.activity.remove(&ino);
+        }
         Ok(())
     }
 }
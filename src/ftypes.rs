@@ -1,47 +1,248 @@
 use fuser::FileAttr;
-use serde::Serialize;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::Instant;
 
 use crate::effect::Group;
-use crate::storage::Storage;
+use crate::events;
+use crate::procfs::ProcFile;
+use crate::storage::{Storage, freeze};
 
 pub type Ino = usize;
 pub type ErrNo = libc::c_int;
 
+// Node ids handed to the kernel carry the alias of the entry the node was looked up by in upper bits
+pub const ALIAS_SHIFT: u32 = 32;
+
+// Inode of node id
+pub fn ino_of(nodeid: u64) -> Ino {
+    (nodeid & ((1 << ALIAS_SHIFT) - 1)) as Ino
+}
+
+// Errno values in json are emitted as symbolic names ("EIO") and accepted as names or numbers
+pub mod errno {
+    use super::ErrNo;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    macro_rules! errno_table {
+        ($($name:ident),*) => {
+            const TABLE: &[(&str, ErrNo)] = &[$((stringify!($name), libc::$name)),*];
+        };
+    }
+
+    errno_table!(
+        EPERM, ENOENT, EINTR, EIO, ENXIO, E2BIG, EBADF, EAGAIN, ENOMEM, EACCES, EFAULT, EBUSY,
+        EEXIST, EXDEV, ENODEV, ENOTDIR, EISDIR, EINVAL, ENFILE, EMFILE, ETXTBSY, EFBIG, ENOSPC,
+        ESPIPE, EROFS, EMLINK, EPIPE, ERANGE, EDEADLK, ENAMETOOLONG, ENOLCK, ENOSYS, ENOTEMPTY,
+        ELOOP, ENODATA, ETIMEDOUT, ESTALE, EDQUOT, EOPNOTSUPP, EOVERFLOW, ECANCELED, EILSEQ,
+        EBADMSG, ENOTCONN, ECONNRESET, EREMOTEIO, ENOTSUP
+    );
+
+    // Errnos applications rarely expect from filesystems, accepted only once allowed
+    const UNSAFE: &[ErrNo] = &[libc::EFAULT, libc::EBADF, libc::EOVERFLOW, libc::ERANGE];
+
+    static ALLOW_UNSAFE: AtomicBool = AtomicBool::new(false);
+
+    pub fn allow_unsafe(allow: bool) {
+        ALLOW_UNSAFE.store(allow, Ordering::Relaxed);
+    }
+
+    // Symbolic name of errno, if known
+    pub fn name(errno: ErrNo) -> Option<&'static str> {
+        TABLE.iter().find(|(_, e)| *e == errno).map(|(n, _)| *n)
+    }
+
+    // Parse errno from its symbolic name or number
+    pub fn parse(s: &str) -> Option<ErrNo> {
+        let s = s.trim();
+        TABLE
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(s))
+            .map(|(_, e)| *e)
+            .or_else(|| s.parse().ok())
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Number(ErrNo),
+        Name(String),
+    }
+
+    pub fn serialize<S: Serializer>(errno: &ErrNo, s: S) -> Result<S::Ok, S::Error> {
+        match name(*errno) {
+            Some(name) => s.serialize_str(name),
+            None => s.serialize_i32(*errno),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<ErrNo, D::Error> {
+        let errno = match Repr::deserialize(d)? {
+            Repr::Number(errno) => errno,
+            Repr::Name(name) => parse(&name).ok_or_else(|| D::Error::custom("unknown errno"))?,
+        };
+        if UNSAFE.contains(&errno) && !ALLOW_UNSAFE.load(Ordering::Relaxed) {
+            return Err(D::Error::custom("unsafe errno"));
+        }
+        Ok(errno)
+    }
+
+    // Same for optional errno values
+    pub mod option {
+        use super::ErrNo;
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(errno: &Option<ErrNo>, s: S) -> Result<S::Ok, S::Error> {
+            match errno {
+                Some(errno) => super::serialize(errno, s),
+                None => s.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<ErrNo>, D::Error> {
+            #[derive(Deserialize)]
+            struct Wrapped(#[serde(with = "super")] ErrNo);
+            Ok(Option::<Wrapped>::deserialize(d)?.map(|Wrapped(errno)| errno))
+        }
+    }
+}
+
+// Order of entries in directory listings
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DirOrder {
+    #[default]
+    Insertion,
+    Lexicographic,
+    Reverse, // newest first
+    Hashed,  // by name hash, like ext4
+    Random,  // different for every listing
+}
+
+impl DirOrder {
+    // Arrange entries listed in insertion order. Random order depends on `seed`
+    pub fn arrange(self, entries: &mut [(Ino, String)], seed: u64) {
+        match self {
+            DirOrder::Insertion => (),
+            DirOrder::Lexicographic => entries.sort_by(|a, b| a.1.cmp(&b.1)),
+            DirOrder::Reverse => entries.reverse(),
+            DirOrder::Hashed => entries.sort_by_cached_key(|(_, name)| {
+                let mut hasher = DefaultHasher::new();
+                name.hash(&mut hasher);
+                hasher.finish()
+            }),
+            DirOrder::Random => entries.shuffle(&mut StdRng::seed_from_u64(seed)),
+        }
+    }
+}
+
+// Directories with more entries than this are indexed by name
+const INDEX_THRESHOLD: usize = 1024;
+
 // Dir manages a list of children. It does NOT manage the nodes lifetimes
 #[derive(Default)]
 pub struct Dir {
-    children: Vec<(Ino, String)>,
+    // Entries in insertion order with their sequence numbers, which serve as positions of listings.
+    // Removed entries leave holes until compaction, sequence numbers never change
+    children: Vec<(usize, Option<(Ino, String)>)>,
+    next: usize, // sequence number of the next entry
+    holes: usize,
+    index: Option<BTreeMap<String, usize>>, // sequence numbers by name of large directories
+    pub order: Option<DirOrder>, // listing order, mount default if unset
 }
 
 impl Dir {
+    // Index of entry in children by name
+    fn position(&self, name: &(impl AsRef<OsStr> + ?Sized)) -> Option<usize> {
+        let name = name.as_ref();
+        match self.index {
+            Some(ref index) => {
+                let seq = *index.get(name.to_str()?)?;
+                self.children.binary_search_by_key(&seq, |(seq, _)| *seq).ok()
+            }
+            None => self
+                .children
+                .iter()
+                .position(|(_, c)| c.as_ref().is_some_and(|(_, fname)| name == fname.as_str())),
+        }
+    }
+
     // Find entry by name
-    pub fn lookup(&self, name: &(impl PartialEq<str> + ?Sized)) -> Option<Ino> {
-        self.children
-            .iter()
-            .filter(|(_, fname)| name == fname.as_str())
-            .map(|(fino, _)| *fino)
-            .next()
+    pub fn lookup(&self, name: &(impl AsRef<OsStr> + ?Sized)) -> Option<Ino> {
+        self.children[self.position(name)?].1.as_ref().map(|(fino, _)| *fino)
     }
 
     // List all entries in undefined order
     pub fn list(&self) -> impl Iterator<Item = (Ino, &str)> {
         self.children
             .iter()
+            .filter_map(|(_, c)| c.as_ref())
             .map(|(fino, fname)| (*fino, fname.as_str()))
     }
 
+    // List entries in insertion order from position `from` on, with their positions. Positions stay
+    // stable across removals, so listings in progress neither skip nor repeat entries
+    pub fn page(&self, from: usize) -> impl Iterator<Item = (usize, Ino, &str)> {
+        let start = self.children.partition_point(|(seq, _)| *seq < from);
+        self.children[start..]
+            .iter()
+            .filter_map(|(seq, c)| c.as_ref().map(|(fino, fname)| (*seq, *fino, fname.as_str())))
+    }
+
+    // Number of entries
+    pub fn entries(&self) -> usize {
+        self.children.len() - self.holes
+    }
+
+    pub fn indexed(&self) -> bool {
+        self.index.is_some()
+    }
+
     // Add entry
     pub fn add(&mut self, ino: Ino, name: String) {
-        self.children.push((ino, name))
+        let seq = self.next;
+        self.next += 1;
+        if let Some(ref mut index) = self.index {
+            index.insert(name.clone(), seq);
+        }
+        self.children.push((seq, Some((ino, name))));
+        if self.index.is_none() && self.entries() > INDEX_THRESHOLD {
+            self.reindex();
+        }
     }
 
     // Remove entry and return removed inode
-    pub fn remove(&mut self, name: &(impl PartialEq<str> + ?Sized)) -> Option<Ino> {
-        let ino = self.lookup(name)?;
-        self.children.retain(|(_, fname)| name != fname.as_str());
+    pub fn remove(&mut self, name: &(impl AsRef<OsStr> + ?Sized)) -> Option<Ino> {
+        let pos = self.position(name)?;
+        let (ino, fname) = self.children[pos].1.take()?;
+        self.holes += 1;
+        if let Some(ref mut index) = self.index {
+            index.remove(&fname);
+        }
+        // Compact once mostly empty
+        if self.holes > self.children.len() / 2 {
+            self.children.retain(|(_, c)| c.is_some());
+            self.holes = 0;
+        }
         Some(ino)
     }
+
+    fn reindex(&mut self) {
+        let index = self
+            .children
+            .iter()
+            .filter_map(|(seq, c)| c.as_ref().map(|(_, fname)| (fname.clone(), *seq)))
+            .collect();
+        self.index = Some(index);
+    }
 }
 
 #[derive(Default, Serialize)]
@@ -56,6 +257,10 @@ pub struct FileStats {
 pub struct File {
     storage: Box<dyn Storage>,
     pub stats: FileStats,
+    pub direct_io: bool, // bypass page cache
+    // Damaged blocks by offset with time of damage and original contents
+    damaged: BTreeMap<usize, (Instant, Vec<u8>)>,
+    synced: Option<Box<dyn Storage>>, // contents as of the last fsync, if unsynced data can be lost
 }
 
 impl File {
@@ -63,6 +268,68 @@ impl File {
         File {
             storage,
             stats: FileStats::default(),
+            direct_io: false,
+            damaged: BTreeMap::new(),
+            synced: None,
+        }
+    }
+
+    // Keep track of synced contents, so unsynced data can be dropped on power cuts
+    pub fn track_sync(&mut self) {
+        self.synced = freeze(self.storage());
+    }
+
+    pub fn sync(&mut self) {
+        if self.synced.is_some() {
+            self.track_sync();
+        }
+    }
+
+    // Drop data written since the last sync, return the new size if sync is tracked
+    pub fn power_cut(&mut self) -> Option<usize> {
+        let synced = self.synced.as_deref()?;
+        let data = synced.read(0, synced.len()).into_owned();
+        self.storage.truncate(0);
+        self.storage.write(0, &data);
+        Some(data.len())
+    }
+
+    // Offsets of currently damaged blocks
+    pub fn damaged(&self) -> impl Iterator<Item = usize> {
+        self.damaged.keys().copied()
+    }
+
+    // Corrupt stored data in range, remembering original contents of the affected blocks
+    pub fn damage(&mut self, ino: Ino, offset: usize, len: usize, blksize: usize) {
+        let end = offset.saturating_add(len).min(self.storage.len());
+        let mut start = offset / blksize * blksize;
+        while start < end {
+            let mut block = self.storage.read(start, blksize).into_owned();
+            self.damaged.entry(start).or_insert_with(|| {
+                events::emit(ino, "damage", json!({ "offset": start }));
+                (Instant::now(), block.clone())
+            });
+
+            let from = offset.max(start) - start;
+            let to = end.min(start + block.len()) - start;
+            block[from..to].iter_mut().for_each(|b| *b = !*b);
+            self.storage.write(start, &block);
+            start += blksize;
+        }
+    }
+
+    // Restore original contents of damaged blocks selected by `pred(offset, len, damaged_at)`
+    pub fn repair(&mut self, ino: Ino, pred: impl Fn(usize, usize, Instant) -> bool) {
+        let selected: Vec<usize> = self
+            .damaged
+            .iter()
+            .filter(|(off, (at, data))| pred(**off, data.len(), *at))
+            .map(|(off, _)| *off)
+            .collect();
+        for off in selected {
+            let (_, data) = self.damaged.remove(&off).unwrap();
+            self.storage.write(off, &data);
+            events::emit(ino, "repair", json!({ "offset": off }));
         }
     }
 
@@ -79,6 +346,7 @@ pub enum NodeItem {
     File(File),
     Dir(Dir),
     Symlink(std::path::PathBuf),
+    Proc(ProcFile),
 }
 
 pub struct Node {
@@ -86,4 +354,5 @@ pub struct Node {
     pub attr: FileAttr,
     pub item: NodeItem,
     pub effects: Group,
+    pub xattrs: BTreeMap<String, Vec<u8>>, // user extended attributes, bf. ones are handled by xaops
 }
@@ -0,0 +1,51 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::ftypes::Ino;
+
+#[derive(Serialize)]
+pub struct Handle {
+    pub fh: u64,
+    pub ino: Ino,
+    pub flags: i32,
+    pub pid: u32,
+    pub reads: usize,
+}
+
+// Table of open file handles
+#[derive(Default)]
+pub struct Handles {
+    next: u64,
+    open: BTreeMap<u64, Handle>,
+}
+
+impl Handles {
+    // Register new handle and return its number
+    pub fn open(&mut self, ino: Ino, flags: i32, pid: u32) -> u64 {
+        self.next += 1;
+        let fh = self.next;
+        self.open.insert(fh, Handle { fh, ino, flags, pid, reads: 0 });
+        fh
+    }
+
+    pub fn release(&mut self, fh: u64) -> Option<Handle> {
+        self.open.remove(&fh)
+    }
+
+    // Count read through handle, return whether it's the first one
+    pub fn read(&mut self, fh: u64) -> bool {
+        self.open.get_mut(&fh).is_some_and(|h| {
+            h.reads += 1;
+            h.reads == 1
+        })
+    }
+
+    // Whether `ino` has any open handles
+    pub fn is_open(&self, ino: Ino) -> bool {
+        self.open.values().any(|h| h.ino == ino)
+    }
+
+    pub fn list(&self) -> impl Iterator<Item = &Handle> {
+        self.open.values()
+    }
+}
@@ -0,0 +1,163 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, Sender, channel};
+use std::time::{Duration, Instant};
+
+use crate::ftypes::ErrNo;
+
+// Fuser replies that can carry an error
+pub trait Replier: Send + 'static {
+    fn fail(self, errno: ErrNo);
+}
+
+macro_rules! impl_replier {
+    ($($rt:ty),*) => {
+        $(impl Replier for $rt {
+            fn fail(self, errno: ErrNo) {
+                self.error(errno)
+            }
+        })*
+    };
+}
+
+impl_replier!(
+    fuser::ReplyData,
+    fuser::ReplyEntry,
+    fuser::ReplyAttr,
+    fuser::ReplyWrite,
+    fuser::ReplyEmpty,
+    fuser::ReplyOpen,
+    fuser::ReplyCreate,
+    fuser::ReplyDirectory,
+    fuser::ReplyStatfs,
+    fuser::ReplyXattr
+);
+
+// Command for an in-flight operation
+enum Command {
+    Complete,
+    Fail(ErrNo),
+}
+
+struct InFlight {
+    label: String,
+    since: Instant,
+    deadline: Option<Instant>, // none if hung until commanded
+    control: Sender<Command>,
+}
+
+#[derive(Serialize)]
+struct Listed<'a> {
+    id: u64,
+    label: &'a str,
+    age_ms: u128,
+    remaining_ms: Option<u128>,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    outstanding: usize,
+    limit: usize,
+    overflowed: usize,
+}
+
+static IN_FLIGHT: Mutex<(u64, BTreeMap<u64, InFlight>)> = Mutex::new((0, BTreeMap::new()));
+
+// Maximum number of in-flight operations, each holds a thread
+static LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+// Operations replied right away because the limit was reached
+static OVERFLOWED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_limit(limit: usize) {
+    LIMIT.store(limit, Ordering::Relaxed);
+}
+
+// Reply after `delay` on a separate thread, or once commanded. Without delay, wait for commands only.
+// Once the limit of in-flight operations is reached, reply right away instead. Returns the id of the
+// operation if it's in flight
+pub fn spawn<R: Replier>(
+    delay: Option<Duration>,
+    label: &str,
+    reply: R,
+    replier: impl FnOnce(R) + Send + 'static,
+) -> Option<u64> {
+    let (control, commands) = channel();
+    let id = {
+        let mut guard = IN_FLIGHT.lock().unwrap();
+        let (next, ops) = &mut *guard;
+        if ops.len() >= LIMIT.load(Ordering::Relaxed) {
+            drop(guard);
+            OVERFLOWED.fetch_add(1, Ordering::Relaxed);
+            replier(reply);
+            return None;
+        }
+        *next += 1;
+        ops.insert(
+            *next,
+            InFlight {
+                label: label.to_owned(),
+                since: Instant::now(),
+                deadline: delay.map(|d| Instant::now() + d),
+                control,
+            },
+        );
+        *next
+    };
+
+    std::thread::spawn(move || {
+        let command = match delay {
+            Some(delay) => commands.recv_timeout(delay),
+            None => commands.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        IN_FLIGHT.lock().unwrap().1.remove(&id);
+        match command {
+            Ok(Command::Fail(errno)) => reply.fail(errno),
+            Ok(Command::Complete) | Err(_) => replier(reply),
+        }
+    });
+    Some(id)
+}
+
+// List in-flight operations as json
+pub fn list() -> String {
+    let guard = IN_FLIGHT.lock().unwrap();
+    let listed: Vec<_> = guard
+        .1
+        .iter()
+        .map(|(id, op)| Listed {
+            id: *id,
+            label: &op.label,
+            age_ms: op.since.elapsed().as_millis(),
+            remaining_ms: op
+                .deadline
+                .map(|d| d.saturating_duration_since(Instant::now()).as_millis()),
+        })
+        .collect();
+    serde_json::to_string(&listed).unwrap()
+}
+
+// Number of in-flight operations, their limit and operations replied early due to it as json
+pub fn stats() -> String {
+    let stats = Stats {
+        outstanding: IN_FLIGHT.lock().unwrap().1.len(),
+        limit: LIMIT.load(Ordering::Relaxed),
+        overflowed: OVERFLOWED.load(Ordering::Relaxed),
+    };
+    serde_json::to_string(&stats).unwrap()
+}
+
+// Force-complete operation or force-fail it with `errno`. Returns false if it's not in flight
+pub fn finish(id: u64, errno: Option<ErrNo>) -> bool {
+    let guard = IN_FLIGHT.lock().unwrap();
+    let Some(op) = guard.1.get(&id) else {
+        return false;
+    };
+    let command = match errno {
+        Some(errno) => Command::Fail(errno),
+        None => Command::Complete,
+    };
+    op.control.send(command).is_ok()
+}
@@ -4,35 +4,87 @@ use fuser::{
     Request, TimeOrNow,
 };
 use libc::ENOENT;
-use rand::SeedableRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
-use std::time::{Duration, SystemTime};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
 
 mod effect;
+mod events;
 mod ftree;
 mod ftypes;
+mod handles;
+mod inflight;
+mod metrics;
+mod procfs;
+mod snapshot;
 mod storage;
+mod trace;
 mod util;
 mod xaops;
 
 use effect::OpType;
 use ftree::Tree;
-use ftypes::{Dir, ErrNo, File, Ino, Node, NodeItem};
-use util::ImmutCounter;
+use ftypes::{Dir, DirOrder, ErrNo, File, Ino, Node, NodeItem, ino_of};
+use util::{AttrOps, ImmutCounter};
 
 const TTL: Duration = Duration::from_secs(1);
 
+// Interval between sweeps of the tree for rot effects and expired effects
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+// Maximum number of entries prepared for a single readdir call
+const READDIR_PAGE: usize = 1024;
+
+// Write persisted later, letting other operations observe intermediate states
+struct PendingWrite {
+    due: Instant,
+    ino: Ino,
+    offset: usize,
+    data: Vec<u8>,
+}
+
+// Silent corruption of stored data in the background, applied lazily for the time passed
+struct BitRot {
+    rate: f64,          // bit flips per second
+    paths: Vec<String>, // subtrees affected, all if empty
+    last: Instant,
+}
+
 struct TestFS {
     tree: ftree::Tree,
     sfactory: Box<dyn storage::Factory>,
     rgen: rand::rngs::StdRng,
+    budget: Option<effect::ErrorBudget>,
+    handles: handles::Handles,
+    config: String, // command line options as json
+    pending: Vec<PendingWrite>,
+    direct_io: bool, // bypass page cache for all files
+    rss_guard: Option<effect::RssGuard>,
+    aliases: Vec<(Ino, String)>, // entries (parent, name) handed out as node id aliases
+    blksize: u32,
+    busy_open: bool, // fail unlink and rename of open files with EBUSY
+    dir_order: DirOrder, // listing order of directories without their own
+    listings: BTreeMap<Ino, u64>, // seed of the current listing by directory
+    negative_ttl: Option<Duration>, // ttl of negative entries for missing names
+    bitrot: Option<BitRot>,
+    last_sweep: Instant, // last sweep for rot effects and expired effects
+    power_cuts: bool,   // keep track of synced data, so unsynced data can be dropped
+    snapshots: BTreeMap<String, snapshot::Snapshot>,
+    state_file: Option<String>, // where effects are saved on unmount
+    unresolved: serde_json::Map<String, serde_json::Value>, // saved effects at paths missing from the tree
 }
 
 enum NodeCreateT<'a> {
     Dir,
     File,
     Symlink(&'a std::path::Path),
+    Generated(storage::GenStorage),
 }
 struct NodeCreateReq<'a> {
     ntype: NodeCreateT<'a>,
@@ -40,7 +92,15 @@ struct NodeCreateReq<'a> {
 }
 
 // Create fresh attributes
-fn fresh_attr(ino: Ino, kind: FileType, flags: u32, mode: u32, uid: u32, gid: u32) -> FileAttr {
+fn fresh_attr(
+    ino: Ino,
+    kind: FileType,
+    flags: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    blksize: u32,
+) -> FileAttr {
     let now = SystemTime::now();
     FileAttr {
         ino: ino as u64,
@@ -56,7 +116,7 @@ fn fresh_attr(ino: Ino, kind: FileType, flags: u32, mode: u32, uid: u32, gid: u3
         uid: uid,
         gid: gid,
         rdev: 0,
-        blksize: 4096,
+        blksize,
         flags,
     }
 }
@@ -110,61 +170,453 @@ impl TestFS {
         let (kind, item) = match ntype {
             NodeCreateT::Dir => (FileType::Directory, NodeItem::Dir(Dir::default())),
             NodeCreateT::File => {
-                let storage = self.sfactory.create(ino);
-                (FileType::RegularFile, NodeItem::File(File::create(storage)))
+                let mut file = File::create(self.sfactory.create(ino));
+                if self.power_cuts {
+                    file.track_sync();
+                }
+                (FileType::RegularFile, NodeItem::File(file))
             }
             NodeCreateT::Symlink(path) => (FileType::Symlink, NodeItem::Symlink(path.to_owned())),
+            NodeCreateT::Generated(storage) => (
+                FileType::RegularFile,
+                NodeItem::File(File::create(Box::new(storage))),
+            ),
         };
 
-        let attr = fresh_attr(ino, kind, flags, mode, req.uid(), req.gid());
+        let mut attr = fresh_attr(ino, kind, flags, mode, req.uid(), req.gid(), self.blksize);
+        if let NodeItem::File(ref file) = item {
+            attr.resize(file.storage().len() as u64);
+        }
         let node = Node {
             parent,
             attr,
             item,
             effects: effect::Group::default(),
+            xattrs: BTreeMap::new(),
         };
         nref.replace(node);
         Ok(attr)
     }
 
     fn unlink(&mut self, parent: Ino, name: &OsStr) -> Result<(), ErrNo> {
+        self.check_in_use(parent, name)?;
         self.tree.unlink(parent, &name.to_string_lossy())
     }
 
-    fn run_effects(&mut self, op: effect::OpDesr, ino: Ino) -> (u64, Option<i32>) {
-        let ctx = effect::Context {
+    // Open files can't be unlinked or renamed with windows-like semantics
+    fn check_in_use(&mut self, parent: Ino, name: &OsStr) -> Result<(), ErrNo> {
+        if !self.busy_open {
+            return Ok(());
+        }
+        let ino = self.access_dir(parent)?.0.lookup(name);
+        match ino {
+            Some(ino) if self.handles.is_open(ino) => Err(libc::EBUSY),
+            _ => Ok(()),
+        }
+    }
+
+    // Store data in file, updating its attributes
+    fn persist(&mut self, ino: Ino, offset: usize, data: &[u8]) -> Result<(), ErrNo> {
+        let node = self.access_node_mut(ino)?;
+        match node.item {
+            NodeItem::File(ref file) if file.storage().readonly() => Err(libc::EROFS),
+            NodeItem::Proc(_) => Err(libc::EROFS),
+            NodeItem::File(ref mut file) => {
+                // Rewriting damaged blocks repairs them
+                let end = offset + data.len();
+                file.repair(ino, |off, len, _| off < end && off + len > offset);
+                file.storage_mut().write(offset, data);
+                node.attr.resize(file.storage().len() as u64);
+                Ok(())
+            }
+            _ => Err(ENOENT),
+        }
+    }
+
+    // Read back data written at `offset` and compare it with `data`
+    fn verify_write(&mut self, ino: Ino, offset: usize, data: &[u8], verify: effect::Verify) -> Result<(), ErrNo> {
+        let NodeItem::File(ref file) = self.access_node(ino)?.item else {
+            return Ok(());
+        };
+        verify.stats.checked.incr();
+        if file.storage().read(offset, data.len()).as_ref() == data {
+            return Ok(());
+        }
+        verify.stats.mismatches.incr();
+        events::emit(ino, "mismatch", serde_json::json!({"offset": offset, "len": data.len()}));
+        verify.errno.map_or(Ok(()), Err)
+    }
+
+    // Copy range between files, sharing storage extents copy-on-write where possible
+    fn copy_range(
+        &mut self,
+        (ino_in, offset_in): (Ino, usize),
+        (ino_out, offset_out): (Ino, usize),
+        len: usize,
+        mode: Option<effect::CloneMode>,
+    ) -> Result<usize, ErrNo> {
+        let NodeItem::File(ref file) = self.access_node(ino_in)?.item else {
+            return Err(libc::EINVAL);
+        };
+        let len = len.min(file.storage().len().saturating_sub(offset_in));
+        let extents = file
+            .storage()
+            .extents(offset_in, len)
+            .filter(|_| mode != Some(effect::CloneMode::Copy));
+
+        if let Some(extents) = extents {
+            let node = self.access_node_mut(ino_out)?;
+            let shared = mode == Some(effect::CloneMode::Shared);
+            if let NodeItem::File(ref mut file) = node.item
+                && file.storage_mut().reflink(offset_out, extents, shared)
+            {
+                let end = offset_out + len;
+                file.repair(ino_out, |off, dlen, _| off < end && off + dlen > offset_out);
+                node.attr.resize(file.storage().len() as u64);
+                return Ok(len);
+            }
+        }
+
+        // Fall back to a plain copy
+        let data = match self.access_node(ino_in)?.item {
+            NodeItem::File(ref file) => file.storage().read(offset_in, len).into_owned(),
+            _ => return Err(libc::EINVAL),
+        };
+        self.persist(ino_out, offset_out, &data).map(|_| len)
+    }
+
+    // Persist pending writes that are due
+    fn flush_pending(&mut self) {
+        let now = Instant::now();
+        self.pending.sort_by_key(|pw| pw.due);
+        let due = self.pending.partition_point(|pw| pw.due <= now);
+        for pw in self.pending.drain(..due).collect::<Vec<_>>() {
+            self.persist(pw.ino, pw.offset, &pw.data).ok();
+        }
+    }
+
+    // Drop data written since the last fsync to files below `root`, including pending writes
+    fn power_cut(&mut self, root: Ino) {
+        let files: Vec<Ino> = self.tree.traverse(root).map(|n| n.attr.ino as Ino).collect();
+        self.pending.retain(|pw| !files.contains(&pw.ino));
+        for ino in files {
+            if let Some(Node {
+                item: NodeItem::File(file),
+                attr,
+                ..
+            }) = self.tree.get_mut(ino)
+                && let Some(size) = file.power_cut()
+            {
+                attr.resize(size as u64);
+                events::emit(ino, "powercut", serde_json::json!({"size": size}));
+            }
+        }
+    }
+
+    // Run consistency checks of the tree, storage and effects, to tell own bugs from injected faults
+    fn doctor(&self) -> String {
+        let mut problems = self.tree.check();
+        for node in self.tree.traverse(1) {
+            let ino = node.attr.ino as Ino;
+            if let NodeItem::File(ref file) = node.item {
+                if !file.storage().reachable() {
+                    problems.push((ino, "storage is unreachable".to_owned()));
+                } else if file.storage().len() as u64 != node.attr.size {
+                    problems.push((ino, format!("size {} with {} bytes stored", node.attr.size, file.storage().len())));
+                }
+            }
+        }
+        problems.extend(effect::check(&self.tree, 1));
+
+        let problems: Vec<_> = problems
+            .into_iter()
+            .map(|(ino, problem)| serde_json::json!({"ino": ino, "path": self.tree.path(ino), "problem": problem}))
+            .collect();
+        let stale = |ino: Ino| self.tree.get(ino).is_none();
+        let report = serde_json::json!({
+            "ok": problems.is_empty(),
+            "problems": problems,
+            "nodes": self.tree.count(),
+            "handles": self.handles.list().count(),
+            "stale_handles": self.handles.list().filter(|h| stale(h.ino)).count(),
+            "pending_writes": self.pending.len(),
+            "stale_pending_writes": self.pending.iter().filter(|pw| stale(pw.ino)).count(),
+            "inflight": serde_json::from_str::<serde_json::Value>(&inflight::stats()).unwrap(),
+        });
+        report.to_string()
+    }
+
+    // Flip random bits of stored data for the time passed since the last rot
+    fn rot(&mut self) {
+        if let Some(ref mut bitrot) = self.bitrot {
+            let due = (bitrot.last.elapsed().as_secs_f64() * bitrot.rate) as u64;
+            if due > 0 {
+                bitrot.last += Duration::from_secs_f64(due as f64 / bitrot.rate);
+                let paths = bitrot.paths.clone();
+                let within = |path: &str| {
+                    paths.is_empty()
+                        || paths.iter().any(|p| {
+                            let p = p.trim_end_matches('/');
+                            path == p || path.starts_with(&format!("{}/", p))
+                        })
+                };
+                let files: Vec<_> = self
+                    .rottable(1)
+                    .into_iter()
+                    .filter(|(ino, _)| self.tree.path(*ino).is_some_and(|p| within(&p)))
+                    .collect();
+                self.flip_bits(&files, due);
+            }
+        }
+    }
+
+    // Rot effects are swept periodically, since they don't depend on io to their subtrees.
+    // Expired effects are pruned along the way
+    fn sweep(&mut self) {
+        if self.last_sweep.elapsed() < SWEEP_INTERVAL {
+            return;
+        }
+        self.last_sweep = Instant::now();
+        for (origin, due) in effect::rot_due(&self.tree) {
+            let files = self.rottable(origin);
+            self.flip_bits(&files, due);
+        }
+        effect::prune(&mut self.tree);
+    }
+
+    // Writable non empty files below `root` with their sizes
+    fn rottable(&self, root: Ino) -> Vec<(Ino, usize)> {
+        self.tree
+            .traverse(root)
+            .filter_map(|node| match node.item {
+                NodeItem::File(ref file) if !file.storage().readonly() => {
+                    Some((node.attr.ino as Ino, file.storage().len()))
+                }
+                _ => None,
+            })
+            .filter(|(_, len)| *len > 0)
+            .collect()
+    }
+
+    // Flip `due` random bits of `files`, picking bytes uniformly across all of them
+    fn flip_bits(&mut self, files: &[(Ino, usize)], due: u64) {
+        let total: usize = files.iter().map(|(_, len)| len).sum();
+        if total == 0 {
+            return;
+        }
+
+        for _ in 0..due {
+            let mut pos = self.rgen.random_range(0..total);
+            let Some(&(ino, _)) = files.iter().find(|(_, len)| {
+                let found = pos < *len;
+                if !found {
+                    pos -= len;
+                }
+                found
+            }) else {
+                continue;
+            };
+            let bit = self.rgen.random_range(0..8u8);
+            if let Some(Node {
+                item: NodeItem::File(file),
+                ..
+            }) = self.tree.get_mut(ino)
+            {
+                let mut byte = file.storage().read(pos, 1).into_owned();
+                if let Some(b) = byte.first_mut() {
+                    *b ^= 1 << bit;
+                    file.storage_mut().write(pos, &byte);
+                    events::emit(ino, "rot", serde_json::json!({"offset": pos, "bit": bit}));
+                }
+            }
+        }
+    }
+
+    // Diff report between snapshots "<from>..<to>", or snapshot "<from>" and the current state
+    fn snapshot_diff(&self, spec: &str) -> Option<String> {
+        let (from, to) = match spec.split_once("..") {
+            Some((from, to)) => (from, Some(to)),
+            None => (spec, None),
+        };
+        let from = self.snapshots.get(from)?;
+        let current;
+        let to = match to {
+            Some(to) => self.snapshots.get(to)?,
+            None => {
+                current = snapshot::Snapshot::take(&self.tree, from.root());
+                &current
+            }
+        };
+        Some(snapshot::diff(from, to).to_string())
+    }
+
+    // Flags for replies opening `ino`
+    fn open_flags(&self, ino: Ino) -> u32 {
+        let direct_io = match self.tree.get(ino).map(|n| &n.item) {
+            // Introspection files have no stable size, bypass the page cache for them
+            Some(NodeItem::Proc(_)) => true,
+            Some(NodeItem::File(file)) => self.direct_io || file.direct_io,
+            _ => false,
+        };
+        if direct_io { fuser::consts::FOPEN_DIRECT_IO } else { 0 }
+    }
+
+    fn run_effects(&mut self, req: &Request, op: effect::OpDesr, nodeid: u64) -> effect::Outcome {
+        self.flush_pending();
+        self.rot();
+        self.sweep();
+        let ino = ino_of(nodeid);
+        let entry = (nodeid >> ftypes::ALIAS_SHIFT)
+            .checked_sub(1)
+            .and_then(|alias| self.aliases.get(alias as usize))
+            .cloned();
+        let mut ctx = effect::Context {
             op: op,
             origin: 0,
             target: ino,
+            entry,
+            caller: effect::Caller {
+                pid: req.pid(),
+                uid: req.uid(),
+                gid: req.gid(),
+            },
             tree: &self.tree,
             rgen: &mut self.rgen,
+            start: Instant::now(),
+            delayed_ms: 0,
+            actions: vec![],
+            changes: effect::Changes::default(),
+            trace: trace::enabled().then(Vec::new),
         };
-        effect::run(self.tree.climb(ino as Ino), ctx)
+        let (sleep_ms, errno) = effect::run(self.tree.climb(ino), &mut ctx);
+        let opname = ctx.op.name();
+        let label = format!("{} {}", opname, self.tree.path(ino).unwrap_or_default());
+        let changes = ctx.changes;
+        let steps = ctx.trace.take();
+        for action in ctx.actions {
+            self.apply_action(action);
+        }
+        self.tree.touch(ino);
+
+        let errno = errno.or_else(|| self.rss_guard.as_mut().and_then(|g| g.check()));
+        // Let errors pass through once the budget is exhausted
+        let errno = errno.filter(|_| self.budget.as_mut().is_none_or(|b| b.spend()));
+        metrics::record(opname, errno.is_some(), sleep_ms);
+        if let Some(steps) = steps {
+            let steps = steps
+                .into_iter()
+                .map(|(effect, origin, verdict)| trace::Step {
+                    effect,
+                    origin: self.tree.path(origin).unwrap_or_default(),
+                    verdict,
+                })
+                .collect();
+            trace::record(label.clone(), steps, errno, sleep_ms);
+        }
+        effect::Outcome {
+            sleep_ms,
+            errno,
+            changes,
+            label,
+            op: opname,
+            start: ctx.start,
+        }
+    }
+
+    fn apply_action(&mut self, action: effect::Action) {
+        match action {
+            effect::Action::Repair { ino, older_than } => {
+                if let Some(Node {
+                    item: NodeItem::File(file),
+                    ..
+                }) = self.tree.get_mut(ino)
+                {
+                    file.repair(ino, |_, _, at| at.elapsed() >= older_than);
+                }
+            }
+        }
     }
 }
 
 impl Filesystem for TestFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        match self
+    // Save effects by path, keeping the ones that couldn't be installed at mount
+    fn destroy(&mut self) {
+        let Some(ref path) = self.state_file else {
+            return;
+        };
+        let mut state = std::mem::take(&mut self.unresolved);
+        state.extend(effect::export(&self.tree));
+        if let Err(err) = std::fs::write(path, serde_json::Value::Object(state).to_string()) {
+            eprintln!("Failed to save state to {}: {}", path, err);
+        }
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let descr = effect::OpDesr::Lookup { name: name.to_string_lossy().to_string() };
+        let outcome = self.run_effects(req, descr, parent);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
+        // Negative entries with node id zero are cached by the kernel for their ttl
+        let negative = |ttl: Duration| move |reply: ReplyEntry| {
+            let attr = fresh_attr(0, FileType::RegularFile, 0, 0, 0, 0, 0);
+            reply.entry(&ttl, &attr, 0)
+        };
+        if let Some(ttl) = outcome.changes.negative_ttl {
+            outcome.reply(reply, negative(ttl));
+            return;
+        }
+
+        let mut attr = match self
             .access_dir(parent as Ino)
             .and_then(|(d, _)| d.lookup(name).ok_or(ENOENT))
             .and_then(|ino| self.access_node(ino))
         {
-            Ok(node) => reply.entry(&TTL, &node.attr, 0),
-            Err(errno) => reply.error(errno),
+            Ok(node) => node.attr,
+            Err(errno) => {
+                match self.negative_ttl.filter(|_| errno == ENOENT) {
+                    Some(ttl) => outcome.reply(reply, negative(ttl)),
+                    None => outcome.reply(reply, move |reply| reply.error(errno)),
+                }
+                return;
+            }
+        };
+
+        // Hard linked nodes with diverging entries are handed out under a distinct node id per entry
+        let ino = attr.ino as Ino;
+        if attr.nlink > 1 && effect::resolve(self.tree.climb(ino)).any(|(_, de)| de.eftype() == "diverge") {
+            let entry = (parent as Ino, name.to_string_lossy().to_string());
+            let alias = match self.aliases.iter().position(|e| *e == entry) {
+                Some(pos) => pos,
+                None => {
+                    self.aliases.push(entry);
+                    self.aliases.len() - 1
+                }
+            };
+            attr.ino |= ((alias + 1) as u64) << ftypes::ALIAS_SHIFT;
         }
+        outcome.reply(reply, move |reply| reply.entry(&TTL, &attr, 0));
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        match self.access_node(ino as Ino) {
-            Ok(node) => reply.attr(&TTL, &node.attr),
-            Err(errno) => reply.error(errno),
+    fn getattr(&mut self, req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        let outcome = self.run_effects(req, effect::OpDesr::Getattr, ino);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
+        match self.access_node(ino_of(ino)) {
+            Ok(node) => {
+                let attr = node.attr;
+                outcome.reply(reply, move |reply| reply.attr(&TTL, &attr))
+            }
+            Err(errno) => outcome.reply(reply, move |reply| reply.error(errno)),
         }
     }
 
     fn setattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         mode: Option<u32>,
         _uid: Option<u32>,
@@ -180,24 +632,36 @@ impl Filesystem for TestFS {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        let node = match self.access_node_mut(ino as Ino) {
+        let outcome = self.run_effects(req, effect::OpDesr::Setattr { size }, ino);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
+        let node = match self.access_node_mut(ino_of(ino)) {
             Ok(node) => node,
-            Err(errno) => return reply.error(errno),
+            Err(errno) => return outcome.reply(reply, move |reply| reply.error(errno)),
         };
 
+        // Only writable files can be resized
+        let resizable = match node.item {
+            _ if size.is_none() => Ok(()),
+            NodeItem::File(ref file) if file.storage().readonly() => Err(libc::EROFS),
+            NodeItem::File(_) => Ok(()),
+            NodeItem::Proc(_) => Err(libc::EROFS),
+            NodeItem::Dir(_) => Err(libc::EISDIR),
+            NodeItem::Symlink(_) => Err(libc::EINVAL),
+        };
+        if let Err(errno) = resizable {
+            return outcome.reply(reply, move |reply| reply.error(errno));
+        }
+
         if let Some(mode) = mode {
             node.attr.perm = mode as u16;
         }
 
-        if let Some(size) = size {
-            match node.item {
-                NodeItem::File(ref mut f) => {
-                    f.storage_mut().truncate(size as usize);
-                    node.attr.size = size;
-                    node.attr.blocks = size / node.attr.blksize as u64;
-                }
-                _ => panic!(""),
-            }
+        if let (Some(size), NodeItem::File(f)) = (size, &mut node.item) {
+            f.storage_mut().truncate(size as usize);
+            node.attr.resize(size);
         }
 
         let tontot = |ton: TimeOrNow| match ton {
@@ -213,43 +677,92 @@ impl Filesystem for TestFS {
             node.attr.mtime = tontot(mtime);
         }
 
-        reply.attr(&TTL, &node.attr);
+        let attr = node.attr;
+        outcome.reply(reply, move |reply| reply.attr(&TTL, &attr));
     }
 
     fn readdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         _fh: u64,
         offset: i64,
-        mut reply: ReplyDirectory,
+        reply: ReplyDirectory,
     ) {
-        let (raw_entries, parent): (Vec<(Ino, String)>, Ino) = match self.access_dir(ino as Ino) {
-            Ok((dir, parent)) => (dir.list().map(|(i, n)| (i, n.to_owned())).collect(), parent),
-            Err(errno) => return reply.error(errno),
+        let outcome = self.run_effects(req, effect::OpDesr::Readdir { offset }, ino);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
+        // Listings start at offset zero, keep the seed for their continuations
+        if offset == 0 {
+            self.listings.insert(ino_of(ino), self.rgen.random());
+        }
+        let seed = self.listings.get(&ino_of(ino)).copied().unwrap_or_default();
+        let dir_order = self.dir_order;
+        let order_of = |dir: &Dir| outcome.changes.dir_order.or(dir.order).unwrap_or(dir_order);
+        // Offsets of entries follow the two base entries
+        let skip = (offset as usize).saturating_sub(2);
+        let (mut raw_entries, parent): (Vec<(usize, Ino, String)>, Ino) = match self.access_dir(ino_of(ino)) {
+            // Listings in insertion order are served page by page with positions as offsets,
+            // so huge directories aren't copied on every call
+            Ok((dir, parent)) if matches!(order_of(dir), DirOrder::Insertion) => (
+                dir.page(skip)
+                    .take(READDIR_PAGE)
+                    .map(|(pos, i, n)| (pos + 3, i, n.to_owned()))
+                    .collect(),
+                parent,
+            ),
+            Ok((dir, parent)) => {
+                let mut entries: Vec<_> = dir.list().map(|(i, n)| (i, n.to_owned())).collect();
+                order_of(dir).arrange(&mut entries, seed);
+                let entries = (3..)
+                    .zip(entries)
+                    .skip(skip)
+                    .take(READDIR_PAGE)
+                    .map(|(off, (i, n))| (off, i, n))
+                    .collect();
+                (entries, parent)
+            }
+            Err(errno) => {
+                outcome.reply(reply, move |reply| reply.error(errno));
+                return;
+            }
         };
+        // Omitted entries keep their offsets, so continuations of the listing stay consistent
+        if let Some(prob) = outcome.changes.omit {
+            raw_entries.retain(|_| self.rgen.random::<f32>() >= prob);
+        }
+        if let Some(prob) = outcome.changes.dup {
+            let rgen = &mut self.rgen;
+            raw_entries = raw_entries
+                .into_iter()
+                .flat_map(|entry| {
+                    let times = if rgen.random::<f32>() < prob { 2 } else { 1 };
+                    std::iter::repeat_n(entry, times)
+                })
+                .collect();
+        }
         let base_entries = [
-            (ino as usize, FileType::Directory, "."),
-            (parent, FileType::Directory, ".."),
+            (1, ino as usize, FileType::Directory, ".".to_owned()),
+            (2, parent, FileType::Directory, "..".to_owned()),
         ];
-        let dir_entries = raw_entries.iter().map(|(fino, fname)| {
-            (
-                *fino,
-                self.access_node(*fino).unwrap().attr.kind,
-                fname.as_str(),
-            )
-        });
-        for (i, e) in base_entries
+        let dir_entries = raw_entries
             .into_iter()
+            .map(|(off, fino, fname)| (off, fino, self.access_node(fino).unwrap().attr.kind, fname));
+        let entries: Vec<_> = base_entries
+            .into_iter()
+            .filter(|e| e.0 > offset as usize)
             .chain(dir_entries)
-            .enumerate()
-            .skip(offset as usize)
-        {
-            if reply.add(e.0 as u64, (i + 1) as i64, e.1, e.2) {
-                break;
+            .collect();
+        outcome.reply(reply, move |mut reply| {
+            for (off, fino, kind, name) in entries {
+                if reply.add(fino as u64, off as i64, kind, name) {
+                    break;
+                }
             }
-        }
-        reply.ok();
+            reply.ok()
+        });
     }
 
     fn mkdir(
@@ -261,13 +774,19 @@ impl Filesystem for TestFS {
         _umask: u32,
         reply: ReplyEntry,
     ) {
+        let op = effect::OpDesr::Mkdir { name: name.to_string_lossy().into() };
+        let outcome = self.run_effects(req, op, parent);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
         let req = NodeCreateReq {
             req,
             ntype: NodeCreateT::Dir,
         };
         match self.create_node(req, parent as Ino, name, mode, 0) {
-            Ok(attr) => reply.entry(&TTL, &attr, 0),
-            Err(errno) => reply.error(errno),
+            Ok(attr) => outcome.reply(reply, move |reply| reply.entry(&TTL, &attr, 0)),
+            Err(errno) => outcome.reply(reply, move |reply| reply.error(errno)),
         }
     }
 
@@ -281,6 +800,12 @@ impl Filesystem for TestFS {
         flags: i32,
         reply: fuser::ReplyCreate,
     ) {
+        let op = effect::OpDesr::Create { name: name.to_string_lossy().into() };
+        let outcome = self.run_effects(req, op, parent);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
         match self.create_node(
             NodeCreateReq {
                 ntype: NodeCreateT::File,
@@ -291,84 +816,191 @@ impl Filesystem for TestFS {
             mode,
             flags as u32,
         ) {
-            Ok(attr) => reply.created(&TTL, &attr, 0, attr.ino, 0),
-            Err(errno) => reply.error(errno),
+            Ok(attr) => {
+                let fh = self.handles.open(attr.ino as Ino, flags, req.pid());
+                let open_flags = self.open_flags(attr.ino as Ino);
+                outcome.reply(reply, move |reply| reply.created(&TTL, &attr, 0, fh, open_flags))
+            }
+            Err(errno) => outcome.reply(reply, move |reply| reply.error(errno)),
         }
     }
 
     fn write(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         _fh: u64,
         offset: i64,
         data: &[u8],
-        _write_flags: u32,
+        write_flags: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: fuser::ReplyWrite,
     ) {
-        let descr = effect::OpDesr::Write { offset: offset as usize, len: data.len() };
-        let (ef_sleep, ef_err) = self.run_effects(descr, ino as Ino);
-        if let Some(errno) = ef_err {
-            effect::reply(ef_sleep, move || reply.error(errno));
+        let descr = effect::OpDesr::Write {
+            offset: offset as usize,
+            len: data.len(),
+            cached: write_flags & fuser::consts::FUSE_WRITE_CACHE != 0,
+        };
+        let mut outcome = self.run_effects(req, descr, ino);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
             return;
         }
+        let data = &data[..outcome.changes.max_len.unwrap_or(data.len()).min(data.len())];
+        let offset = offset as usize;
 
-        let node = match self.access_node_mut(ino as Ino) {
-            Ok(node) => node,
-            Err(errno) => return reply.error(errno),
+        // Data as persisted, effects may corrupt it on the way
+        let stored = match outcome.changes.corruption {
+            Some(corruption) => {
+                let mut corrupted = data.to_vec();
+                corruption.apply(&mut corrupted, &mut self.rgen);
+                Cow::Owned(corrupted)
+            }
+            None => Cow::Borrowed(data),
+        };
+        let stored_offset = match outcome.changes.misdirect {
+            Some(by) => offset.saturating_add_signed(by as isize),
+            None => offset,
         };
 
-        let written = if let NodeItem::File(ref mut file) = node.item {
-            file.storage_mut().write(offset as usize, data);
-            node.attr.size = file.storage().len() as u64;
-            node.attr.blocks = (node.attr.size / (node.attr.blksize as u64)) + 1;
+        // Torn writes persist only their leading part
+        let torn = outcome.changes.torn;
+        let stored = match (stored, torn) {
+            (Cow::Borrowed(s), Some(kept)) => Cow::Borrowed(&s[..kept.min(s.len())]),
+            (Cow::Owned(mut s), Some(kept)) => {
+                s.truncate(kept);
+                Cow::Owned(s)
+            }
+            (stored, None) => stored,
+        };
 
-            file.stats.writes.incr();
-            file.stats.write_volume.add(data.len());
-            Some(data.len())
+        let lost = outcome.changes.lost;
+        // Duplicates are persisted after the write itself, like a replay
+        let duplicate = outcome.changes.duplicate.filter(|_| !lost).map(|at| (at, stored.to_vec()));
+        let written = if let Some(window) = outcome.changes.coalesce.filter(|_| !lost) {
+            // Join the pending batch of the file, persisted all at once later
+            let due = self
+                .pending
+                .iter()
+                .find(|pw| pw.ino == ino_of(ino))
+                .map_or(Instant::now() + window, |pw| pw.due);
+            self.pending.push(PendingWrite {
+                due,
+                ino: ino_of(ino),
+                offset: stored_offset,
+                data: stored.into_owned(),
+            });
+            Ok(())
         } else {
-            None
+            // Persist only the first chunk now and the rest with intervals in between
+            let (now_data, later) = match outcome.changes.chunking {
+                Some(chunking) => stored.split_at(chunking.size.min(stored.len())),
+                None => stored.split_at(stored.len()),
+            };
+            // Lost writes are acknowledged without being persisted
+            let written = match lost {
+                true => Ok(()),
+                false => self.persist(ino_of(ino), stored_offset, now_data),
+            };
+            if let (Ok(_), Some(chunking), false) = (&written, outcome.changes.chunking, lost) {
+                let mut chunk_offset = stored_offset + now_data.len();
+                for part in later.chunks(chunking.size.max(1)) {
+                    outcome.sleep_ms += chunking.interval_ms;
+                    self.pending.push(PendingWrite {
+                        due: Instant::now() + Duration::from_millis(outcome.sleep_ms),
+                        ino: ino_of(ino),
+                        offset: chunk_offset,
+                        data: part.to_vec(),
+                    });
+                    chunk_offset += part.len();
+                }
+            }
+            // Verification compares with the data the application wrote, all of it if torn
+            let expected = torn.map_or(now_data.len(), |_| data.len());
+            match (written, outcome.changes.verify.take()) {
+                (Ok(()), Some(verify)) => {
+                    self.verify_write(ino_of(ino), offset, &data[..expected], verify)
+                }
+                (written, _) => written,
+            }
         };
 
-        effect::reply(ef_sleep, move || {
-            if let Some(written) = written {
-                reply.written(written as u32);
-            } else {
-                reply.error(ENOENT)
-            }
+        if let (Ok(()), Some((at, dup))) = (&written, duplicate) {
+            // The replay's outcome is never seen by the application
+            let _ = self.persist(ino_of(ino), at, &dup);
+        }
+
+        // Shadow checksums are of the data the application wrote
+        if let (Some(scrub), Some(Node { item: NodeItem::File(file), .. })) =
+            (outcome.changes.scrub.take(), self.tree.get(ino_of(ino)))
+        {
+            scrub.record(ino_of(ino), offset, data, file.storage());
+        }
+
+        if let Ok(Node {
+            item: NodeItem::File(file),
+            ..
+        }) = self.access_node(ino_of(ino))
+        {
+            file.stats.writes.incr();
+            file.stats.write_volume.add(data.len());
+        }
+
+        let written = written.map(|_| data.len());
+        outcome.reply(reply, move |reply| match written {
+            Ok(written) => reply.written(written as u32),
+            Err(errno) => reply.error(errno),
         });
     }
 
     fn read(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        let descr = effect::OpDesr::Read{offset: offset as usize, len: size as usize};
-        let (ef_sleep, ef_errno) = self.run_effects(descr, ino as Ino);
-        if let Some(errno) = ef_errno {
-            effect::reply(ef_sleep, move || reply.error(errno));
+        let first = self.handles.read(fh);
+        let descr = effect::OpDesr::Read{offset: offset as usize, len: size as usize, first};
+        let mut outcome = self.run_effects(req, descr, ino);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
+        let size = outcome.changes.max_len.map_or(size, |m| size.min(m as u32));
+
+        if let Some(NodeItem::Proc(pfile)) = self.tree.get(ino_of(ino)).map(|n| &n.item) {
+            let text = procfs::render(*pfile, &self.tree, &self.handles, &self.config);
+            let start = (offset as usize).min(text.len());
+            let end = (start + size as usize).min(text.len());
+            let data = text.as_bytes()[start..end].to_vec();
+            outcome.reply(reply, move |reply| reply.data(&data));
             return;
         }
 
-        let node = match self.access_node(ino as Ino) {
+        let node = match self.access_node(ino_of(ino)) {
             Ok(node) => node,
             Err(errno) => return reply.error(errno),
         };
 
+        let read_data = outcome.changes.read_data.take();
         let data = if let NodeItem::File(ref file) = node.item {
-            let data = file
-                .storage()
-                .read(offset as usize, size as usize)
-                .into_owned();
+            let mut data = read_data.unwrap_or_else(|| {
+                file.storage()
+                    .read(offset as usize, size as usize)
+                    .into_owned()
+            });
+            data.truncate(size as usize);
+            if let Some(scrub) = outcome.changes.scrub.take() {
+                for start in scrub.verify(ino_of(ino), offset as usize, &data, file.storage().len()) {
+                    events::emit(ino_of(ino), "scrub", serde_json::json!({"offset": start}));
+                }
+            }
             file.stats.reads.incr();
             file.stats.read_volume.add(data.len());
             Some(data)
@@ -376,7 +1008,7 @@ impl Filesystem for TestFS {
             None
         };
 
-        effect::reply(ef_sleep, move || {
+        outcome.reply(reply, move |reply| {
             if let Some(data) = data {
                 reply.data(&data)
             } else {
@@ -387,7 +1019,7 @@ impl Filesystem for TestFS {
 
     fn rename(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         newparent: u64,
@@ -395,15 +1027,53 @@ impl Filesystem for TestFS {
         _flags: u32,
         reply: fuser::ReplyEmpty,
     ) {
+        let op = effect::OpDesr::Rename {
+            name: name.to_string_lossy().into(),
+            newparent: newparent as Ino,
+            newname: newname.to_string_lossy().into(),
+        };
+        let outcome = self.run_effects(req, op, parent);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
+        let in_use = self
+            .check_in_use(parent as Ino, name)
+            .and_then(|_| self.check_in_use(newparent as Ino, newname));
+        if let Err(errno) = in_use {
+            return outcome.reply(reply, move |reply| reply.error(errno));
+        }
         match self.tree.rename(
             parent as Ino,
             name.to_string_lossy().as_ref(),
             newparent as Ino,
             newname.to_string_lossy().as_ref(),
         ) {
-            Ok(_) => reply.ok(),
-            Err(errno) => reply.error(errno),
+            Ok(_) => outcome.reply(reply, move |reply| reply.ok()),
+            Err(errno) => outcome.reply(reply, move |reply| reply.error(errno)),
+        }
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        if let Err(errno) = self.access_node(ino_of(ino)) {
+            return reply.error(errno);
         }
+        let fh = self.handles.open(ino_of(ino), flags, req.pid());
+        reply.opened(fh, self.open_flags(ino_of(ino)));
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.handles.release(fh);
+        reply.ok();
     }
 
     fn flush(
@@ -417,29 +1087,98 @@ impl Filesystem for TestFS {
         reply.ok();
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-        match self.unlink(parent as Ino, name) {
-            Ok(_) => reply.ok(),
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, _fh: u64, _datasync: bool, reply: fuser::ReplyEmpty) {
+        let outcome = self.run_effects(req, effect::OpDesr::Fsync, ino);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
+        // Pending writes of the file are persisted before it's synced
+        let (due, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|pw| pw.ino == ino_of(ino));
+        self.pending = rest;
+        for pw in due {
+            self.persist(pw.ino, pw.offset, &pw.data).ok();
+        }
+        let synced = match self.access_node_mut(ino_of(ino)) {
+            Ok(Node {
+                item: NodeItem::File(file),
+                ..
+            }) => {
+                file.sync();
+                Ok(())
+            }
+            Ok(_) => Ok(()),
+            Err(errno) => Err(errno),
+        };
+        outcome.reply(reply, move |reply| match synced {
+            Ok(()) => reply.ok(),
             Err(errno) => reply.error(errno),
+        });
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        let op = effect::OpDesr::Unlink { name: name.to_string_lossy().into() };
+        let outcome = self.run_effects(req, op, parent);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
+        match self.unlink(parent as Ino, name) {
+            Ok(_) => outcome.reply(reply, move |reply| reply.ok()),
+            Err(errno) => outcome.reply(reply, move |reply| reply.error(errno)),
         }
     }
 
-    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        let op = effect::OpDesr::Rmdir { name: name.to_string_lossy().into() };
+        let outcome = self.run_effects(req, op, parent);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
         match self.unlink(parent as Ino, name) {
-            Ok(_) => reply.ok(),
-            Err(errno) => reply.error(errno),
+            Ok(_) => outcome.reply(reply, move |reply| reply.ok()),
+            Err(errno) => outcome.reply(reply, move |reply| reply.error(errno)),
         }
     }
 
     fn getxattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         name: &OsStr,
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
-        match xaops::get(&self.tree, ino as Ino, &name.to_string_lossy()) {
+        let name = name.to_string_lossy();
+        if !name.starts_with("bf.") {
+            let outcome = self.run_effects(req, effect::OpDesr::Getxattr { name: name.to_string(), size: size as usize }, ino);
+            let value = match outcome.errno {
+                Some(errno) => Err(errno),
+                None => self
+                    .access_node(ino_of(ino))
+                    .and_then(|node| node.xattrs.get(&*name).cloned().ok_or(libc::ENODATA)),
+            };
+            outcome.reply(reply, move |reply| match value {
+                Ok(v) if size == 0 => reply.size(v.len() as u32),
+                Ok(v) if size as usize >= v.len() => reply.data(&v),
+                Ok(_) => reply.error(libc::ERANGE),
+                Err(errno) => reply.error(errno),
+            });
+            return;
+        }
+        let value = match name.strip_prefix("bf.diff.") {
+            Some(spec) => self.snapshot_diff(spec),
+            None if name == "bf.doctor" => Some(self.doctor()),
+            None if name == "bf.snapshot" => {
+                let names: Vec<_> = self.snapshots.keys().collect();
+                Some(serde_json::to_string(&names).unwrap())
+            }
+            None => xaops::get(&self.tree, ino_of(ino), &name),
+        };
+        match value {
             Some(v) if size as usize > v.as_bytes().len() => reply.data(v.as_bytes()),
             Some(v) => reply.size(v.as_bytes().len() as u32),
             None => reply.error(ENOENT),
@@ -448,17 +1187,67 @@ impl Filesystem for TestFS {
 
     fn setxattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         name: &OsStr,
         value: &[u8],
-        _flags: i32,
+        flags: i32,
         _position: u32,
         reply: fuser::ReplyEmpty,
     ) {
+        if !name.to_string_lossy().starts_with("bf.") {
+            let name = name.to_string_lossy().to_string();
+            let op = effect::OpDesr::Setxattr { name: name.clone(), size: value.len() };
+            let outcome = self.run_effects(req, op, ino);
+            let result = match outcome.errno {
+                Some(errno) => Err(errno),
+                None => self.access_node_mut(ino_of(ino)).and_then(|node| {
+                    match (node.xattrs.contains_key(&name), flags) {
+                        (true, libc::XATTR_CREATE) => return Err(libc::EEXIST),
+                        (false, libc::XATTR_REPLACE) => return Err(libc::ENODATA),
+                        _ => (),
+                    }
+                    node.xattrs.insert(name, value.to_vec());
+                    Ok(())
+                }),
+            };
+            outcome.reply(reply, move |reply| match result {
+                Ok(()) => reply.ok(),
+                Err(errno) => reply.error(errno),
+            });
+            return;
+        }
+
+        // Generated files are created in the directory the attribute is set on
+        if let Some(fname) = name.to_string_lossy().strip_prefix("bf.generate.") {
+            let req = match serde_json::from_slice(value) {
+                Ok(storage) => NodeCreateReq {
+                    ntype: NodeCreateT::Generated(storage),
+                    req,
+                },
+                Err(_) => return reply.error(libc::EINVAL),
+            };
+            return match self.create_node(req, ino_of(ino), OsStr::new(fname), 0o444, 0) {
+                Ok(_) => reply.ok(),
+                Err(errno) => reply.error(errno),
+            };
+        }
+
+        if name == "bf.powercut" {
+            self.power_cut(ino_of(ino));
+            return reply.ok();
+        }
+
+        // Snapshots are taken of the subtree the attribute is set on
+        if let Some(sname) = name.to_string_lossy().strip_prefix("bf.snapshot.") {
+            let snapshot = snapshot::Snapshot::take(&self.tree, ino_of(ino));
+            self.snapshots.insert(sname.to_owned(), snapshot);
+            return reply.ok();
+        }
+
         match xaops::set(
             &mut self.tree,
-            ino as Ino,
+            ino_of(ino),
             &name.to_string_lossy(),
             &String::from_utf8_lossy(value),
         ) {
@@ -469,42 +1258,167 @@ impl Filesystem for TestFS {
 
     fn removexattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
-        match xaops::remove(&mut self.tree, ino as Ino, &name.to_string_lossy()) {
+        if !name.to_string_lossy().starts_with("bf.") {
+            let name = name.to_string_lossy().to_string();
+            let outcome = self.run_effects(req, effect::OpDesr::Removexattr { name: name.clone() }, ino);
+            let result = match outcome.errno {
+                Some(errno) => Err(errno),
+                None => self
+                    .access_node_mut(ino_of(ino))
+                    .and_then(|node| node.xattrs.remove(&name).map(|_| ()).ok_or(libc::ENODATA)),
+            };
+            outcome.reply(reply, move |reply| match result {
+                Ok(()) => reply.ok(),
+                Err(errno) => reply.error(errno),
+            });
+            return;
+        }
+        if let Some(sname) = name.to_string_lossy().strip_prefix("bf.snapshot.") {
+            return match self.snapshots.remove(sname) {
+                Some(_) => reply.ok(),
+                None => reply.error(ENOENT),
+            };
+        }
+        match xaops::remove(&mut self.tree, ino_of(ino), &name.to_string_lossy()) {
             Some(_) => reply.ok(),
             None => reply.error(ENOENT),
         }
     }
 
+    // Lists user extended attributes only, bf. ones are an interface rather than metadata
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
+        let outcome = self.run_effects(req, effect::OpDesr::Listxattr { size: size as usize }, ino);
+        let list = match outcome.errno {
+            Some(errno) => Err(errno),
+            None => self.access_node(ino_of(ino)).map(|node| {
+                let mut list = vec![];
+                for name in node.xattrs.keys() {
+                    list.extend_from_slice(name.as_bytes());
+                    list.push(0);
+                }
+                list
+            }),
+        };
+        outcome.reply(reply, move |reply| match list {
+            Ok(list) if size == 0 => reply.size(list.len() as u32),
+            Ok(list) if size as usize >= list.len() => reply.data(&list),
+            Ok(_) => reply.error(libc::ERANGE),
+            Err(errno) => reply.error(errno),
+        });
+    }
+
     fn fallocate(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
-        _offset: i64,
-        _length: i64,
-        _mode: i32,
+        offset: i64,
+        length: i64,
+        mode: i32,
         reply: fuser::ReplyEmpty,
     ) {
-        reply.ok();
+        let node = match self.access_node_mut(ino_of(ino)) {
+            Ok(node) => node,
+            Err(errno) => return reply.error(errno),
+        };
+        if offset < 0 || length <= 0 {
+            return reply.error(libc::EINVAL);
+        }
+        let Some(end) = offset.checked_add(length) else {
+            return reply.error(libc::EFBIG);
+        };
+        // Storage is never preallocated, so only allocations without visible effects besides the size
+        // are supported. Punching holes, zeroing or collapsing ranges isn't
+        if mode & !libc::FALLOC_FL_KEEP_SIZE != 0 {
+            return reply.error(libc::EOPNOTSUPP);
+        }
+        match node.item {
+            NodeItem::File(ref file) if file.storage().readonly() => reply.error(libc::EROFS),
+            NodeItem::File(ref mut file) => {
+                // Plain allocation extends the file, so it can be mmap'd right away
+                if mode == 0 && end as u64 > node.attr.size {
+                    file.storage_mut().truncate(end as usize);
+                    node.attr.resize(end as u64);
+                }
+                reply.ok();
+            }
+            NodeItem::Proc(_) => reply.error(libc::EROFS),
+            _ => reply.error(libc::ENODEV),
+        }
     }
 
-    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
-        let storage::Stat { blocks, bavail } = self.sfactory.statfs();
-        reply.statfs(
-            blocks,
-            bavail,
-            bavail,
-            self.tree.count() as u64,
-            100500,
-            4096,
-            255,
-            0,
+    fn copy_file_range(
+        &mut self,
+        req: &Request<'_>,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: fuser::ReplyWrite,
+    ) {
+        // Copies are reads of the source and writes of the destination for effects
+        let read = self.run_effects(
+            req,
+            effect::OpDesr::Read { offset: offset_in as usize, len: len as usize, first: false },
+            ino_in,
+        );
+        let mut outcome = self.run_effects(
+            req,
+            effect::OpDesr::Write { offset: offset_out as usize, len: len as usize, cached: false },
+            ino_out,
+        );
+        outcome.sleep_ms += read.sleep_ms;
+        if let Some(errno) = read.errno.or(outcome.errno) {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
+
+        let len = [read.changes.max_len, outcome.changes.max_len]
+            .into_iter()
+            .flatten()
+            .fold(len as usize, usize::min);
+        let copied = self.copy_range(
+            (ino_of(ino_in), offset_in as usize),
+            (ino_of(ino_out), offset_out as usize),
+            len,
+            outcome.changes.clone_mode,
         );
+        outcome.reply(reply, move |reply| match copied {
+            Ok(copied) => reply.written(copied as u32),
+            Err(errno) => reply.error(errno),
+        });
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
+        let outcome = self.run_effects(req, effect::OpDesr::Statfs, ino);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
+
+        let bsize = self.blksize;
+        let storage::Stat { blocks, bavail } = outcome
+            .changes
+            .statfs
+            .unwrap_or_else(|| self.sfactory.statfs(bsize as u64));
+        let bavail = match outcome.changes.free_ratio {
+            Some(ratio) => (bavail as f64 * ratio.clamp(0.0, 1.0)) as u64,
+            None => bavail,
+        };
+        let files = outcome.changes.files.unwrap_or(self.tree.count() as u64);
+        let ffree = outcome.changes.ffree.unwrap_or(100500);
+        outcome.reply(reply, move |reply| {
+            reply.statfs(blocks, bavail, bavail, files, ffree, bsize, 255, bsize)
+        });
     }
 
     fn symlink(
@@ -515,49 +1429,138 @@ impl Filesystem for TestFS {
         target: &std::path::Path,
         reply: ReplyEntry,
     ) {
+        let op = effect::OpDesr::Symlink { name: link_name.to_string_lossy().into() };
+        let outcome = self.run_effects(req, op, parent);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
         let req = NodeCreateReq {
             ntype: NodeCreateT::Symlink(target),
             req,
         };
         match self.create_node(req, parent as Ino, link_name, 0x777, 0) {
-            Ok(ref attr) => reply.entry(&TTL, attr, 0),
-            Err(errno) => reply.error(errno),
+            Ok(attr) => outcome.reply(reply, move |reply| reply.entry(&TTL, &attr, 0)),
+            Err(errno) => outcome.reply(reply, move |reply| reply.error(errno)),
         }
     }
 
-    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
-        let node = match self.access_node(ino as Ino) {
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let mut outcome = self.run_effects(req, effect::OpDesr::Readlink, ino);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
+
+        let node = match self.access_node(ino_of(ino)) {
             Ok(node) => node,
             Err(errno) => return reply.error(errno),
         };
-        if let NodeItem::Symlink(ref path) = node.item {
-            reply.data(&path.as_os_str().as_bytes());
-        } else {
-            reply.error(ENOENT);
-        }
+        let target = match node.item {
+            NodeItem::Symlink(ref path) => outcome.changes.link_target.take().unwrap_or_else(|| path.clone()),
+            _ => return reply.error(ENOENT),
+        };
+        outcome.reply(reply, move |reply| reply.data(target.as_os_str().as_bytes()));
     }
 
     fn link(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         newparent: u64,
         newname: &OsStr,
         reply: ReplyEntry,
     ) {
+        let op = effect::OpDesr::Link { name: newname.to_string_lossy().into() };
+        let outcome = self.run_effects(req, op, newparent);
+        if let Some(errno) = outcome.errno {
+            outcome.reply(reply, move |reply| reply.error(errno));
+            return;
+        }
         match self.tree.link(
-            ino as Ino,
+            ino_of(ino),
             newparent as Ino,
             newname.to_string_lossy().to_string(),
         ) {
-            Ok(ref attr) => reply.entry(&TTL, attr, 0),
-            Err(errno) => reply.error(errno),
+            Ok(attr) => outcome.reply(reply, move |reply| reply.entry(&TTL, &attr, 0)),
+            Err(errno) => outcome.reply(reply, move |reply| reply.error(errno)),
+        }
+    }
+}
+
+// Create the virtual introspection directory with its files under root
+fn mount_procfs(tree: &mut Tree, blksize: u32) {
+    let (dino, slot) = tree.create(1, procfs::DIR_NAME.to_owned()).unwrap();
+    slot.replace(Node {
+        parent: 1,
+        item: NodeItem::Dir(Dir::default()),
+        attr: fresh_attr(dino, FileType::Directory, 0, 0o555, 1000, 1001, blksize),
+        effects: effect::Group::default(),
+        xattrs: BTreeMap::new(),
+    });
+    for (name, pfile) in procfs::FILES {
+        let (ino, slot) = tree.create(dino, name.to_owned()).unwrap();
+        slot.replace(Node {
+            parent: dino,
+            item: NodeItem::Proc(pfile),
+            attr: fresh_attr(ino, FileType::RegularFile, 0, 0o444, 1000, 1001, blksize),
+            effects: effect::Group::default(),
+            xattrs: BTreeMap::new(),
+        });
+    }
+}
+
+// Mirror host directory `path` under `dir` as the lower layer of an overlay. Files are read
+// from the host until modified, deletions only hide them
+fn mount_lower(tree: &mut Tree, dir: Ino, path: &Path, blksize: u32) {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(meta) = entry.path().symlink_metadata() else {
+            continue;
+        };
+        let (kind, item) = if meta.is_dir() {
+            (FileType::Directory, NodeItem::Dir(Dir::default()))
+        } else if meta.is_file() {
+            match storage::LowerStorage::open(&entry.path()) {
+                Ok(storage) => (FileType::RegularFile, NodeItem::File(File::create(Box::new(storage)))),
+                Err(_) => continue,
+            }
+        } else if meta.is_symlink() {
+            match std::fs::read_link(entry.path()) {
+                Ok(target) => (FileType::Symlink, NodeItem::Symlink(target)),
+                Err(_) => continue,
+            }
+        } else {
+            continue;
+        };
+
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Ok((ino, slot)) = tree.create(dir, name) else {
+            continue;
+        };
+        let mut attr = fresh_attr(ino, kind, 0, meta.mode() & 0o7777, meta.uid(), meta.gid(), blksize);
+        // Directory sizes count their entries
+        if kind != FileType::Directory {
+            attr.resize(meta.len());
+        }
+        attr.mtime = meta.modified().unwrap_or(attr.mtime);
+        slot.replace(Node {
+            parent: dir,
+            item,
+            attr,
+            effects: effect::Group::default(),
+            xattrs: BTreeMap::new(),
+        });
+        if kind == FileType::Directory {
+            mount_lower(tree, ino, &entry.path(), blksize);
         }
     }
 }
 
 // Broken fuse FS
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Serialize)]
 #[command(version, about, long_about = None)]
 struct Args {
     // Mount point of filesystem
@@ -568,13 +1571,108 @@ struct Args {
     #[arg(short, long)]
     passthrough: Option<String>,
 
+    // Silently flip this many random bits per second in stored data
+    #[arg(long)]
+    bitrot_rate: Option<f64>,
+
+    // Limit bit rot to these subtrees, like /data
+    #[arg(long)]
+    bitrot_path: Vec<String>,
+
+    // Host directory shown read only below the tree, like the lower layer of an overlay
+    #[arg(long)]
+    lower: Option<String>,
+
     #[arg(long)]
     seed: Option<u64>,
+
+    // Globs of paths effects may be attached at, like /sandbox/**. Anywhere if unset
+    #[arg(long)]
+    chaos_paths: Vec<String>,
+
+    // Json error model applied to the whole mount, see the model effect
+    #[arg(long)]
+    error_model: Option<String>,
+
+    // Json bad block map of paths to their unreadable [offset, len] ranges, as exported by bf.badblocks
+    #[arg(long)]
+    badblocks: Option<String>,
+
+    // Fail all operations once the daemon's resident memory exceeds this limit
+    #[arg(long)]
+    rss_limit_mb: Option<u64>,
+
+    // Allow effects to inject errnos applications rarely expect: EFAULT, EBADF, EOVERFLOW, ERANGE
+    #[arg(long)]
+    unsafe_errnos: bool,
+
+    // Error returned once the rss limit is exceeded, by name or number
+    #[arg(long, default_value = "ENOMEM", value_parser = parse_errno)]
+    #[serde(with = "ftypes::errno")]
+    rss_errno: ErrNo,
+
+    // Keep at most this many delayed replies in flight, reply to the rest right away
+    #[arg(long, default_value_t = 1024)]
+    max_delayed: usize,
+
+    // Trace effects consulted by this many most recent operations, retrieved with bf.trace
+    #[arg(long, default_value_t = 0)]
+    trace: usize,
+
+    // Interval of metric samples exported as time series
+    #[arg(long, default_value_t = 1000)]
+    metrics_interval_ms: u64,
+
+    // Block size reported in attributes and statfs
+    #[arg(long, default_value_t = 4096)]
+    blksize: u32,
+
+    // Bypass the kernel page cache, so effects see all io
+    #[arg(long)]
+    direct_io: bool,
+
+    // Let the kernel cache missing names for this long instead of failing lookups with ENOENT
+    #[arg(long)]
+    negative_ttl_ms: Option<u64>,
+
+    // Order of directory listings, can be changed per directory
+    #[arg(long, value_enum, default_value_t)]
+    dir_order: DirOrder,
+
+    // Buffer writes as unsynced until fsync, so bf.powercut can drop them
+    #[arg(long)]
+    power_cuts: bool,
+
+    // Fail unlink and rename of open files with EBUSY, like on windows
+    #[arg(long)]
+    busy_open: bool,
+
+    // Inject at most this many errors per budget window across the mount
+    #[arg(long)]
+    error_budget: Option<usize>,
+
+    // Length of the error budget window
+    #[arg(long, default_value_t = 60000)]
+    error_budget_window_ms: u64,
+
+    // Save effects by path to this file on unmount and install them again on the next mount
+    #[arg(long)]
+    state_file: Option<String>,
+}
+
+fn parse_errno(s: &str) -> Result<ErrNo, String> {
+    ftypes::errno::parse(s).ok_or_else(|| format!("unknown errno {}", s))
 }
 
 fn main() {
     let args = Args::parse();
+    let config = serde_json::to_string(&args).unwrap();
     env_logger::init();
+    ftypes::errno::allow_unsafe(args.unsafe_errnos);
+    inflight::set_limit(args.max_delayed);
+    effect::restrict_paths(args.chaos_paths.clone());
+    trace::set_capacity(args.trace);
+    metrics::set_interval(Duration::from_millis(args.metrics_interval_ms.max(1)));
 
     let mountpoint = args.mount_path;
     let options = vec![
@@ -585,21 +1683,58 @@ fn main() {
         MountOption::AllowRoot,
     ];
 
+    let blksize = args.blksize;
+    assert!(
+        blksize.is_power_of_two() && blksize >= 512,
+        "Block size must be a power of two of at least 512"
+    );
+
     let nodes = [
         Node {
             parent: 0,
             item: NodeItem::Dir(Dir::default()),
-            attr: fresh_attr(0, FileType::Directory, 0, 0x000, 1000, 1001),
+            attr: fresh_attr(0, FileType::Directory, 0, 0x000, 1000, 1001, blksize),
             effects: effect::Group::default(),
+            xattrs: BTreeMap::new(),
         },
         Node {
             parent: 1,
             item: NodeItem::Dir(Dir::default()),
-            attr: fresh_attr(1, FileType::Directory, 0, 0o754, 1000, 1001),
+            attr: fresh_attr(1, FileType::Directory, 0, 0o754, 1000, 1001, blksize),
             effects: effect::Group::default(),
+            xattrs: BTreeMap::new(),
         },
     ];
-    let tree = Tree::new(nodes);
+    let mut tree = Tree::new(nodes);
+    mount_procfs(&mut tree, blksize);
+    if let Some(ref lower) = args.lower {
+        mount_lower(&mut tree, 1, Path::new(lower), blksize);
+    }
+    if let Some(ref path) = args.error_model {
+        let mut model: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).expect("Failed to read error model"))
+                .expect("Error model is not valid json");
+        model["op"] = "rwls".into();
+        let effect = effect::DefinedEffect::create("model", &model.to_string()).expect("Invalid error model");
+        effect::attach(&mut tree, 1, effect).expect("Effects can't be attached at the root outside of --chaos-paths");
+    }
+    if let Some(ref path) = args.badblocks {
+        let paths: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).expect("Failed to read bad block map"))
+                .expect("Bad block map is not valid json");
+        let params = serde_json::json!({"paths": paths, "op": "r"});
+        let effect = effect::DefinedEffect::create("badrange", &params.to_string()).expect("Invalid bad block map");
+        effect::attach(&mut tree, 1, effect).expect("Effects can't be attached at the root outside of --chaos-paths");
+    }
+    // Installed after the lower layer is mounted, so its paths resolve. A missing file is a first run
+    let unresolved = match args.state_file.as_ref().map(std::fs::read_to_string) {
+        Some(Ok(data)) => {
+            let state = serde_json::from_str(&data).expect("State file is not a json object");
+            effect::import(&mut tree, state)
+        }
+        Some(Err(err)) if err.kind() != std::io::ErrorKind::NotFound => panic!("Failed to read state file: {}", err),
+        _ => serde_json::Map::new(),
+    };
     let sfactory = if let Some(path) = args.passthrough {
         Box::new(storage::FileSFactory::new(&path)) as Box<dyn storage::Factory>
     } else {
@@ -611,6 +1746,20 @@ fn main() {
         rand::rngs::StdRng::from_os_rng()
     };
 
+    let budget = args
+        .error_budget
+        .map(|limit| effect::ErrorBudget::new(limit, Duration::from_millis(args.error_budget_window_ms)));
+
+    let rss_guard = args
+        .rss_limit_mb
+        .map(|limit| effect::RssGuard::new(limit * 1024 * 1024, args.rss_errno));
+
+    let bitrot = args.bitrot_rate.filter(|rate| *rate > 0.0).map(|rate| BitRot {
+        rate,
+        paths: args.bitrot_path.clone(),
+        last: Instant::now(),
+    });
+
     println!("Running brokenfuse");
 
     fuser::mount2(
@@ -618,6 +1767,24 @@ fn main() {
             tree,
             sfactory,
             rgen,
+            budget,
+            handles: handles::Handles::default(),
+            config,
+            pending: vec![],
+            direct_io: args.direct_io,
+            rss_guard,
+            aliases: vec![],
+            blksize,
+            busy_open: args.busy_open,
+            dir_order: args.dir_order,
+            listings: BTreeMap::new(),
+            negative_ttl: args.negative_ttl_ms.map(Duration::from_millis),
+            bitrot,
+            last_sweep: Instant::now(),
+            power_cuts: args.power_cuts,
+            snapshots: BTreeMap::new(),
+            state_file: args.state_file,
+            unresolved,
         },
         mountpoint,
         &options,
@@ -0,0 +1,96 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Number of most recent samples kept
+const CAPACITY: usize = 3600;
+
+// Counters of one operation kind within a sample
+#[derive(Serialize, Default, Clone, Copy)]
+struct Counts {
+    ops: u64,
+    errors: u64,
+    injected_ms: u64,
+}
+
+#[derive(Serialize)]
+struct Sample {
+    time_ms: u128, // end of the sampled interval
+    ops: BTreeMap<&'static str, Counts>,
+}
+
+struct Metrics {
+    interval: Duration,
+    since: Option<Instant>, // start of the current interval
+    current: BTreeMap<&'static str, Counts>,
+    samples: VecDeque<Sample>,
+}
+
+impl Metrics {
+    // Close the current interval if it's over
+    fn roll(&mut self) {
+        let since = *self.since.get_or_insert_with(Instant::now);
+        if since.elapsed() < self.interval {
+            return;
+        }
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        if self.samples.len() >= CAPACITY {
+            self.samples.pop_front();
+        }
+        let ops = std::mem::take(&mut self.current);
+        self.samples.push_back(Sample { time_ms, ops });
+        self.since = Some(Instant::now());
+    }
+}
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    interval: Duration::from_millis(1000),
+    since: None,
+    current: BTreeMap::new(),
+    samples: VecDeque::new(),
+});
+
+pub fn set_interval(interval: Duration) {
+    METRICS.lock().unwrap().interval = interval;
+}
+
+// Count operation, whether it failed and the latency injected into it
+pub fn record(op: &'static str, failed: bool, injected_ms: u64) {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.roll();
+    let counts = metrics.current.entry(op).or_default();
+    counts.ops += 1;
+    counts.errors += failed as u64;
+    counts.injected_ms += injected_ms;
+}
+
+// Samples as json, oldest first
+pub fn dump() -> String {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.roll();
+    serde_json::to_string(&metrics.samples).unwrap()
+}
+
+// Samples as csv with a row per sample and operation kind, oldest first
+pub fn dump_csv() -> String {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.roll();
+    let mut out = String::from("time_ms,op,ops,errors,injected_ms\n");
+    for sample in &metrics.samples {
+        for (op, c) in &sample.ops {
+            writeln!(out, "{},{},{},{},{}", sample.time_ms, op, c.ops, c.errors, c.injected_ms).unwrap();
+        }
+    }
+    out
+}
+
+pub fn clear() {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics.samples.clear();
+    metrics.current.clear();
+}
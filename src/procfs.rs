@@ -0,0 +1,71 @@
+use serde_json::{Value as JValue, json};
+
+use crate::{
+    ftree::Tree,
+    ftypes::{Ino, NodeItem},
+    handles::Handles,
+    metrics,
+};
+
+// Name of the virtual introspection directory under root
+pub const DIR_NAME: &str = ".brokenfuse";
+
+// Read only file mirroring internal state, generated on every read
+#[derive(Clone, Copy)]
+pub enum ProcFile {
+    Tree,
+    Handles,
+    Effects,
+    Version,
+    Config,
+    Metrics,
+}
+
+pub const FILES: [(&str, ProcFile); 6] = [
+    ("tree", ProcFile::Tree),
+    ("handles", ProcFile::Handles),
+    ("effects", ProcFile::Effects),
+    ("version", ProcFile::Version),
+    ("config", ProcFile::Config),
+    ("metrics.csv", ProcFile::Metrics),
+];
+
+// Dump node and its subtree
+fn dump_node(tree: &Tree, ino: Ino, name: &str) -> JValue {
+    let Some(node) = tree.get(ino) else {
+        return JValue::Null;
+    };
+    let mut out = json!({
+        "ino": ino,
+        "name": name,
+        "kind": format!("{:?}", node.attr.kind),
+        "size": node.attr.size,
+        "nlink": node.attr.nlink,
+        "effects": &node.effects,
+    });
+    if let NodeItem::Dir(ref dir) = node.item {
+        out["children"] = dir
+            .list()
+            .map(|(child, cname)| dump_node(tree, child, cname))
+            .collect();
+    }
+    out
+}
+
+pub fn render(file: ProcFile, tree: &Tree, handles: &Handles, config: &str) -> String {
+    match file {
+        ProcFile::Tree => dump_node(tree, 1, "/").to_string(),
+        ProcFile::Handles => serde_json::to_string(&handles.list().collect::<Vec<_>>()).unwrap(),
+        ProcFile::Effects => {
+            let all: Vec<_> = tree
+                .traverse(1)
+                .filter(|n| (&n.effects).into_iter().next().is_some())
+                .map(|n| json!({ "ino": n.attr.ino, "effects": &n.effects }))
+                .collect();
+            serde_json::to_string(&all).unwrap()
+        }
+        ProcFile::Version => format!("{}\n", env!("CARGO_PKG_VERSION")),
+        ProcFile::Config => config.to_owned(),
+        ProcFile::Metrics => metrics::dump_csv(),
+    }
+}
@@ -0,0 +1,121 @@
+use serde_json::{Value as JValue, json};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::ftree::Tree;
+use crate::ftypes::{Ino, NodeItem};
+use crate::storage::{Storage, freeze};
+
+// State of a node at the time of a snapshot
+struct Entry {
+    kind: &'static str,
+    size: u64,
+    perm: u16,
+    uid: u32,
+    gid: u32,
+    mtime: SystemTime,
+    content: Option<Box<dyn Storage>>, // frozen contents of writable files
+    target: Option<PathBuf>,           // target of symlinks
+}
+
+// Point in time state of a subtree, by paths relative to its root. Contents of files in memory
+// are shared copy-on-write with the live files, others are copied
+pub struct Snapshot {
+    root: Ino,
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Snapshot {
+    pub fn take(tree: &Tree, root: Ino) -> Snapshot {
+        let prefix = tree.path(root).unwrap_or_default();
+        let mut entries = BTreeMap::new();
+        for node in tree.traverse(root) {
+            let Some(path) = tree.path(node.attr.ino as Ino) else {
+                continue;
+            };
+            let path = path.strip_prefix(&prefix).unwrap_or(&path);
+            let (kind, content, target) = match node.item {
+                NodeItem::Dir(_) => ("dir", None, None),
+                NodeItem::File(ref file) => ("file", freeze(file.storage()), None),
+                NodeItem::Symlink(ref target) => ("symlink", None, Some(target.clone())),
+                NodeItem::Proc(_) => continue,
+            };
+            entries.insert(
+                format!("/{}", path.trim_start_matches('/')),
+                Entry {
+                    kind,
+                    size: node.attr.size,
+                    perm: node.attr.perm,
+                    uid: node.attr.uid,
+                    gid: node.attr.gid,
+                    mtime: node.attr.mtime,
+                    content,
+                    target,
+                },
+            );
+        }
+        Snapshot { root, entries }
+    }
+
+    // Node the snapshot was taken at
+    pub fn root(&self) -> Ino {
+        self.root
+    }
+}
+
+// Byte ranges (offset, len) with different contents, including growth and shrinkage
+fn changed_ranges(a: &dyn Storage, b: &dyn Storage) -> Vec<(usize, usize)> {
+    const STEP: usize = 65536;
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for offset in (0..a.len().max(b.len())).step_by(STEP) {
+        let (x, y) = (a.read(offset, STEP), b.read(offset, STEP));
+        for i in 0..x.len().max(y.len()) {
+            if x.get(i) == y.get(i) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((start, len)) if *start + *len == offset + i => *len += 1,
+                _ => ranges.push((offset + i, 1)),
+            }
+        }
+    }
+    ranges
+}
+
+// Report changes from `a` to `b`: added and removed paths, changed metadata and byte ranges
+// of modified ones
+pub fn diff(a: &Snapshot, b: &Snapshot) -> JValue {
+    let added: Vec<_> = b.entries.keys().filter(|p| !a.entries.contains_key(*p)).collect();
+    let removed: Vec<_> = a.entries.keys().filter(|p| !b.entries.contains_key(*p)).collect();
+    let mut modified = serde_json::Map::new();
+    for (path, old) in &a.entries {
+        let Some(new) = b.entries.get(path) else {
+            continue;
+        };
+        let mut changes = serde_json::Map::new();
+        let mut compare = |field: &str, x: JValue, y: JValue| {
+            if x != y {
+                changes.insert(field.to_owned(), json!([x, y]));
+            }
+        };
+        let ms = |t: SystemTime| t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        compare("kind", old.kind.into(), new.kind.into());
+        compare("size", old.size.into(), new.size.into());
+        compare("perm", old.perm.into(), new.perm.into());
+        compare("uid", old.uid.into(), new.uid.into());
+        compare("gid", old.gid.into(), new.gid.into());
+        compare("mtime_ms", ms(old.mtime).into(), ms(new.mtime).into());
+        compare("target", json!(old.target), json!(new.target));
+        if let (Some(x), Some(y)) = (&old.content, &new.content) {
+            let ranges = changed_ranges(x.as_ref(), y.as_ref());
+            if !ranges.is_empty() {
+                changes.insert("ranges".to_owned(), json!(ranges));
+            }
+        }
+        if !changes.is_empty() {
+            modified.insert(path.clone(), changes.into());
+        }
+    }
+    json!({"added": added, "removed": removed, "modified": modified})
+}
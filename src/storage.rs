@@ -1,8 +1,12 @@
+use rand::RngCore;
+use serde::Deserialize;
 use std::{
     borrow::Cow,
+    cell::{Cell, RefCell, RefMut},
     fs::File,
     os::unix::fs::FileExt,
     path::{Path, PathBuf}, str::FromStr,
+    rc::Rc,
 };
 
 use crate::ftypes::Ino;
@@ -12,56 +16,198 @@ pub trait Storage {
     fn truncate(&mut self, size: usize);
     fn read(&self, offset: usize, size: usize) -> Cow<'_, [u8]>;
     fn write(&mut self, offset: usize, data: &[u8]);
+
+    // Read only storage rejects writes
+    fn readonly(&self) -> bool {
+        false
+    }
+
+    // Whether backing data can still be accessed, storage swallows io errors otherwise
+    fn reachable(&self) -> bool {
+        true
+    }
+
+    // Extents covering `len` bytes at `offset` for sharing, if supported for the range
+    fn extents(&self, _offset: usize, _len: usize) -> Option<Vec<Rc<Extent>>> {
+        None
+    }
+
+    // Share `extents` at `offset` copy-on-write, or with writes visible through all sharers if
+    // `shared` is set. Returns false if not supported for the range
+    fn reflink(&mut self, _offset: usize, _extents: Vec<Rc<Extent>>, _shared: bool) -> bool {
+        false
+    }
 }
 
+#[derive(Clone, Copy)]
 pub struct Stat {
     pub blocks: u64,
     pub bavail: u64,
 }
 
+// Frozen copy of storage contents. Read only storages produce their contents and aren't kept
+pub fn freeze(storage: &dyn Storage) -> Option<Box<dyn Storage>> {
+    if storage.readonly() {
+        return None;
+    }
+    let frozen = match storage.extents(0, storage.len()) {
+        Some(extents) => RamStorage::from_extents(extents),
+        None => {
+            let mut copy = RamStorage::create();
+            copy.write(0, &storage.read(0, storage.len()));
+            copy
+        }
+    };
+    Some(Box::new(frozen))
+}
+
 pub trait Factory {
     fn create(&self, ino: Ino) -> Box<dyn Storage>;
-    fn statfs(&self) -> Stat;
+    // Capacity in blocks of `bsize` bytes
+    fn statfs(&self, bsize: u64) -> Stat;
 }
 
+// Block of data that can be shared between storages by reflinks. It's copied on write unless
+// writes are shared
+pub struct Extent {
+    data: RefCell<Vec<u8>>,
+    cow: Cell<bool>,
+}
+
+impl Extent {
+    fn new(data: Vec<u8>) -> Rc<Extent> {
+        Rc::new(Extent {
+            data: RefCell::new(data),
+            cow: Cell::new(true),
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.data.borrow().len()
+    }
+}
+
+// Storage in memory, split into extents of CHUNK bytes. All but the last extent are full
 pub struct RamStorage {
-    buffer: Vec<u8>,
+    chunks: Vec<Rc<Extent>>,
+    len: usize,
 }
 
 impl RamStorage {
+    // Extent size and granularity of sharing
+    const CHUNK: usize = 4096;
+
     pub fn create() -> RamStorage {
-        RamStorage { buffer: vec![] }
+        RamStorage {
+            chunks: vec![],
+            len: 0,
+        }
+    }
+
+    // Storage sharing `extents` copy-on-write
+    pub fn from_extents(extents: Vec<Rc<Extent>>) -> RamStorage {
+        RamStorage {
+            len: extents.iter().map(|ext| ext.len()).sum(),
+            chunks: extents,
+        }
+    }
+
+    // Chunk for writing, unshared first if it's copy on write or `unshare` is set
+    fn chunk_mut(&mut self, idx: usize, unshare: bool) -> RefMut<'_, Vec<u8>> {
+        let ext = &mut self.chunks[idx];
+        if Rc::strong_count(ext) > 1 && (unshare || ext.cow.get()) {
+            let data = ext.data.borrow().clone();
+            *ext = Extent::new(data);
+        }
+        ext.data.borrow_mut()
     }
 }
 
 impl Storage for RamStorage {
     fn len(&self) -> usize {
-        self.buffer.len()
+        self.len
     }
 
     fn truncate(&mut self, size: usize) {
-        // No need to extend ahead
-        if size <= self.buffer.len() {
-            self.buffer.resize(size, 0);
+        // Extend as well, short reads within the file size confuse the kernel about its size
+        let count = size.div_ceil(Self::CHUNK);
+        self.chunks.truncate(count);
+        if let Some(last) = self.chunks.len().checked_sub(1) {
+            let last_len = (size - last * Self::CHUNK).min(Self::CHUNK);
+            if self.chunks[last].len() != last_len {
+                self.chunk_mut(last, true).resize(last_len, 0);
+            }
+        }
+        while self.chunks.len() < count {
+            let chunk_len = (size - self.chunks.len() * Self::CHUNK).min(Self::CHUNK);
+            self.chunks.push(Extent::new(vec![0; chunk_len]));
         }
+        self.len = size;
     }
 
     fn read(&self, offset: usize, size: usize) -> Cow<'_, [u8]> {
-        let start = offset.min(self.buffer.len());
-        let end = (offset + size).min(self.buffer.len());
-        if start >= end {
-            return Cow::from(vec![]);
+        let end = (offset + size).min(self.len);
+        let mut out = Vec::with_capacity(end.saturating_sub(offset));
+        let mut pos = offset;
+        while pos < end {
+            let (idx, start) = (pos / Self::CHUNK, pos % Self::CHUNK);
+            let data = self.chunks[idx].data.borrow();
+            let stop = data.len().min(start + end - pos);
+            out.extend_from_slice(&data[start..stop]);
+            pos += stop - start;
         }
-        Cow::from(&self.buffer[start..end])
+        Cow::Owned(out)
     }
 
     fn write(&mut self, offset: usize, data: &[u8]) {
         let end = offset + data.len();
-        if end >= self.buffer.len() {
-            self.buffer.resize(end, 0);
+        if end > self.len {
+            self.truncate(end);
+        }
+        let mut pos = offset;
+        while pos < end {
+            let (idx, start) = (pos / Self::CHUNK, pos % Self::CHUNK);
+            let mut chunk = self.chunk_mut(idx, false);
+            let stop = chunk.len().min(start + end - pos);
+            chunk[start..stop].copy_from_slice(&data[pos - offset..pos - offset + stop - start]);
+            pos += stop - start;
+        }
+    }
+
+    fn extents(&self, offset: usize, len: usize) -> Option<Vec<Rc<Extent>>> {
+        let end = offset + len;
+        if offset % Self::CHUNK != 0 || end > self.len || (end % Self::CHUNK != 0 && end != self.len) {
+            return None;
+        }
+        Some(self.chunks[offset / Self::CHUNK..end.div_ceil(Self::CHUNK)].to_vec())
+    }
+
+    fn reflink(&mut self, offset: usize, extents: Vec<Rc<Extent>>, shared: bool) -> bool {
+        let total: usize = extents.iter().map(|ext| ext.len()).sum();
+        // A short last extent can only end the file
+        let partial = extents.last().is_some_and(|ext| ext.len() < Self::CHUNK);
+        if offset % Self::CHUNK != 0 || (partial && offset + total < self.len) {
+            return false;
+        }
+        if self.len < offset {
+            self.truncate(offset);
+        }
+
+        let first = offset / Self::CHUNK;
+        let count = extents.len();
+        for (idx, ext) in (first..).zip(extents) {
+            ext.cow.set(ext.cow.get() && !shared);
+            if idx < self.chunks.len() {
+                self.chunks[idx] = ext;
+            } else {
+                self.chunks.push(ext);
+            }
+        }
+        if partial {
+            self.chunks.truncate(first + count);
         }
-        let dest: &mut [u8] = &mut self.buffer[offset..offset + data.len()];
-        dest.copy_from_slice(data);
+        self.len = if partial { offset + total } else { self.len.max(offset + total) };
+        true
     }
 }
 
@@ -72,14 +218,14 @@ impl Factory for RamSFactory {
         Box::new(RamStorage::create())
     }
 
-    fn statfs(&self) -> Stat {
+    fn statfs(&self, bsize: u64) -> Stat {
         let mi = meminfo::MemInfo::new().unwrap();
         let mut values = mi.parse();
         let total = values.next().unwrap().size().unwrap() * 1024;
         let available = values.skip(1).next().unwrap().size().unwrap() * 1024;
         Stat {
-            blocks: (total / 4096) as u64,
-            bavail: (available / 4096) as u64,
+            blocks: total as u64 / bsize,
+            bavail: available as u64 / bsize,
         }
     }
 }
@@ -117,8 +263,8 @@ impl Storage for FileStorage {
         self.file.metadata().map(|m| m.len() as usize).unwrap_or(0)
     }
 
-    fn truncate(&mut self, _size: usize) {
-        todo!()
+    fn truncate(&mut self, size: usize) {
+        self.file.set_len(size as u64).ok();
     }
 
     fn read(&self, offset: usize, size: usize) -> Cow<'_, [u8]> {
@@ -130,6 +276,10 @@ impl Storage for FileStorage {
     fn write(&mut self, offset: usize, data: &[u8]) {
         self.file.write_all_at(data, offset as u64).ok();
     }
+
+    fn reachable(&self) -> bool {
+        self.file.metadata().is_ok() && self.path.exists()
+    }
 }
 
 pub struct FileSFactory {
@@ -142,7 +292,7 @@ impl Factory for FileSFactory {
         Box::new(FileStorage::create(&path))
     }
 
-    fn statfs(&self) -> Stat {
+    fn statfs(&self, _bsize: u64) -> Stat {
         Stat {
             blocks: 100,
             bavail: 100,
@@ -155,3 +305,127 @@ impl FileSFactory {
         FileSFactory { basepath: PathBuf::from_str(path).unwrap() }
     }
 }
+
+// File of the read only lower layer of an overlay. It's read from the host until first modified,
+// then copied up into memory. The host file is never modified
+pub struct LowerStorage {
+    file: File,
+    len: usize,
+    upper: Option<RamStorage>,
+}
+
+impl LowerStorage {
+    pub fn open(path: &Path) -> std::io::Result<LowerStorage> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        Ok(LowerStorage {
+            file,
+            len,
+            upper: None,
+        })
+    }
+
+    fn copy_up(&mut self) -> &mut RamStorage {
+        self.upper.get_or_insert_with(|| {
+            let mut upper = RamStorage::create();
+            let mut buffer = vec![0; self.len];
+            self.file.read_exact_at(&mut buffer, 0).ok();
+            upper.write(0, &buffer);
+            upper
+        })
+    }
+}
+
+impl Storage for LowerStorage {
+    fn len(&self) -> usize {
+        self.upper.as_ref().map_or(self.len, |upper| upper.len())
+    }
+
+    fn truncate(&mut self, size: usize) {
+        self.copy_up().truncate(size);
+    }
+
+    fn read(&self, offset: usize, size: usize) -> Cow<'_, [u8]> {
+        if let Some(ref upper) = self.upper {
+            return upper.read(offset, size);
+        }
+        let end = (offset + size).min(self.len);
+        let mut buffer = vec![0; end.saturating_sub(offset)];
+        self.file.read_exact_at(&mut buffer, offset as u64).ok();
+        Cow::Owned(buffer)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) {
+        self.copy_up().write(offset, data);
+    }
+
+    fn reachable(&self) -> bool {
+        self.upper.is_some() || self.file.metadata().is_ok()
+    }
+}
+
+// Content produced by a generator storage
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Pattern {
+    Zeros,
+    Random { seed: u64 },        // pseudorandom, stable for the same seed
+    Repeat { pattern: String },  // repeating byte pattern
+    Urandom,                     // fresh random bytes on every read
+}
+
+// Generator of declared size: reads are produced on the fly, nothing is stored.
+// {"size": 1073741824, "kind": "random", "seed": 42}
+#[derive(Deserialize)]
+pub struct GenStorage {
+    size: usize,
+    #[serde(flatten)]
+    pattern: Pattern,
+}
+
+// Mix input into well distributed 64 bits (splitmix64)
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+impl Storage for GenStorage {
+    fn len(&self) -> usize {
+        self.size
+    }
+
+    fn truncate(&mut self, size: usize) {
+        self.size = size;
+    }
+
+    fn read(&self, offset: usize, size: usize) -> Cow<'_, [u8]> {
+        let start = offset.min(self.size);
+        let end = (offset + size).min(self.size);
+        let mut buffer = vec![0; end - start];
+        match self.pattern {
+            Pattern::Zeros => (),
+            Pattern::Random { seed } => {
+                for (i, b) in (start..end).zip(buffer.iter_mut()) {
+                    *b = mix(seed ^ (i / 8) as u64).to_le_bytes()[i % 8];
+                }
+            }
+            Pattern::Repeat { ref pattern } if !pattern.is_empty() => {
+                let pattern = pattern.as_bytes();
+                for (i, b) in (start..end).zip(buffer.iter_mut()) {
+                    *b = pattern[i % pattern.len()];
+                }
+            }
+            Pattern::Repeat { .. } => (),
+            Pattern::Urandom => rand::rng().fill_bytes(&mut buffer),
+        }
+        Cow::Owned(buffer)
+    }
+
+    fn write(&mut self, _offset: usize, _data: &[u8]) {}
+
+    fn readonly(&self) -> bool {
+        true
+    }
+}
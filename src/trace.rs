@@ -0,0 +1,98 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::ftypes::{ErrNo, errno};
+
+// What an effect did with an operation
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Skipped, // operation type not matched
+    Ack,
+    Error(#[serde(with = "errno")] ErrNo),
+    Delay(u64),
+    Timeout {
+        after_ms: u64,
+        #[serde(with = "errno")]
+        errno: ErrNo,
+    },
+    // Delay and error a dry run effect would have injected
+    Dryrun {
+        delay_ms: u64,
+        #[serde(with = "errno::option")]
+        errno: Option<ErrNo>,
+    },
+}
+
+impl Verdict {
+    // Verdict of a dry run effect that would have returned this one
+    pub fn dry(self) -> Verdict {
+        match self {
+            Verdict::Error(errno) => Verdict::Dryrun { delay_ms: 0, errno: Some(errno) },
+            Verdict::Delay(ms) => Verdict::Dryrun { delay_ms: ms, errno: None },
+            Verdict::Timeout { after_ms, errno } => Verdict::Dryrun { delay_ms: after_ms, errno: Some(errno) },
+            verdict => verdict,
+        }
+    }
+}
+
+// Effect consulted for an operation
+#[derive(Serialize)]
+pub struct Step {
+    pub effect: String,
+    pub origin: String, // path the effect is attached at
+    pub verdict: Verdict,
+}
+
+// Effects consulted for an operation and its outcome
+#[derive(Serialize)]
+struct Traced {
+    op: String,
+    steps: Vec<Step>,
+    #[serde(with = "errno::option")]
+    errno: Option<ErrNo>,
+    sleep_ms: u64,
+}
+
+// Number of most recent operations traced, tracing is off if zero
+static TRACES: Mutex<(usize, VecDeque<Traced>)> = Mutex::new((0, VecDeque::new()));
+
+pub fn set_capacity(capacity: usize) {
+    let mut guard = TRACES.lock().unwrap();
+    guard.0 = capacity;
+    while guard.1.len() > capacity {
+        guard.1.pop_front();
+    }
+}
+
+pub fn enabled() -> bool {
+    TRACES.lock().unwrap().0 > 0
+}
+
+// Record trace of operation `op`, dropping the oldest one if full
+pub fn record(op: String, steps: Vec<Step>, errno: Option<ErrNo>, sleep_ms: u64) {
+    let mut guard = TRACES.lock().unwrap();
+    let (capacity, traces) = &mut *guard;
+    if *capacity == 0 {
+        return;
+    }
+    if traces.len() >= *capacity {
+        traces.pop_front();
+    }
+    traces.push_back(Traced {
+        op,
+        steps,
+        errno,
+        sleep_ms,
+    });
+}
+
+// Serialize traces, oldest first
+pub fn dump() -> String {
+    serde_json::to_string(&TRACES.lock().unwrap().1).unwrap()
+}
+
+pub fn clear() {
+    TRACES.lock().unwrap().1.clear();
+}
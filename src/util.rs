@@ -19,14 +19,21 @@ impl ImmutCounter for std::cell::Cell<usize> {
 pub trait AttrOps {
     fn dir_balance(&mut self, balance: i8);
     fn nlink_balance(&mut self, balance: i8);
+    fn resize(&mut self, size: u64);
 }
 
 impl AttrOps for FileAttr {
     fn dir_balance(&mut self, balance: i8) {
         self.mtime = SystemTime::now();
         self.ctime = self.mtime;
-        self.size = self.size.wrapping_add_signed(balance as i64);
-        self.blocks = self.size / self.blksize as u64;
+        self.resize(self.size.wrapping_add_signed(balance as i64));
+    }
+
+    // Set size and block count, blocks are counted in 512 byte units like st_blocks
+    fn resize(&mut self, size: u64) {
+        let blksize = self.blksize as u64;
+        self.size = size;
+        self.blocks = size.div_ceil(blksize) * (blksize / 512);
     }
 
     fn nlink_balance(&mut self, balance: i8) {
@@ -34,3 +41,49 @@ impl AttrOps for FileAttr {
         self.nlink = self.nlink.wrapping_add_signed(balance as i32);
     }
 }
+
+// Resident set size of this process in bytes
+pub fn rss_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size as u64)
+}
+
+// Match path against glob with `*` and `?` within a path component and `**` across components.
+// A trailing "/**" also matches the directory itself
+pub fn glob_match(glob: &str, path: &str) -> bool {
+    fn matches(g: &[u8], p: &[u8]) -> bool {
+        match g {
+            [] => p.is_empty(),
+            [b'*', b'*', rest @ ..] => (0..=p.len()).any(|i| matches(rest, &p[i..])),
+            [b'*', rest @ ..] => (0..=p.len())
+                .take_while(|&i| i == 0 || p[i - 1] != b'/')
+                .any(|i| matches(rest, &p[i..])),
+            [b'?', rest @ ..] => p.first().is_some_and(|&c| c != b'/') && matches(rest, &p[1..]),
+            [c, rest @ ..] => p.first() == Some(c) && matches(rest, &p[1..]),
+        }
+    }
+    matches(glob.as_bytes(), path.as_bytes())
+        || glob
+            .strip_suffix("/**")
+            .is_some_and(|dir| matches(dir.as_bytes(), path.as_bytes()))
+}
+
+// Name of process `pid`, as in /proc/<pid>/comm
+pub fn comm(pid: i32) -> Option<String> {
+    let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(comm.trim_end().to_owned())
+}
+
+// Pids of processes named `name`, as in /proc/<pid>/comm
+pub fn pids_by_name(name: &str) -> Vec<i32> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return vec![];
+    };
+    entries
+        .flatten()
+        .filter_map(|e| e.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|pid| comm(*pid).is_some_and(|comm| comm == name))
+        .collect()
+}
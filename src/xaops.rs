@@ -1,11 +1,26 @@
-use libc::ENOENT;
+use libc::{EINVAL, ENOENT};
+use serde::Deserialize;
 
 use crate::{
-    effect,
+    effect, events, inflight, metrics, trace,
     ftree::Tree,
-    ftypes::{ErrNo, Ino, NodeItem},
+    ftypes::{ErrNo, Ino, NodeItem, errno},
 };
 
+#[derive(Deserialize)]
+struct Range {
+    offset: usize,
+    len: usize,
+}
+
+// Force-complete in-flight operation `id`, or fail it with `errno`
+#[derive(Deserialize)]
+struct Finish {
+    id: u64,
+    #[serde(default, with = "errno::option")]
+    errno: Option<ErrNo>,
+}
+
 pub fn get(tree: &Tree, ino: Ino, name: &str) -> Option<String> {
     match name {
         "bf.ino" => Some(format!("{}", ino)),
@@ -16,6 +31,59 @@ pub fn get(tree: &Tree, ino: Ino, name: &str) -> Option<String> {
                 None
             }
         }
+        "bf.damage" => {
+            if let NodeItem::File(ref file) = tree.get(ino)?.item {
+                Some(serde_json::to_string(&file.damaged().collect::<Vec<_>>()).unwrap())
+            } else {
+                None
+            }
+        }
+        "bf.events" => Some(events::dump()),
+        "bf.inflight" => Some(inflight::list()),
+        "bf.dirstats" => {
+            tree.get(ino)?;
+            let dirs = tree.traverse(ino).filter_map(|n| match n.item {
+                NodeItem::Dir(ref dir) => Some((n.attr.ino as Ino, dir)),
+                _ => None,
+            });
+            let (mut count, mut entries, mut indexed, mut largest) = (0, 0, 0, (0, ino));
+            for (dino, dir) in dirs {
+                count += 1;
+                entries += dir.entries();
+                indexed += dir.indexed() as usize;
+                largest = largest.max((dir.entries(), dino));
+            }
+            let stats = serde_json::json!({
+                "dirs": count,
+                "entries": entries,
+                "indexed": indexed,
+                "largest": {"path": tree.path(largest.1), "entries": largest.0},
+            });
+            Some(stats.to_string())
+        }
+        "bf.badblocks" => {
+            tree.get(ino)?;
+            Some(serde_json::to_string(&effect::badblocks(tree, ino)).unwrap())
+        }
+        "bf.trace" => Some(trace::dump()),
+        "bf.metrics" => Some(metrics::dump()),
+        "bf.metrics.csv" => Some(metrics::dump_csv()),
+        "bf.inflight/stats" => Some(inflight::stats()),
+        "bf.idle" => {
+            tree.get(ino)?;
+            Some(format!("{}", tree.idle(ino).as_millis()))
+        }
+        "bf.order" => match tree.get(ino)?.item {
+            NodeItem::Dir(ref dir) => dir
+                .order
+                .and_then(|o| serde_json::to_value(o).unwrap().as_str().map(str::to_owned)),
+            _ => None,
+        },
+        "bf.readonly" => Some(format!("{}", tree.get(ino)?.effects.find("readonly").is_some() as u8)),
+        "bf.direct_io" => match tree.get(ino)?.item {
+            NodeItem::File(ref file) => Some(format!("{}", file.direct_io as u8)),
+            _ => None,
+        },
         "bf.effect" | "bf.effect/self" => {
             Some(serde_json::to_string(&tree.get(ino)?.effects).unwrap())
         }
@@ -27,6 +95,31 @@ pub fn get(tree: &Tree, ino: Ino, name: &str) -> Option<String> {
                 .collect();
             Some(serde_json::to_string(&all_effects).unwrap())
         }
+        "bf.effect/effective" => {
+            tree.get(ino)?;
+            let effective: Vec<_> = effect::resolve(tree.climb(ino))
+                .map(|(origin, de)| {
+                    let mut out = serde_json::to_value(de).unwrap();
+                    out["origin"] = tree.path(origin).into();
+                    out
+                })
+                .collect();
+            Some(serde_json::to_string(&effective).unwrap())
+        }
+        "bf.effect/stats" => {
+            let stats: serde_json::Map<_, _> = (&tree.get(ino)?.effects)
+                .into_iter()
+                .map(|de| (de.name.clone(), serde_json::to_value(&de.stats).unwrap()))
+                .collect();
+            Some(serde_json::to_string(&stats).unwrap())
+        }
+        "bf.effect.help" => Some(serde_json::to_string(effect::schema::SCHEMAS).unwrap()),
+        "bf.effect.help.conditions" => Some(serde_json::to_string(effect::schema::CONDITIONS).unwrap()),
+        "bf.effect.help.presets" => Some(serde_json::to_string(effect::preset::PRESETS).unwrap()),
+        name if name.starts_with("bf.effect.help.") => {
+            let schema = effect::schema::find(name.strip_prefix("bf.effect.help.")?)?;
+            Some(serde_json::to_string(schema).unwrap())
+        }
         name if name.starts_with("bf.effect.") => {
             let name = name.strip_prefix("bf.effect.")?;
             tree.get(ino as Ino)?.effects.find(name)?.effect.display()
@@ -36,11 +129,68 @@ pub fn get(tree: &Tree, ino: Ino, name: &str) -> Option<String> {
 }
 pub fn set(tree: &mut Tree, ino: Ino, name: &str, value: &str) -> Result<(), ErrNo> {
     match name {
+        "bf.damage" => {
+            let Range { offset, len } = serde_json::from_str(value).map_err(|_| EINVAL)?;
+            let node = tree.get_mut(ino).ok_or(ENOENT)?;
+            let blksize = node.attr.blksize as usize;
+            match node.item {
+                // Writes to read only storage like generated files are dropped, so it can't be damaged
+                NodeItem::File(ref file) if file.storage().readonly() => Err(libc::EROFS),
+                NodeItem::File(ref mut file) => {
+                    file.damage(ino, offset, len, blksize);
+                    Ok(())
+                }
+                _ => Err(EINVAL),
+            }
+        }
+        "bf.readonly" => match value.trim().parse::<u8>().map_err(|_| EINVAL)? {
+            0 => {
+                tree.get_mut(ino).ok_or(ENOENT)?.effects.remove("readonly");
+                Ok(())
+            }
+            _ => effect::attach(tree, ino, effect::DefinedEffect::create("readonly", r#"{"op": "wm"}"#)?),
+        },
+        "bf.direct_io" => match tree.get_mut(ino).ok_or(ENOENT)?.item {
+            NodeItem::File(ref mut file) => {
+                file.direct_io = value.trim().parse::<u8>().map_err(|_| EINVAL)? != 0;
+                Ok(())
+            }
+            _ => Err(EINVAL),
+        },
+        "bf.repair" => match tree.get_mut(ino).ok_or(ENOENT)?.item {
+            NodeItem::File(ref mut file) => {
+                file.repair(ino, |_, _, _| true);
+                Ok(())
+            }
+            _ => Err(EINVAL),
+        },
+        "bf.order" => match tree.get_mut(ino).ok_or(ENOENT)?.item {
+            NodeItem::Dir(ref mut dir) => {
+                let order = serde_json::from_value(value.trim().into()).map_err(|_| EINVAL)?;
+                dir.order = Some(order);
+                Ok(())
+            }
+            _ => Err(EINVAL),
+        },
+        "bf.trace" => {
+            trace::set_capacity(value.trim().parse().map_err(|_| EINVAL)?);
+            Ok(())
+        }
+        "bf.release" => effect::release(tree, ino).ok_or(ENOENT),
+        "bf.quota.reset" => effect::reset_quota(tree, ino).ok_or(ENOENT),
+        "bf.inflight" => {
+            let Finish { id, errno } = serde_json::from_str(value).map_err(|_| EINVAL)?;
+            if inflight::finish(id, errno) {
+                Ok(())
+            } else {
+                Err(ENOENT)
+            }
+        }
+        "bf.effect.preset" => effect::preset::install(tree, ino, value),
         name if name.starts_with("bf.effect.") => {
             let name = name.strip_prefix("bf.effect.").unwrap();
             let effect = effect::DefinedEffect::create(name, value)?;
-            tree.get_mut(ino).ok_or(ENOENT)?.effects.add(effect);
-            Ok(())
+            effect::attach(tree, ino, effect)
         }
         _ => Err(ENOENT),
     }
@@ -48,10 +198,30 @@ pub fn set(tree: &mut Tree, ino: Ino, name: &str, value: &str) -> Result<(), Err
 
 pub fn remove(tree: &mut Tree, ino: Ino, name: &str) -> Option<()> {
     match name {
+        "bf.events" => {
+            events::clear();
+            Some(())
+        }
+        "bf.trace" => {
+            trace::clear();
+            Some(())
+        }
+        "bf.metrics" => {
+            metrics::clear();
+            Some(())
+        }
+        "bf.order" => match tree.get_mut(ino)?.item {
+            NodeItem::Dir(ref mut dir) => {
+                dir.order = None;
+                Some(())
+            }
+            _ => None,
+        },
         "bf.effect" => {
             tree.get_mut(ino as Ino)?.effects.clear();
             Some(())
         }
+        "bf.effect.preset" => effect::preset::uninstall(tree, ino),
         name if name.starts_with("bf.effect.") => {
             tree.get_mut(ino as Ino)?
                 .effects